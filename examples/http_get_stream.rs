@@ -0,0 +1,16 @@
+extern crate cabot;
+
+use async_std::io;
+use cabot::{Client, RequestBuilder};
+
+#[async_std::main]
+async fn main() {
+    let request = RequestBuilder::new("https://www.rust-lang.org")
+        .build()
+        .unwrap();
+    let client = Client::new();
+    let response = client.execute_stream(&request).await.unwrap();
+    eprintln!("{}", response.status_line());
+    let mut body = response.into_body_stream().unwrap();
+    io::copy(&mut body, &mut io::stdout()).await.unwrap();
+}
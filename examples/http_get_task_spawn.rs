@@ -1,18 +1,24 @@
 extern crate cabot;
 
-//use async_std;
+use std::sync::Arc;
+
 use async_std::task;
 use cabot::{Client, RequestBuilder};
 
 #[async_std::main]
 async fn main() {
-    let response = task::spawn(async {
-        let request = RequestBuilder::new("https://www.rust-lang.org")
-            .build()
-            .unwrap();
-        let client = Client::new();
-        let response = client.execute_box(&request).await;
-        response
-    });
-    println!("{}", response.await.unwrap().body_as_string().unwrap());
+    let client = Arc::new(Client::new());
+    let tasks: Vec<_> = ["https://www.rust-lang.org", "https://crates.io"]
+        .iter()
+        .map(|url| {
+            let client = client.clone();
+            task::spawn(async move {
+                let request = RequestBuilder::new(url).build().unwrap();
+                client.execute(&request).await
+            })
+        })
+        .collect();
+    for task in tasks {
+        println!("{}", task.await.unwrap().body_as_string().unwrap());
+    }
 }
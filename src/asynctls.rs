@@ -1,33 +1,376 @@
 //! decrypt TLS data using rustls on a async-std TcpStream
 
-use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read as SyncRead, Write as SyncWrite};
+use std::fs;
+use std::io::{
+    BufReader, Error as IoError, ErrorKind as IoErrorKind, Read as SyncRead, Write as SyncWrite,
+};
+use std::path::Path;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::vec::Vec;
 
 use async_std::io::{Read, Result as IoResult, Write};
 use async_std::net::TcpStream;
 use async_std::prelude::*;
 use async_std::task::{Context, Poll};
-use rustls::{ClientConfig, ClientSession, ProtocolVersion, Session};
-use webpki::DNSNameRef;
+use ring::signature::Ed25519KeyPair;
+use rustls::internal::msgs::enums::SignatureAlgorithm;
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey, Signer, SigningKey};
+use rustls::{
+    Certificate, ClientConfig, ClientSession, ClientSessionMemoryCache, PrivateKey,
+    ProtocolVersion, ResolvesClientCert, RootCertStore, Session, SignatureScheme,
+    StoresClientSessions, TLSError,
+};
+use webpki::{DNSNameRef, EndEntityCert};
 use webpki_roots;
 
 use super::constants;
 use super::errors::CabotError;
 use super::results::CabotResult;
 
-fn create_config() -> Arc<ClientConfig> {
-    let mut config = ClientConfig::new();
-    config
-        .root_store
-        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-    let rc_config = Arc::new(config);
-    rc_config
+/// Signature schemes used to check that a private key match its certificate.
+const KEY_CHECK_SCHEMES: &[(SignatureScheme, &webpki::SignatureAlgorithm)] = &[
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// Ed25519 private key, not supported by rustls::sign::any_supported_type.
+struct Ed25519SigningKey {
+    key: Arc<Ed25519KeyPair>,
+}
+
+impl SigningKey for Ed25519SigningKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        if offered.contains(&SignatureScheme::ED25519) {
+            Some(Box::new(Ed25519Signer {
+                key: self.key.clone(),
+            }))
+        } else {
+            None
+        }
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        // ed25519 is not listed in rustls, its value is defined in RFC 8422.
+        SignatureAlgorithm::Unknown(7)
+    }
+}
+
+struct Ed25519Signer {
+    key: Arc<Ed25519KeyPair>,
+}
+
+impl Signer for Ed25519Signer {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, TLSError> {
+        Ok(self.key.sign(message).as_ref().to_vec())
+    }
+
+    fn get_scheme(&self) -> SignatureScheme {
+        SignatureScheme::ED25519
+    }
+}
+
+/// Parse a PEM private key, in the PKCS#8 or the PKCS#1 (RSA) format.
+fn parse_private_key(pem: &[u8]) -> CabotResult<PrivateKey> {
+    let invalid_key = |_| CabotError::PrivateKeyParseError("Invalid PEM private key".to_owned());
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(pem)).map_err(invalid_key)?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(pem)).map_err(invalid_key)?;
+    }
+    match keys.len() {
+        0 => Err(CabotError::PrivateKeyParseError(
+            "No PKCS#8 or RSA private key found in PEM".to_owned(),
+        )),
+        1 => Ok(keys.remove(0)),
+        _ => Err(CabotError::PrivateKeyParseError(
+            "Many private keys found in PEM".to_owned(),
+        )),
+    }
+}
+
+/// Load a RSA, ECDSA or Ed25519 private key.
+fn load_signing_key(key: &PrivateKey) -> CabotResult<Box<dyn SigningKey>> {
+    if let Ok(signing_key) = sign::any_supported_type(key) {
+        return Ok(signing_key);
+    }
+    match Ed25519KeyPair::from_pkcs8_maybe_unchecked(&key.0) {
+        Ok(key) => Ok(Box::new(Ed25519SigningKey { key: Arc::new(key) })),
+        Err(_) => Err(CabotError::PrivateKeyParseError(
+            "Unsupported private key type, RSA, ECDSA or Ed25519 expected".to_owned(),
+        )),
+    }
+}
+
+/// Ensure the private key match the certificate by verifying a signature
+/// with the public key of the certificate.
+fn check_key_match(cert: &Certificate, key: &dyn SigningKey) -> CabotResult<()> {
+    let end_entity = EndEntityCert::from(&cert.0)
+        .map_err(|err| CabotError::CertificateParseError(format!("{:?}", err)))?;
+    let message = b"cabot client certificate key check";
+    for (scheme, algorithm) in KEY_CHECK_SCHEMES {
+        if let Some(signer) = key.choose_scheme(&[*scheme]) {
+            let signature = signer.sign(message)?;
+            return end_entity
+                .verify_signature(algorithm, message, &signature)
+                .map_err(|_| CabotError::CertificateKeyMismatch);
+        }
+    }
+    Err(CabotError::CertificateKeyMismatch)
 }
 
-fn create_client(host: &str) -> CabotResult<ClientSession> {
-    let rc_config = create_config();
+/// Always present the same client certificate if the server accepts
+/// its signature scheme.
+struct ClientCertificate {
+    certified_key: CertifiedKey,
+    /// rustls only signs with Ed25519 keys in TLS 1.2 handshakes.
+    tls12_only: bool,
+}
+
+impl ResolvesClientCert for ClientCertificate {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        if self.certified_key.key.choose_scheme(sigschemes).is_some() {
+            Some(self.certified_key.clone())
+        } else {
+            warn!("The client certificate key type is not accepted by the server");
+            None
+        }
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// TLS settings used to verify the certificates of the servers,
+/// and to authenticate the client.
+///
+/// By default, the Mozilla root certificates bundled by webpki-roots are
+/// trusted, custom certificate authorities can be added to them or replace
+/// them.
+///
+/// The rustls configuration is built once and shared by every connections,
+/// with a cache of TLS sessions in order to resume them. Cloned configs
+/// share their sessions cache until their certificates are changed, a
+/// session is never resumed with other certificates than the ones that
+/// verified it.
+#[derive(Clone)]
+pub struct TLSConfig {
+    /// certificate authorities added to the default ones.
+    root_store: RootCertStore,
+    /// trust the webpki-roots certificates.
+    default_roots: bool,
+    /// certificate presented to the servers that require client authentication.
+    client_cert: Option<Arc<ClientCertificate>>,
+    /// TLS sessions to resume.
+    session_cache: Arc<dyn StoresClientSessions>,
+    /// rustls configuration provided by the user, replacing the settings.
+    custom_config: Option<Arc<ClientConfig>>,
+    /// rustls configuration built from the settings, on the first connection.
+    client_config: Arc<Mutex<Option<Arc<ClientConfig>>>>,
+}
+
+impl Default for TLSConfig {
+    fn default() -> Self {
+        TLSConfig::new()
+    }
+}
+
+impl TLSConfig {
+    /// Construct a config trusting the default root certificates.
+    pub fn new() -> Self {
+        TLSConfig {
+            root_store: RootCertStore::empty(),
+            default_roots: true,
+            client_cert: None,
+            session_cache: ClientSessionMemoryCache::new(constants::TLS_SESSION_CACHE_SIZE),
+            custom_config: None,
+            client_config: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Trust the webpki-roots certificates, set it to false to only
+    /// trust the added certificate authorities.
+    pub fn set_default_root_certificates(&mut self, enabled: bool) {
+        self.default_roots = enabled;
+        self.reset_client_config();
+    }
+
+    /// Trust a certificate authority in the DER format.
+    pub fn add_root_certificate_der(&mut self, der: &[u8]) -> CabotResult<()> {
+        self.root_store
+            .add(&Certificate(der.to_vec()))
+            .map_err(|err| CabotError::CertificateParseError(format!("{:?}", err)))?;
+        self.reset_client_config();
+        Ok(())
+    }
+
+    /// Trust the certificate authorities of a PEM bundle.
+    ///
+    /// Return the number of certificates added.
+    pub fn add_root_certificates_pem(&mut self, pem: &[u8]) -> CabotResult<usize> {
+        let certs = pemfile::certs(&mut BufReader::new(pem))
+            .map_err(|_| CabotError::CertificateParseError("Invalid PEM certificate".to_owned()))?;
+        if certs.is_empty() {
+            return Err(CabotError::CertificateParseError(
+                "No certificate found in PEM".to_owned(),
+            ));
+        }
+        for cert in certs.iter() {
+            self.add_root_certificate_der(&cert.0)?;
+        }
+        Ok(certs.len())
+    }
+
+    /// Trust the certificate authorities of a file, in the PEM or DER format.
+    ///
+    /// Return the number of certificates added.
+    pub fn add_root_certificates_file(&mut self, path: &Path) -> CabotResult<usize> {
+        let content = fs::read(path)?;
+        if content.starts_with(b"-----BEGIN") || content.starts_with(b"\n-----BEGIN") {
+            self.add_root_certificates_pem(&content)
+        } else {
+            self.add_root_certificate_der(&content)?;
+            Ok(1)
+        }
+    }
+
+    /// Trust the certificate authorities of every files of a directory,
+    /// files that are not certificates are ignored.
+    ///
+    /// Return the number of certificates added.
+    pub fn add_root_certificates_dir(&mut self, path: &Path) -> CabotResult<usize> {
+        let mut count = 0;
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            match self.add_root_certificates_file(&path) {
+                Ok(cnt) => count += cnt,
+                Err(err) => debug!("Ignore {:?}: {}", path, err),
+            }
+        }
+        Ok(count)
+    }
+
+    /// Trust the certificate authorities of the operating system store.
+    ///
+    /// Return the number of certificates added.
+    pub fn add_native_root_certificates(&mut self) -> CabotResult<usize> {
+        let store = match rustls_native_certs::load_native_certs() {
+            Ok(store) => store,
+            Err((Some(store), err)) => {
+                warn!("Some native certificates can't be loaded: {}", err);
+                store
+            }
+            Err((None, err)) => return Err(CabotError::IOError(err)),
+        };
+        let count = store.len();
+        self.root_store.roots.extend(store.roots);
+        self.reset_client_config();
+        Ok(count)
+    }
+
+    /// Set the certificate chain and its private key, in the PEM format,
+    /// used to authenticate the client.
+    ///
+    /// RSA, ECDSA and Ed25519 private keys are supported, an error is
+    /// returned if the key does not match the certificate.
+    /// Connections are limited to TLS 1.2 while an Ed25519 key is used.
+    pub fn set_client_certificate(
+        &mut self,
+        cert_chain: &[u8],
+        private_key: &[u8],
+    ) -> CabotResult<()> {
+        let certs = pemfile::certs(&mut BufReader::new(cert_chain))
+            .map_err(|_| CabotError::CertificateParseError("Invalid PEM certificate".to_owned()))?;
+        if certs.is_empty() {
+            return Err(CabotError::CertificateParseError(
+                "No certificate found in PEM".to_owned(),
+            ));
+        }
+        let key = parse_private_key(private_key)?;
+        let signing_key = load_signing_key(&key)?;
+        check_key_match(&certs[0], signing_key.as_ref())?;
+        let tls12_only = signing_key
+            .choose_scheme(&[SignatureScheme::ED25519])
+            .is_some();
+        self.client_cert = Some(Arc::new(ClientCertificate {
+            certified_key: CertifiedKey::new(certs, Arc::new(signing_key)),
+            tls12_only,
+        }));
+        self.reset_client_config();
+        Ok(())
+    }
+
+    /// Use the given rustls configuration, the other settings of the
+    /// config are ignored.
+    pub fn set_client_config(&mut self, client_config: Arc<ClientConfig>) {
+        self.custom_config = Some(client_config);
+    }
+
+    /// The rustls configuration, shared by the connections.
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        if let Some(config) = self.custom_config.as_ref() {
+            return config.clone();
+        }
+        let mut client_config = self.client_config.lock().unwrap();
+        if let Some(config) = client_config.as_ref() {
+            return config.clone();
+        }
+        let config = self.build_client_config();
+        *client_config = Some(config.clone());
+        config
+    }
+
+    /// The settings have changed, the rustls configuration has to be built
+    /// again, without altering the one of the cloned configs.
+    ///
+    /// Resumed sessions skip the verification of the server certificate,
+    /// the sessions verified using the previous settings are dropped.
+    fn reset_client_config(&mut self) {
+        self.session_cache = ClientSessionMemoryCache::new(constants::TLS_SESSION_CACHE_SIZE);
+        self.client_config = Arc::new(Mutex::new(None));
+    }
+
+    /// Build the rustls configuration.
+    fn build_client_config(&self) -> Arc<ClientConfig> {
+        let mut config = ClientConfig::new();
+        config.set_persistence(self.session_cache.clone());
+        config.root_store = self.root_store.clone();
+        if self.default_roots {
+            config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        }
+        if let Some(client_cert) = self.client_cert.as_ref() {
+            if client_cert.tls12_only {
+                config.versions = vec![ProtocolVersion::TLSv1_2];
+            }
+            config.client_auth_cert_resolver = client_cert.clone();
+        }
+        Arc::new(config)
+    }
+}
+
+fn create_client(host: &str, tls_config: &TLSConfig) -> CabotResult<ClientSession> {
+    let rc_config = tls_config.client_config();
     let host = DNSNameRef::try_from_ascii_str(host)
         .map_err(|_| CabotError::HostnameParseError(host.to_string()))?;
     let tlsclient = ClientSession::new(&rc_config, host);
@@ -37,70 +380,113 @@ fn create_client(host: &str) -> CabotResult<ClientSession> {
 /// Stream to read from a ciphered TcpStream and cipher data before
 /// writing the that TcpStream.
 /// Use it like a TcpStream, but call the starttls after calling new.
-pub struct TLSStream<'a> {
-    tcpstream: &'a mut TcpStream,
+///
+/// The TLSStream owns the TcpStream in order to be kept alive in the
+/// connection pool with its TLS session.
+pub struct TLSStream {
+    tcpstream: TcpStream,
     tlsclient: ClientSession,
+    /// ciphered bytes not written to the TcpStream yet.
+    tls_out: Vec<u8>,
 }
 
-impl<'a> TLSStream<'a> {
+impl TLSStream {
     /// Create new TLSStream instance
-    pub fn new(tcpstream: &'a mut TcpStream, host: &str) -> CabotResult<Self> {
+    pub fn new(tcpstream: TcpStream, host: &str, tls_config: &TLSConfig) -> CabotResult<Self> {
         Ok(TLSStream {
             tcpstream,
-            tlsclient: create_client(host)?,
+            tlsclient: create_client(host, tls_config)?,
+            tls_out: Vec::with_capacity(constants::BUFFER_PAGE_SIZE),
         })
     }
+
     /// Call it just after creating the stream
     pub async fn starttls(&mut self) -> CabotResult<()> {
-        let mut buf_tlswrite: Vec<u8> = Vec::new();
         let mut read_buf: [u8; constants::BUFFER_PAGE_SIZE] = [0; constants::BUFFER_PAGE_SIZE];
-        let mut is_handshaking = true;
-        while is_handshaking {
+        while self.tlsclient.is_handshaking() {
             while self.tlsclient.wants_write() {
-                let count = self.write(&mut buf_tlswrite).await?;
+                let count = self.tlsclient.write_tls(&mut self.tls_out)?;
                 debug!("Write {} TLS bytes during hangshake", count);
+                self.tcpstream.write_all(self.tls_out.as_slice()).await?;
+                self.tls_out.clear();
             }
-            if self.tlsclient.wants_read() {
-                let count = self.read(&mut read_buf).await?;
+            if self.tlsclient.is_handshaking() && self.tlsclient.wants_read() {
+                let count = self.tcpstream.read(&mut read_buf).await?;
                 debug!("Read {} TLS bytes during hangshake", count);
-            }
-            if is_handshaking && !self.tlsclient.is_handshaking() {
-                info!("Handshake complete");
-                is_handshaking = false;
-                let protocol = self.tlsclient.get_protocol_version();
-                match protocol {
-                    Some(ProtocolVersion::SSLv2) => {
-                        info!("Protocol SSL v2 negociated");
-                    }
-                    Some(ProtocolVersion::SSLv3) => {
-                        info!("Protocol SSL v3 negociated");
-                    }
-                    Some(ProtocolVersion::TLSv1_0) => {
-                        info!("Protocol TLS v1.0 negociated");
-                    }
-                    Some(ProtocolVersion::TLSv1_1) => {
-                        info!("Protocol TLS v1.1 negociated");
-                    }
-                    Some(ProtocolVersion::TLSv1_2) => {
-                        info!("Protocol TLS v1.2 negociated");
-                    }
-                    Some(ProtocolVersion::TLSv1_3) => {
-                        info!("Protocol TLS v1.3 negociated");
-                    }
-                    Some(ProtocolVersion::Unknown(num)) => {
-                        info!("Unknown TLS Protocol negociated: {}", num);
-                    }
-                    None => {
-                        info!("No TLS Protocol negociated");
-                    }
+                if count == 0 {
+                    return Err(CabotError::IOError(IoError::new(
+                        IoErrorKind::UnexpectedEof,
+                        "Connection closed during TLS handshake",
+                    )));
                 }
+                self.tlsclient.read_tls(&mut &read_buf[..count])?;
+                self.tlsclient.process_new_packets()?;
+            }
+        }
+        // the client may have a finished message to send
+        while self.tlsclient.wants_write() {
+            self.tlsclient.write_tls(&mut self.tls_out)?;
+        }
+        self.tcpstream.write_all(self.tls_out.as_slice()).await?;
+        self.tls_out.clear();
+
+        info!("Handshake complete");
+        let protocol = self.tlsclient.get_protocol_version();
+        match protocol {
+            Some(ProtocolVersion::SSLv2) => {
+                info!("Protocol SSL v2 negociated");
+            }
+            Some(ProtocolVersion::SSLv3) => {
+                info!("Protocol SSL v3 negociated");
+            }
+            Some(ProtocolVersion::TLSv1_0) => {
+                info!("Protocol TLS v1.0 negociated");
+            }
+            Some(ProtocolVersion::TLSv1_1) => {
+                info!("Protocol TLS v1.1 negociated");
+            }
+            Some(ProtocolVersion::TLSv1_2) => {
+                info!("Protocol TLS v1.2 negociated");
+            }
+            Some(ProtocolVersion::TLSv1_3) => {
+                info!("Protocol TLS v1.3 negociated");
+            }
+            Some(ProtocolVersion::Unknown(num)) => {
+                info!("Unknown TLS Protocol negociated: {}", num);
+            }
+            None => {
+                info!("No TLS Protocol negociated");
             }
         }
         Ok(())
     }
+
+    /// True if the TLS session has been resumed during the handshake.
+    ///
+    /// The server does not send its certificates while resuming a session.
+    pub fn is_resumed(&self) -> bool {
+        !self.tlsclient.is_handshaking() && self.tlsclient.get_peer_certificates().is_none()
+    }
+
+    /// Write the pending ciphered bytes to the TcpStream.
+    fn poll_write_tls(&mut self, cx: &mut Context) -> Poll<IoResult<()>> {
+        while !self.tls_out.is_empty() {
+            let stream = Pin::new(&mut self.tcpstream);
+            let count = futures::ready!(stream.poll_write(cx, self.tls_out.as_slice()))?;
+            if count == 0 {
+                return Poll::Ready(Err(IoError::new(
+                    IoErrorKind::WriteZero,
+                    "Unable to write TLS bytes",
+                )));
+            }
+            debug!("Write {} TLS Ciphered bytes", count);
+            self.tls_out.drain(..count);
+        }
+        Poll::Ready(Ok(()))
+    }
 }
 
-impl<'a> Read for TLSStream<'a> {
+impl Read for TLSStream {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -108,70 +494,433 @@ impl<'a> Read for TLSStream<'a> {
     ) -> Poll<IoResult<usize>> {
         let self_ = Pin::get_mut(self);
 
-        let cnt = self_.tlsclient.read(&mut buf[..]).unwrap();
-        if cnt > 0 {
-            debug!("Remaining {} Unencrypted bytes", cnt);
-            return Poll::Ready(Ok(cnt));
-        }
-
         let mut tcp_buf: [u8; constants::BUFFER_PAGE_SIZE] = [0; constants::BUFFER_PAGE_SIZE];
-
-        let count = futures::ready!(Pin::new(&mut self_.tcpstream).poll_read(cx, &mut tcp_buf[..]));
-
-        match count {
-            Err(err) => {
-                error!("Received an error {:?}", err);
-                Poll::Ready(Err(err))
+        loop {
+            let cnt = match self_.tlsclient.read(&mut buf[..]) {
+                // rustls reports the close_notify alert as an error,
+                // this is the end of the stream.
+                Err(err) if err.kind() == IoErrorKind::ConnectionAborted => {
+                    debug!("TLS session closed");
+                    return Poll::Ready(Ok(0));
+                }
+                res => res?,
+            };
+            if cnt > 0 {
+                debug!("Read {} Unencrypted bytes", cnt);
+                return Poll::Ready(Ok(cnt));
             }
-            Ok(n) => {
-                debug!("Read {} TCP bytes", n);
-                let count = self_.tlsclient.read_tls(&mut &tcp_buf[..n])?;
-                debug!("Decode {} TLS bytes", count);
 
-                let packets = self_.tlsclient.process_new_packets();
-                match packets {
-                    Ok(_) => {
-                        let cnt = self_.tlsclient.read(&mut buf[..]).unwrap();
-                        debug!("Read {} Unencrypted bytes", cnt);
-                        Poll::Ready(Ok(cnt))
+            // a TLS record may be received in many TCP packets,
+            // we read until we get some plain text or the end of the stream.
+            let count =
+                futures::ready!(Pin::new(&mut self_.tcpstream).poll_read(cx, &mut tcp_buf[..]));
+            match count {
+                Err(err) => {
+                    error!("Received an error {:?}", err);
+                    return Poll::Ready(Err(err));
+                }
+                Ok(0) => {
+                    debug!("TCP connection closed");
+                    return Poll::Ready(Ok(0));
+                }
+                Ok(n) => {
+                    debug!("Read {} TCP bytes", n);
+                    let count = self_.tlsclient.read_tls(&mut &tcp_buf[..n])?;
+                    debug!("Decode {} TLS bytes", count);
+                    if let Err(err) = self_.tlsclient.process_new_packets() {
+                        return Poll::Ready(Err(IoError::new(
+                            IoErrorKind::InvalidData,
+                            format!("{:?}", err),
+                        )));
                     }
-                    Err(err) => Poll::Ready(Err(IoError::new(
-                        IoErrorKind::InvalidData,
-                        format!("{:?}", err),
-                    ))),
                 }
             }
         }
     }
 }
 
-impl<'a> Write for TLSStream<'a> {
+impl Write for TLSStream {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
         let self_ = Pin::get_mut(self);
-        let mut buf_tlswrite: Vec<u8> = Vec::with_capacity(constants::BUFFER_PAGE_SIZE);
+        // ensure the previous write has been sent before ciphering new data.
+        futures::ready!(self_.poll_write_tls(cx))?;
 
         let ret = buf.len();
-
         self_.tlsclient.write_all(&buf)?;
         debug!("Write {} TLS Clear bytes", ret);
         while self_.tlsclient.wants_write() {
-            let count = self_.tlsclient.write_tls(&mut buf_tlswrite)?;
-            debug!("Write {} TLS Ciphered bytes", count);
+            self_.tlsclient.write_tls(&mut self_.tls_out)?;
+        }
+        // The data are ciphered and bufferized, they will be sent on the next
+        // write or on flush if the TcpStream is not ready.
+        if let Poll::Ready(Err(err)) = self_.poll_write_tls(cx) {
+            return Poll::Ready(Err(err));
         }
-        let stream = Pin::new(&mut *self_.tcpstream);
-        let count = futures::ready!(stream.poll_write(cx, buf_tlswrite.as_slice()))?;
-        buf_tlswrite.clear();
-        debug!("Write {} TLS Ciphered bytes", count);
         Poll::Ready(Ok(ret))
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
         let self_ = Pin::get_mut(self);
-        Pin::new(&mut *self_.tcpstream).poll_flush(cx)
+        futures::ready!(self_.poll_write_tls(cx))?;
+        Pin::new(&mut self_.tcpstream).poll_flush(cx)
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
         let self_ = Pin::get_mut(self);
-        Pin::new(&mut *self_.tcpstream).poll_close(cx)
+        futures::ready!(self_.poll_write_tls(cx))?;
+        Pin::new(&mut self_.tcpstream).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::io::{self, Read as SyncRead, Write as SyncWrite};
+    use std::net::{SocketAddr, TcpListener as SyncTcpListener};
+    use std::thread;
+
+    use rustls::internal::pemfile;
+    use rustls::{
+        AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig, ServerSession,
+        StreamOwned,
+    };
+
+    use super::super::client::Client;
+    use super::super::request::RequestBuilder;
+
+    #[test]
+    fn test_add_root_certificates() {
+        let mut config = TLSConfig::new();
+        let count = config
+            .add_root_certificates_pem(include_bytes!("../tests/certs/ca.pem"))
+            .unwrap();
+        assert_eq!(count, 1);
+        config
+            .add_root_certificate_der(include_bytes!("../tests/certs/ca.der"))
+            .unwrap();
+        assert_eq!(config.root_store.len(), 2);
+        config.set_default_root_certificates(false);
+        assert_eq!(config.client_config().root_store.len(), 2);
+        config.set_default_root_certificates(true);
+        assert!(config.client_config().root_store.len() > 2);
+    }
+
+    #[test]
+    fn test_add_root_certificates_invalid() {
+        let mut config = TLSConfig::new();
+        let err = config
+            .add_root_certificates_pem(include_bytes!("../tests/certs/localhost.key"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Certificate Parse Error: No certificate found in PEM"
+        );
+        let err = config.add_root_certificate_der(b"not a certificate");
+        assert!(err.is_err());
+        assert_eq!(config.root_store.len(), 0);
+    }
+
+    #[test]
+    fn test_add_root_certificates_dir() {
+        let mut config = TLSConfig::new();
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs");
+        // ca.pem, ca.der, localhost.pem and the client certificates,
+        // other files are ignored.
+        assert_eq!(config.add_root_certificates_dir(&path).unwrap(), 6);
+        let path = path.join("ca.pem");
+        assert_eq!(config.add_root_certificates_file(&path).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_client_config_shared() {
+        let mut tls_config = TLSConfig::new();
+        let config = tls_config.client_config();
+        assert!(Arc::ptr_eq(&config, &tls_config.client_config()));
+        assert!(Arc::ptr_eq(&config, &tls_config.clone().client_config()));
+        tls_config
+            .add_root_certificates_pem(include_bytes!("../tests/certs/ca.pem"))
+            .unwrap();
+        let updated = tls_config.client_config();
+        assert!(!Arc::ptr_eq(&config, &updated));
+        assert_eq!(updated.root_store.len(), config.root_store.len() + 1);
+        let custom = Arc::new(ClientConfig::new());
+        tls_config.set_client_config(custom.clone());
+        assert!(Arc::ptr_eq(&custom, &tls_config.client_config()));
+    }
+
+    #[test]
+    fn test_set_client_certificate() {
+        let mut config = TLSConfig::new();
+        for name in &["client-ecdsa", "client-rsa", "client-ed25519"] {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs");
+            let cert = fs::read(path.join(format!("{}.pem", name))).unwrap();
+            let key = fs::read(path.join(format!("{}.key", name))).unwrap();
+            config.set_client_certificate(&cert, &key).unwrap();
+            let client_cert = config.client_cert.as_ref().unwrap();
+            assert!(client_cert.has_certs());
+            let schemes = [
+                SignatureScheme::ED25519,
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+                SignatureScheme::RSA_PSS_SHA256,
+            ];
+            assert!(client_cert.resolve(&[], &schemes).is_some());
+            assert!(client_cert
+                .resolve(&[], &[SignatureScheme::ECDSA_NISTP384_SHA384])
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_set_client_certificate_mismatch() {
+        let mut config = TLSConfig::new();
+        let err = config
+            .set_client_certificate(
+                include_bytes!("../tests/certs/client-ecdsa.pem"),
+                include_bytes!("../tests/certs/localhost.key"),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Certificate Key Mismatch: the private key does not match the certificate"
+        );
+        let err = config
+            .set_client_certificate(
+                include_bytes!("../tests/certs/client-ecdsa.pem"),
+                include_bytes!("../tests/certs/client-rsa.key"),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Certificate Key Mismatch: the private key does not match the certificate"
+        );
+        assert!(config.client_cert.is_none());
+    }
+
+    #[test]
+    fn test_set_client_certificate_invalid() {
+        let mut config = TLSConfig::new();
+        let err = config
+            .set_client_certificate(
+                include_bytes!("../tests/certs/client-ecdsa.pem"),
+                include_bytes!("../tests/certs/client-ecdsa.pem"),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Private Key Parse Error: No PKCS#8 or RSA private key found in PEM"
+        );
+        let err = config
+            .set_client_certificate(
+                include_bytes!("../tests/certs/client-ecdsa.key"),
+                include_bytes!("../tests/certs/client-ecdsa.key"),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Certificate Parse Error: No certificate found in PEM"
+        );
+    }
+
+    /// Serve a response over TLS using the certificate of localhost
+    /// signed by the test certificate authority.
+    ///
+    /// If `client_auth` is set, the client has to present a certificate
+    /// signed by the test certificate authority.
+    pub(crate) fn serve_tls(
+        response: &'static str,
+        client_auth: bool,
+        connections: usize,
+    ) -> (SocketAddr, thread::JoinHandle<io::Result<()>>) {
+        let listener = SyncTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut config = if client_auth {
+                let mut roots = RootCertStore::empty();
+                roots
+                    .add_pem_file(&mut &include_bytes!("../tests/certs/ca.pem")[..])
+                    .unwrap();
+                ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+            } else {
+                ServerConfig::new(NoClientAuth::new())
+            };
+            let certs =
+                pemfile::certs(&mut &include_bytes!("../tests/certs/localhost.pem")[..]).unwrap();
+            let mut keys = pemfile::pkcs8_private_keys(
+                &mut &include_bytes!("../tests/certs/localhost.key")[..],
+            )
+            .unwrap();
+            config.set_single_cert(certs, keys.remove(0)).unwrap();
+            let config = Arc::new(config);
+            for _ in 0..connections {
+                let (stream, _) = listener.accept()?;
+                let mut stream = StreamOwned::new(ServerSession::new(&config), stream);
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let count = stream.read(&mut buf)?;
+                    if count == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..count]);
+                }
+                stream.write_all(response.as_bytes())?;
+                stream.flush()?;
+            }
+            Ok(())
+        });
+        (addr, handle)
+    }
+
+    pub(crate) fn tls_client(addr: &SocketAddr) -> Client {
+        let mut client = Client::new();
+        client.add_authority("localhost:443", addr);
+        client
+    }
+
+    pub(crate) const TLS_RESPONSE: &str = "HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello";
+
+    #[async_std::test]
+    async fn test_tls_root_certificate_pem() {
+        let (addr, server) = serve_tls(TLS_RESPONSE, false, 1);
+        let mut client = tls_client(&addr);
+        let count = client
+            .add_root_certificates_pem(include_bytes!("../tests/certs/ca.pem"))
+            .unwrap();
+        assert_eq!(count, 1);
+        client.set_default_root_certificates(false);
+        let request = RequestBuilder::new("https://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        server.join().unwrap().unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_tls_root_certificate_der() {
+        let (addr, server) = serve_tls(TLS_RESPONSE, false, 1);
+        let mut client = tls_client(&addr);
+        client
+            .add_root_certificate_der(include_bytes!("../tests/certs/ca.der"))
+            .unwrap();
+        let request = RequestBuilder::new("https://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        server.join().unwrap().unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_tls_unknown_certificate_authority() {
+        let (addr, server) = serve_tls(TLS_RESPONSE, false, 1);
+        let client = tls_client(&addr);
+        let request = RequestBuilder::new("https://localhost/").build().unwrap();
+        let err = client.execute(&request).await.unwrap_err();
+        assert!(err.is_tls());
+        assert_eq!(
+            err.to_string(),
+            "TLS Handshake Error while performing the TLS handshake with \
+             https://localhost/: Certificate Error: invalid certificate: UnknownIssuer"
+        );
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[async_std::test]
+    async fn test_tls_session_resumed() {
+        let (addr, server) = serve_tls(TLS_RESPONSE, false, 2);
+        let mut client = tls_client(&addr);
+        client
+            .add_root_certificates_pem(include_bytes!("../tests/certs/ca.pem"))
+            .unwrap();
+        client.set_max_idle_per_host(0);
+        let request = RequestBuilder::new("https://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.tls_session_resumed(), Some(false));
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.tls_session_resumed(), Some(true));
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        server.join().unwrap().unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_tls_session_not_resumed_after_reconfiguration() {
+        let (addr, server) = serve_tls(TLS_RESPONSE, false, 3);
+        let mut client = tls_client(&addr);
+        client
+            .add_root_certificates_pem(include_bytes!("../tests/certs/ca.pem"))
+            .unwrap();
+        client.set_max_idle_per_host(0);
+        let request = RequestBuilder::new("https://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.tls_session_resumed(), Some(false));
+
+        // the session verified by the client is not resumed by a clone
+        // trusting other certificates.
+        let mut other = client.clone();
+        other.set_default_root_certificates(false);
+        let response = other.execute(&request).await.unwrap();
+        assert_eq!(response.tls_session_resumed(), Some(false));
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.tls_session_resumed(), Some(true));
+        server.join().unwrap().unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_tls_client_config() {
+        let (addr, server) = serve_tls(TLS_RESPONSE, false, 1);
+        let mut client = tls_client(&addr);
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_pem_file(&mut &include_bytes!("../tests/certs/ca.pem")[..])
+            .unwrap();
+        client.set_tls_client_config(Arc::new(config));
+        let request = RequestBuilder::new("https://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert_eq!(response.tls_session_resumed(), Some(false));
+        server.join().unwrap().unwrap();
+    }
+
+    fn mtls_client(addr: &SocketAddr, cert: &[u8], key: &[u8]) -> Client {
+        let mut client = tls_client(addr);
+        client
+            .add_root_certificates_pem(include_bytes!("../tests/certs/ca.pem"))
+            .unwrap();
+        client.set_client_certificate(cert, key).unwrap();
+        client
+    }
+
+    #[async_std::test]
+    async fn test_tls_client_certificate() {
+        // Ed25519 client certificates are not supported by the rustls server.
+        let keys: [(&[u8], &[u8]); 2] = [
+            (
+                include_bytes!("../tests/certs/client-ecdsa.pem"),
+                include_bytes!("../tests/certs/client-ecdsa.key"),
+            ),
+            (
+                include_bytes!("../tests/certs/client-rsa.pem"),
+                include_bytes!("../tests/certs/client-rsa.key"),
+            ),
+        ];
+        for (cert, key) in keys.iter() {
+            let (addr, server) = serve_tls(TLS_RESPONSE, true, 1);
+            let client = mtls_client(&addr, cert, key);
+            let request = RequestBuilder::new("https://localhost/").build().unwrap();
+            let response = client.execute(&request).await.unwrap();
+            assert_eq!(response.body_as_string().unwrap(), "Hello");
+            server.join().unwrap().unwrap();
+        }
+    }
+
+    #[async_std::test]
+    async fn test_tls_client_certificate_required() {
+        let (addr, server) = serve_tls(TLS_RESPONSE, true, 1);
+        let mut client = tls_client(&addr);
+        client
+            .add_root_certificates_pem(include_bytes!("../tests/certs/ca.pem"))
+            .unwrap();
+        let request = RequestBuilder::new("https://localhost/").build().unwrap();
+        assert!(client.execute(&request).await.is_err());
+        assert!(server.join().unwrap().is_err());
     }
 }
@@ -0,0 +1,534 @@
+//! HTTP authentication schemes.
+//!
+//! The `Basic` ([rfc7617](https://tools.ietf.org/html/rfc7617)) and
+//! `Bearer` ([rfc6750](https://tools.ietf.org/html/rfc6750)) credentials
+//! are sent with the request, the `Digest` credentials
+//! ([rfc7616](https://tools.ietf.org/html/rfc7616)) answer the challenge
+//! of a `401 Unauthorized` response.
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// The value of the `Authorization` header using the Basic scheme.
+pub(crate) fn basic(username: &str, password: &str) -> String {
+    let credentials = base64::encode(format!("{}:{}", username, password).as_bytes());
+    format!("Basic {}", credentials)
+}
+
+/// The value of the `Authorization` header using the Bearer scheme.
+pub(crate) fn bearer(token: &str) -> String {
+    format!("Bearer {}", token)
+}
+
+/// The hash algorithms of the Digest scheme.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+    MD5,
+    MD5Sess,
+    SHA256,
+    SHA256Sess,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "MD5" => Some(Algorithm::MD5),
+            "MD5-SESS" => Some(Algorithm::MD5Sess),
+            "SHA-256" => Some(Algorithm::SHA256),
+            "SHA-256-SESS" => Some(Algorithm::SHA256Sess),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::MD5 => "MD5",
+            Algorithm::MD5Sess => "MD5-sess",
+            Algorithm::SHA256 => "SHA-256",
+            Algorithm::SHA256Sess => "SHA-256-sess",
+        }
+    }
+
+    fn is_session(self) -> bool {
+        matches!(self, Algorithm::MD5Sess | Algorithm::SHA256Sess)
+    }
+
+    /// The hexadecimal hash of `data`.
+    fn hash(self, data: &str) -> String {
+        match self {
+            Algorithm::MD5 | Algorithm::MD5Sess => format!("{:x}", md5::compute(data)),
+            Algorithm::SHA256 | Algorithm::SHA256Sess => digest(&SHA256, data.as_bytes())
+                .as_ref()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        }
+    }
+}
+
+/// A Digest challenge, read from the `WWW-Authenticate` header of a
+/// `401 Unauthorized` response.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: Algorithm,
+    /// The challenge offers the `auth` quality of protection, otherwise
+    /// it is answered as described in rfc2069.
+    qop_auth: bool,
+}
+
+impl DigestChallenge {
+    /// Parse the value of a `WWW-Authenticate` header.
+    ///
+    /// The header may hold many challenges, the first supported Digest
+    /// challenge is kept. None if there is no Digest challenge, or if their
+    /// algorithm or their quality of protection are not supported.
+    pub(crate) fn parse(header_value: &str) -> Option<Self> {
+        split_challenges(header_value)
+            .into_iter()
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Digest"))
+            .find_map(|(_, params)| DigestChallenge::from_params(&params))
+    }
+
+    /// Read the challenge from the parameters of a Digest challenge.
+    fn from_params(params: &str) -> Option<Self> {
+        let mut realm = None;
+        let mut nonce = None;
+        let mut opaque = None;
+        let mut algorithm = Algorithm::MD5;
+        let mut qop_auth = false;
+        for (name, value) in parse_params(params) {
+            match name.to_ascii_lowercase().as_str() {
+                "realm" => realm = Some(value),
+                "nonce" => nonce = Some(value),
+                "opaque" => opaque = Some(value),
+                "algorithm" => match Algorithm::from_name(&value) {
+                    Some(algo) => algorithm = algo,
+                    None => {
+                        warn!("Unsupported Digest algorithm {}", value);
+                        return None;
+                    }
+                },
+                "qop" => {
+                    if !value.split(',').any(|qop| qop.trim() == "auth") {
+                        warn!("Unsupported Digest quality of protection {}", value);
+                        return None;
+                    }
+                    qop_auth = true;
+                }
+                _ => {}
+            }
+        }
+        Some(DigestChallenge {
+            realm: realm?,
+            nonce: nonce?,
+            opaque,
+            algorithm,
+            qop_auth,
+        })
+    }
+
+    /// The value of the `Authorization` header answering the challenge,
+    /// for a request using the `method` to the `uri`.
+    pub(crate) fn authorization(
+        &self,
+        username: &str,
+        password: &str,
+        method: &str,
+        uri: &str,
+    ) -> String {
+        let mut cnonce = [0; 16];
+        SystemRandom::new()
+            .fill(&mut cnonce)
+            .expect("Unable to generate the Digest client nonce");
+        let cnonce: String = cnonce.iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.authorization_with_cnonce(username, password, method, uri, &cnonce)
+    }
+
+    fn authorization_with_cnonce(
+        &self,
+        username: &str,
+        password: &str,
+        method: &str,
+        uri: &str,
+        cnonce: &str,
+    ) -> String {
+        // Every challenge is answered once, the nonce is never reused.
+        let nc = "00000001";
+        let algo = self.algorithm;
+        let mut ha1 = algo.hash(&format!("{}:{}:{}", username, self.realm, password));
+        if algo.is_session() {
+            ha1 = algo.hash(&format!("{}:{}:{}", ha1, self.nonce, cnonce));
+        }
+        let ha2 = algo.hash(&format!("{}:{}", method, uri));
+        let response = if self.qop_auth {
+            algo.hash(&format!(
+                "{}:{}:{}:{}:auth:{}",
+                ha1, self.nonce, nc, cnonce, ha2
+            ))
+        } else {
+            algo.hash(&format!("{}:{}:{}", ha1, self.nonce, ha2))
+        };
+
+        let mut authorization = format!(
+            "Digest username={}, realm={}, uri={}, algorithm={}, nonce={}",
+            quote(username),
+            quote(&self.realm),
+            quote(uri),
+            algo.name(),
+            quote(&self.nonce),
+        );
+        if self.qop_auth {
+            authorization.push_str(&format!(", nc={}, cnonce={}, qop=auth", nc, quote(cnonce)));
+        }
+        authorization.push_str(&format!(", response={}", quote(&response)));
+        if let Some(opaque) = self.opaque.as_ref() {
+            authorization.push_str(&format!(", opaque={}", quote(opaque)));
+        }
+        authorization
+    }
+}
+
+/// Split the value of a `WWW-Authenticate` header in its challenges, as
+/// `(scheme, parameters)` pairs.
+///
+/// The challenges and their parameters are both separated by commas, a
+/// challenge starts with a scheme that is not followed by an `=`.
+fn split_challenges(header_value: &str) -> Vec<(String, String)> {
+    let mut challenges: Vec<(String, String)> = Vec::new();
+    for item in split_list(header_value) {
+        let pos = item
+            .find(|c: char| c == '=' || c.is_ascii_whitespace())
+            .unwrap_or(item.len());
+        let (token, rest) = item.split_at(pos);
+        let rest = rest.trim_start();
+        match challenges.last_mut() {
+            Some((_, params)) if rest.starts_with('=') => {
+                if !params.is_empty() {
+                    params.push_str(", ");
+                }
+                params.push_str(item);
+            }
+            // A parameter without a challenge is ignored.
+            None if rest.starts_with('=') => {}
+            _ => challenges.push((token.to_owned(), rest.to_owned())),
+        }
+    }
+    challenges
+}
+
+/// Split a comma separated list, ignoring the commas of quoted strings.
+/// The empty elements are skipped.
+fn split_list(value: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (idx, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                result.push(value[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(value[start..].trim());
+    result.retain(|item| !item.is_empty());
+    result
+}
+
+/// Parse the `name=value` parameters of a challenge, values may be quoted.
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    let mut result = Vec::new();
+    let mut chars = params.chars().peekable();
+    loop {
+        while let Some(' ') | Some(',') | Some('\t') = chars.peek() {
+            chars.next();
+        }
+        let name: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if name.is_empty() {
+            break;
+        }
+        while let Some(' ') | Some('\t') = chars.peek() {
+            chars.next();
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == ',' {
+                    break;
+                }
+                value.push(*c);
+                chars.next();
+            }
+        }
+        result.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+    result
+}
+
+/// Quote the value of a parameter.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use super::super::client::Client;
+    use super::super::request::RequestBuilder;
+    use super::super::transport::MockTransport;
+
+    // The example of rfc7616, section 3.9.1.
+    const CHALLENGE: &str = "Digest realm=\"http-auth@example.org\", qop=\"auth, auth-int\", \
+                             algorithm=SHA-256, \
+                             nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", \
+                             opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"";
+    const CNONCE: &str = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+
+    #[test]
+    fn test_basic_and_bearer() {
+        assert_eq!(
+            basic("Aladdin", "open sesame"),
+            "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+        assert_eq!(bearer("mF_9.B5f-4.1JqM"), "Bearer mF_9.B5f-4.1JqM");
+    }
+
+    #[test]
+    fn test_parse_challenge() {
+        let challenge = DigestChallenge::parse(CHALLENGE).unwrap();
+        assert_eq!(
+            challenge,
+            DigestChallenge {
+                realm: "http-auth@example.org".to_owned(),
+                nonce: "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v".to_owned(),
+                opaque: Some("FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS".to_owned()),
+                algorithm: Algorithm::SHA256,
+                qop_auth: true,
+            }
+        );
+
+        let challenge = DigestChallenge::parse("digest realm=\"a \\\"b\\\"\",nonce=abc").unwrap();
+        assert_eq!(challenge.realm, "a \"b\"");
+        assert_eq!(challenge.nonce, "abc");
+        assert_eq!(challenge.algorithm, Algorithm::MD5);
+        assert!(!challenge.qop_auth);
+
+        assert_eq!(DigestChallenge::parse("Basic realm=\"test\""), None);
+        assert_eq!(DigestChallenge::parse("Digest realm=\"test\""), None);
+        assert_eq!(
+            DigestChallenge::parse("Digest realm=\"test\", nonce=\"n\", algorithm=SHA-512"),
+            None
+        );
+        assert_eq!(
+            DigestChallenge::parse("Digest realm=\"test\", nonce=\"n\", qop=\"auth-int\""),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_many_challenges() {
+        let challenge = DigestChallenge::parse(
+            "Basic realm=\"basic, realm\", Digest realm=\"test\", algorithm=SHA-512, \
+             nonce=\"n1\", Negotiate, Digest realm=\"test\", nonce=\"n2\", qop=\"auth, auth-int\", \
+             Bearer realm=\"bearer\", Digest realm=\"test\", nonce=\"n3\"",
+        )
+        .unwrap();
+        // The first Digest challenge uses an unsupported algorithm.
+        assert_eq!(
+            challenge,
+            DigestChallenge {
+                realm: "test".to_owned(),
+                nonce: "n2".to_owned(),
+                opaque: None,
+                algorithm: Algorithm::MD5,
+                qop_auth: true,
+            }
+        );
+
+        let challenge =
+            DigestChallenge::parse("Negotiate YIIB==, Digest realm=\"test\", nonce = \"abc\"")
+                .unwrap();
+        assert_eq!(challenge.nonce, "abc");
+
+        assert_eq!(
+            DigestChallenge::parse("Basic realm=\"Digest realm=test, nonce=abc\""),
+            None
+        );
+    }
+
+    #[test]
+    fn test_authorization() {
+        let challenge = DigestChallenge::parse(CHALLENGE).unwrap();
+        let authorization = challenge.authorization_with_cnonce(
+            "Mufasa",
+            "Circle of Life",
+            "GET",
+            "/dir/index.html",
+            CNONCE,
+        );
+        assert_eq!(
+            authorization,
+            "Digest username=\"Mufasa\", realm=\"http-auth@example.org\", \
+             uri=\"/dir/index.html\", algorithm=SHA-256, \
+             nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", nc=00000001, \
+             cnonce=\"f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ\", qop=auth, \
+             response=\"753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1\", \
+             opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\""
+        );
+
+        let challenge = DigestChallenge::parse(&CHALLENGE.replace("SHA-256", "MD5")).unwrap();
+        let authorization = challenge.authorization_with_cnonce(
+            "Mufasa",
+            "Circle of Life",
+            "GET",
+            "/dir/index.html",
+            CNONCE,
+        );
+        assert!(authorization.contains(", response=\"8ca523f5e9506fed4657c9700eebdbec\""));
+    }
+
+    #[test]
+    fn test_authorization_without_qop() {
+        // The example of rfc2069.
+        let challenge = DigestChallenge::parse(
+            "Digest realm=\"testrealm@host.com\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", \
+             opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+        )
+        .unwrap();
+        let authorization =
+            challenge.authorization("Mufasa", "CircleOfLife", "GET", "/dir/index.html");
+        assert_eq!(
+            authorization,
+            "Digest username=\"Mufasa\", realm=\"testrealm@host.com\", \
+             uri=\"/dir/index.html\", algorithm=MD5, \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", \
+             response=\"1949323746fe6a43ef61f9606e7febea\", \
+             opaque=\"5ccc069c403ebaf9f0171e9517f40e41\""
+        );
+    }
+
+    #[async_std::test]
+    async fn test_digest_auth() {
+        let transport = Arc::new(MockTransport::new());
+        transport.add_response(
+            b"HTTP/1.1 401 Unauthorized\r\n\
+              WWW-Authenticate: Digest realm=\"http-auth@example.org\", qop=\"auth\", \
+              algorithm=SHA-256, nonce=\"7ypf\", opaque=\"FQhe\"\r\n\
+              Content-Length: 6\r\n\r\nDenied",
+        );
+        transport.add_response(b"HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello");
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+        let request = RequestBuilder::new("http://example.com/dir/index.html")
+            .digest_auth("Mufasa", "Circle of Life")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert_eq!(transport.connections().len(), 1);
+
+        let requests: Vec<String> = transport
+            .requests()
+            .into_iter()
+            .map(|req| String::from_utf8(req).unwrap())
+            .collect();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[0].contains("Authorization"));
+        assert!(requests[1].starts_with("GET /dir/index.html HTTP/1.1\r\n"));
+        assert!(requests[1].contains(
+            "\r\nAuthorization: Digest username=\"Mufasa\", realm=\"http-auth@example.org\", \
+             uri=\"/dir/index.html\", algorithm=SHA-256, nonce=\"7ypf\", nc=00000001, "
+        ));
+        assert!(requests[1].contains(", qop=auth, response=\""));
+        assert!(requests[1].contains(", opaque=\"FQhe\"\r\n"));
+    }
+
+    #[async_std::test]
+    async fn test_digest_auth_redirect() {
+        let transport = Arc::new(MockTransport::new());
+        let challenge = b"HTTP/1.1 401 Unauthorized\r\n\
+              WWW-Authenticate: Digest realm=\"test\", nonce=\"abc\"\r\n\
+              Content-Length: 6\r\n\r\nDenied";
+        transport.add_response(challenge);
+        transport
+            .add_response(b"HTTP/1.1 302 Found\r\nLocation: /next\r\nContent-Length: 0\r\n\r\n");
+        transport.add_response(challenge);
+        transport.add_response(b"HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello");
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+        let request = RequestBuilder::new("http://example.com/")
+            .digest_auth("Mufasa", "Circle of Life")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+
+        let requests: Vec<String> = transport
+            .requests()
+            .into_iter()
+            .map(|req| String::from_utf8(req).unwrap())
+            .collect();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[1].contains(", uri=\"/\", "));
+        // the Digest response of the previous uri is not sent again.
+        assert!(requests[2].starts_with("GET /next HTTP/1.1\r\n"));
+        assert!(!requests[2].contains("Authorization"));
+        assert!(requests[3].starts_with("GET /next HTTP/1.1\r\n"));
+        assert!(requests[3].contains(", uri=\"/next\", "));
+    }
+
+    #[async_std::test]
+    async fn test_digest_auth_rejected() {
+        let transport = Arc::new(MockTransport::new());
+        for _ in 0..3 {
+            transport.add_response(
+                b"HTTP/1.1 401 Unauthorized\r\n\
+                  WWW-Authenticate: Digest realm=\"test\", nonce=\"abc\"\r\n\
+                  Content-Length: 6\r\n\r\nDenied",
+            );
+        }
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+
+        // the challenge is answered once.
+        let request = RequestBuilder::new("http://example.com/")
+            .digest_auth("Mufasa", "wrong")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 401);
+        assert_eq!(response.body_as_string().unwrap(), "Denied");
+        assert_eq!(transport.requests().len(), 2);
+
+        // without Digest credentials, the challenge is returned.
+        let request = RequestBuilder::new("http://example.com/")
+            .basic_auth("Mufasa", "wrong")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 401);
+        assert_eq!(transport.requests().len(), 3);
+    }
+}
@@ -4,23 +4,30 @@ extern crate log;
 use cabot;
 
 use std::collections::HashMap;
+use std::env;
+use std::fs;
 use std::iter::FromIterator;
 use std::net::{AddrParseError, SocketAddr};
-use std::pin::Pin;
+use std::path::Path;
+use std::sync::Arc;
 
 use async_std;
 use async_std::fs::{File, OpenOptions};
 use async_std::io::{self, Stdout, Write};
 use async_std::prelude::*;
-use async_std::task::Context;
-use async_std::task::Poll;
 use clap::{App, Arg};
 use log::Level::Info;
+use percent_encoding::percent_decode_str;
+use url::Url;
 
 use cabot::constants;
-use cabot::http;
+use cabot::proxy::{Proxies, Proxy};
+use cabot::redirect::RedirectPolicy;
 use cabot::request::RequestBuilder;
-use cabot::results::CabotResult;
+use cabot::response::Response;
+use cabot::results::{CabotError, CabotResult};
+use cabot::retry::RetryPolicy;
+use cabot::{Client, CookieJar, TLSConfig};
 
 macro_rules! parse_int {
     ($name:expr, $typ:ty, $matches:ident) => {
@@ -136,7 +143,112 @@ pub async fn run() -> CabotResult<()> {
                 .long("max-redirs")
                 .takes_value(true)
                 .default_value(number_of_redirect.as_str())
-                .help("max number of redirection before returning a response"),
+                .help("max number of redirection before returning a response, redirections from https to http are refused unless --allow-https-downgrade is set"),
+        )
+        .arg(
+            Arg::with_name("ALLOW_HTTPS_DOWNGRADE")
+                .long("allow-https-downgrade")
+                .help("Follow the redirections from https to http"),
+        )
+        .arg(
+            Arg::with_name("RETRY")
+                .long("retry")
+                .takes_value(true)
+                .default_value("0")
+                .help("<num> Retry the request on transient errors"),
+        )
+        .arg(
+            Arg::with_name("RETRY_DELAY")
+                .long("retry-delay")
+                .takes_value(true)
+                .default_value("0")
+                .help("<seconds> Wait time between retries, 0 means an exponential backoff"),
+        )
+        .arg(
+            Arg::with_name("RETRY_MAX_TIME")
+                .long("retry-max-time")
+                .takes_value(true)
+                .default_value("0")
+                .help("<seconds> Stop retrying after this time, 0 means no limit"),
+        )
+        .arg(
+            Arg::with_name("MAX_FILESIZE")
+                .long("max-filesize")
+                .takes_value(true)
+                .default_value("0")
+                .help("<bytes> Fail if the response body is larger, 0 means no limit"),
+        )
+        .arg(
+            Arg::with_name("COMPRESSED")
+                .long("compressed")
+                .help("Request a compressed response and decompress it"),
+        )
+        .arg(
+            Arg::with_name("CACERT")
+                .long("cacert")
+                .takes_value(true)
+                .multiple(true)
+                .help("<file> CA certificate to verify peer against (PEM or DER) instead of the default ones"),
+        )
+        .arg(
+            Arg::with_name("CAPATH")
+                .long("capath")
+                .takes_value(true)
+                .multiple(true)
+                .help("<dir> CA directory to verify peer against instead of the default ones"),
+        )
+        .arg(
+            Arg::with_name("CERT")
+                .short("E")
+                .long("cert")
+                .takes_value(true)
+                .help("<file> Client certificate chain file (PEM)"),
+        )
+        .arg(
+            Arg::with_name("KEY")
+                .long("key")
+                .takes_value(true)
+                .requires("CERT")
+                .help("<file> Private key file of the client certificate (PEM), read from the certificate file if omitted"),
+        )
+        .arg(
+            Arg::with_name("COOKIE")
+                .short("b")
+                .long("cookie")
+                .takes_value(true)
+                .help("<data|filename> Send cookies from string or read them from a Netscape cookie file"),
+        )
+        .arg(
+            Arg::with_name("COOKIE_JAR")
+                .short("c")
+                .long("cookie-jar")
+                .takes_value(true)
+                .help("<filename> Write the cookies to this file after the operation"),
+        )
+        .arg(
+            Arg::with_name("USER")
+                .short("u")
+                .long("user")
+                .takes_value(true)
+                .help("<user:password> Server user and password, sent using the Basic scheme by default"),
+        )
+        .arg(
+            Arg::with_name("DIGEST")
+                .long("digest")
+                .help("Use the Digest authentication scheme, with the credentials of -u or of the URL"),
+        )
+        .arg(
+            Arg::with_name("PROXY")
+                .short("x")
+                .long("proxy")
+                .takes_value(true)
+                .help("<[protocol://][user:password@]proxyhost[:port]> Use this proxy, instead of the http_proxy, https_proxy and all_proxy environment variables"),
+        )
+        .arg(
+            Arg::with_name("SOCKS5")
+                .long("socks5")
+                .takes_value(true)
+                .help("<[user:password@]host[:port]> Use this SOCKS5 proxy, overrides -x"),
         )
         .arg(
             Arg::with_name("RESOLVE")
@@ -151,6 +263,7 @@ pub async fn run() -> CabotResult<()> {
     let http_method = matches.value_of("REQUEST").unwrap();
     let verbose = matches.is_present("VERBOSE");
     let body = matches.value_of("BODY");
+    let compressed = matches.is_present("COMPRESSED");
     let ua = matches.value_of("UA").unwrap();
 
     let mut ipv4 = matches.is_present("IPv4");
@@ -217,6 +330,19 @@ pub async fn run() -> CabotResult<()> {
     let read_timeout = parse_int!("READ_TIMEOUT", u64, matches) * 1_000;
     let request_timeout = parse_int!("REQUEST_TIMEOUT", u64, matches) * 1_000;
     let number_of_redirect = parse_int!("NUMBER_OF_REDIRECT", u8, matches);
+    let mut redirect_policy = RedirectPolicy::limited(number_of_redirect);
+    redirect_policy.set_allow_https_downgrade(matches.is_present("ALLOW_HTTPS_DOWNGRADE"));
+    let max_body_size = parse_int!("MAX_FILESIZE", usize, matches);
+
+    let mut retry_policy = RetryPolicy::new(parse_int!("RETRY", u32, matches));
+    let retry_delay = parse_int!("RETRY_DELAY", u64, matches) * 1_000;
+    if retry_delay > 0 {
+        // like curl, a given delay disables the exponential backoff.
+        retry_policy.set_base_delay_ms(retry_delay);
+        retry_policy.set_max_delay_ms(retry_delay);
+        retry_policy.set_jitter(false);
+    }
+    retry_policy.set_max_time_ms(parse_int!("RETRY_MAX_TIME", u64, matches) * 1_000);
 
     let mut builder = RequestBuilder::new(url)
         .set_http_method(http_method)
@@ -227,12 +353,109 @@ pub async fn run() -> CabotResult<()> {
         builder = builder.set_body_as_str(body.unwrap());
     }
 
+    let digest = matches.is_present("DIGEST");
+    if let Some(user) = matches.value_of("USER") {
+        let mut credentials = user.splitn(2, ':');
+        let username = credentials.next().unwrap();
+        let password = credentials.next().unwrap_or("");
+        builder = if digest {
+            builder.digest_auth(username, password)
+        } else {
+            builder.basic_auth(username, password)
+        };
+    } else if digest {
+        // the credentials of the url are sent using the Basic scheme otherwise.
+        if let Ok(url) = Url::parse(url) {
+            if !url.username().is_empty() {
+                let username = percent_decode_str(url.username()).decode_utf8_lossy();
+                let password = percent_decode_str(url.password().unwrap_or("")).decode_utf8_lossy();
+                builder = builder.digest_auth(&username, &password);
+            }
+        }
+    }
+
+    let cookie_jar = Arc::new(CookieJar::new());
+    if let Some(cookie) = matches.value_of("COOKIE") {
+        if cookie.contains('=') {
+            builder = builder.add_header(&format!("Cookie: {}", cookie));
+        } else if Path::new(cookie).exists() {
+            cookie_jar.load(Path::new(cookie))?;
+        }
+    }
+
     let request = builder.build()?;
 
+    let mut tls_config = TLSConfig::new();
+    if matches.is_present("CACERT") || matches.is_present("CAPATH") {
+        // like curl, the given certificate authorities replace the default ones.
+        tls_config.set_default_root_certificates(false);
+    }
+    if let Some(cacerts) = matches.values_of("CACERT") {
+        for cacert in cacerts {
+            tls_config.add_root_certificates_file(Path::new(cacert))?;
+        }
+    }
+    if let Some(capaths) = matches.values_of("CAPATH") {
+        for capath in capaths {
+            tls_config.add_root_certificates_dir(Path::new(capath))?;
+        }
+    }
+    if let Some(cert) = matches.value_of("CERT") {
+        let key = matches.value_of("KEY").unwrap_or(cert);
+        let cert = fs::read(cert)?;
+        let key = fs::read(key)?;
+        tls_config.set_client_certificate(&cert, &key)?;
+    }
+
+    let proxy = match (matches.value_of("SOCKS5"), matches.value_of("PROXY")) {
+        (Some(proxy), _) => Some(Proxy::new(&format!("socks5://{}", proxy))?),
+        (None, Some(proxy)) => Some(Proxy::new(proxy)?),
+        (None, None) => None,
+    };
+    let proxies = match (Proxies::from_env(), proxy) {
+        (Ok(mut proxies), Some(proxy)) => {
+            proxies.set_proxy(proxy);
+            proxies
+        }
+        (Ok(proxies), None) => proxies,
+        // the invalid variables are replaced by the proxy option.
+        (Err(err), Some(proxy)) => {
+            eprintln!("Ignoring the proxy environment variables: {}", err);
+            let mut proxies = Proxies::new();
+            proxies.set_proxy(proxy);
+            if let Ok(no_proxy) = env::var("no_proxy").or_else(|_| env::var("NO_PROXY")) {
+                proxies.set_no_proxy(&no_proxy);
+            }
+            proxies
+        }
+        (Err(err), None) => return Err(err),
+    };
+
+    let mut client = Client::new();
+    client.set_verbose(verbose);
+    client.set_ip_version(ipv4, ipv6);
+    for (authority, sockaddr) in resolved.iter() {
+        client.add_authority(authority, sockaddr);
+    }
+    client.set_dns_timeout_ms(dns_timeout);
+    client.set_connect_timeout_ms(connect_timeout);
+    client.set_read_timeout_ms(read_timeout);
+    client.set_request_timeout_ms(request_timeout);
+    client.set_redirect_policy(redirect_policy);
+    client.set_retry_policy(retry_policy);
+    client.set_compressed(compressed);
+    client.set_max_body_size(max_body_size);
+    client.set_tls_config(tls_config);
+    client.set_proxies(proxies);
+    client.set_cookie_jar(cookie_jar.clone());
+    // The command line performs a single query, connections are not kept alive
+    // in order to close them as soon as the response is read.
+    client.set_max_idle_per_host(0);
+
     let mut file: Option<File>;
     let mut stdout: Option<Stdout>;
 
-    let mut out = if let Some(path) = matches.value_of("FILE") {
+    let out: &mut (dyn Write + Unpin + Send) = if let Some(path) = matches.value_of("FILE") {
         let f = OpenOptions::new()
             .write(true)
             .create(true)
@@ -241,27 +464,20 @@ pub async fn run() -> CabotResult<()> {
             .await
             .unwrap();
         file = Some(f);
-        CabotBinWrite::new(file.as_mut().unwrap(), verbose)
+        file.as_mut().unwrap()
     } else {
         stdout = Some(io::stdout());
-        CabotBinWrite::new(stdout.as_mut().unwrap(), verbose)
+        stdout.as_mut().unwrap()
     };
 
-    http::http_query(
-        &request,
-        &mut out,
-        &resolved,
-        verbose,
-        ipv4,
-        ipv6,
-        dns_timeout,
-        connect_timeout,
-        read_timeout,
-        request_timeout,
-        number_of_redirect,
-    )
-    .await?;
-    Ok(())
+    let result = match client.execute_stream(&request).await {
+        Ok(response) => write_response(response, out, verbose).await,
+        Err(err) => Err(err),
+    };
+    if let Some(path) = matches.value_of("COOKIE_JAR") {
+        cookie_jar.save(Path::new(path))?;
+    }
+    result
 }
 
 #[async_std::main]
@@ -285,70 +501,48 @@ async fn main() {
 
 // Internal Of the Binary
 
-struct CabotBinWrite<'a> {
-    out: &'a mut (dyn Write + Unpin),
-    header_read: bool,
+/// Display the response headers in verbose mode, then write the body
+/// to `out` while it is read.
+async fn write_response(
+    response: Response,
+    out: &mut (dyn Write + Unpin + Send),
     verbose: bool,
-}
-
-impl<'a> CabotBinWrite<'a> {
-    pub fn new(out: &'a mut (dyn Write + Unpin), verbose: bool) -> Self {
-        CabotBinWrite {
-            out,
-            verbose,
-            header_read: false,
-        }
+) -> CabotResult<()> {
+    if log_enabled!(Info) || verbose {
+        let head = format!(
+            "{} {}\r\n{}\r\n",
+            response.http_version(),
+            response.status_line(),
+            response.headers()
+        );
+        display_headers(&head, verbose);
     }
-    fn display_headers(&self, buf: &[u8]) {
-        for hdr in buf.split(|&x| x == b'\n') {
-            let hdr = String::from_utf8_lossy(hdr);
-            if log_enabled!(Info) {
-                info!("< {}", hdr);
-            } else if self.verbose {
-                eprintln!("< {}", hdr);
-            }
+    if let Some(mut body) = response.into_body_stream() {
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(query_error)?;
+            out.write_all(&chunk).await?;
         }
     }
+    out.flush().await?;
+    Ok(())
 }
 
-impl<'a> Write for CabotBinWrite<'a> {
-    // may receive headers
-    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
-        let self_ = Pin::get_mut(self);
-        if !self_.header_read {
-            // the first time write is called, all headers are sent
-            // in the buffer. there is no need to parse it again.
-            if log_enabled!(Info) || self_.verbose {
-                self_.display_headers(&buf);
-            }
-            self_.header_read = true;
-            Poll::Ready(Ok(0))
-        } else {
-            let towrite = buf.len();
-            let mut written = 0;
-            loop {
-                let res = Pin::new(&mut self_.out).poll_write(cx, &buf[written..towrite]);
-                match res {
-                    Poll::Ready(Ok(l)) => written += l,
-                    _ => {}
-                }
-                if written >= towrite {
-                    break;
-                }
-            }
-            Poll::Ready(Ok(written))
+fn display_headers(head: &str, verbose: bool) {
+    for hdr in head.split('\n') {
+        if log_enabled!(Info) {
+            info!("< {}", hdr);
+        } else if verbose {
+            eprintln!("< {}", hdr);
         }
     }
+}
 
-    /// this function is called when the request is done
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
-        let self_ = Pin::get_mut(self);
-        self_.out.flush();
-        Poll::Ready(Ok(()))
-    }
-
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
-        let self_ = Pin::get_mut(self);
-        Pin::new(&mut self_.out).poll_close(cx)
+/// The error of the query, forwarded by the body stream of the response.
+fn query_error(err: io::Error) -> CabotError {
+    match err.get_ref() {
+        Some(inner) if inner.is::<CabotError>() => {
+            *err.into_inner().unwrap().downcast::<CabotError>().unwrap()
+        }
+        _ => CabotError::IOError(err),
     }
 }
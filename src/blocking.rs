@@ -0,0 +1,441 @@
+//! A blocking client, to perform queries from synchronous code.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use cabot::blocking::Client;
+//! use cabot::RequestBuilder;
+//!
+//! let request = RequestBuilder::new("https://www.rust-lang.org/")
+//!     .build()
+//!     .unwrap();
+//! let client = Client::new();
+//! let response = client.execute(&request).unwrap();
+//! println!("{}", response.body_as_string().unwrap());
+//! ```
+//!
+//! The queries are performed by an executor running in a thread owned by the
+//! client, the executor is shared by the clones of the client and stopped once
+//! they are all dropped. The client can be used from many threads at once,
+//! their queries run concurrently.
+//!
+//! The blocking client must not be used from an async task, it would block
+//! the thread of the task until the response is received.
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use async_std::io;
+use futures::channel::{mpsc, oneshot};
+use futures::executor::{self, LocalPool};
+use futures::future::LocalBoxFuture;
+use futures::stream::StreamExt;
+use futures::task::LocalSpawnExt;
+use rustls::ClientConfig;
+
+use super::asynctls::TLSConfig;
+use super::client::Client as AsyncClient;
+use super::cookies::CookieJar;
+use super::dns::{DnsCache, Resolve};
+use super::middleware::Middleware;
+use super::proxy::{Proxies, Proxy};
+use super::redirect::RedirectPolicy;
+use super::request::Request;
+use super::response::Response;
+use super::results::{CabotError, CabotResult};
+use super::retry::RetryPolicy;
+use super::transport::Transport;
+
+/// A query to run on the executor thread.
+type Job = Box<dyn FnOnce() -> LocalBoxFuture<'static, ()> + Send>;
+
+/// Perform the http query, blocking the current thread until the response
+/// has been received.
+///
+/// It is configured like the [async client](../client/struct.Client.html),
+/// cloned clients share their connections pool, their DNS cache, their cookie
+/// jar and their executor. The settings of the pool and of the cache are
+/// changed for every clone.
+#[derive(Clone)]
+pub struct Client {
+    inner: AsyncClient,
+    executor: Arc<Executor>,
+}
+
+impl Client {
+    /// Construct a new `Client`, and start its executor.
+    pub fn new() -> Self {
+        Client::from(AsyncClient::new())
+    }
+
+    /// Write the progress of the queries to stderr, as the command line
+    /// does in verbose mode.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.inner.set_verbose(verbose);
+    }
+
+    /// Set Address Type authorized for DNS resolution.
+    pub fn set_ip_version(&mut self, ipv4: bool, ipv6: bool) {
+        self.inner.set_ip_version(ipv4, ipv6);
+    }
+
+    /// Force an authority, "host:port", to be resolved to the `sock_addr`.
+    pub fn add_authority(&mut self, authority: &str, sock_addr: &SocketAddr) {
+        self.inner.add_authority(authority, sock_addr);
+    }
+
+    /// Set the resolver used to resolve the host names.
+    pub fn set_resolver(&mut self, resolver: Arc<dyn Resolve>) {
+        self.inner.set_resolver(resolver);
+    }
+
+    /// Set the time in seconds a resolution is cached, `0` disable the cache.
+    pub fn set_dns_cache_ttl(&mut self, ttl: u64) {
+        self.inner.set_dns_cache_ttl(ttl);
+    }
+
+    /// Set the time in milliseconds a resolution is cached.
+    pub fn set_dns_cache_ttl_ms(&mut self, ttl: u64) {
+        self.inner.set_dns_cache_ttl_ms(ttl);
+    }
+
+    /// Set the time in seconds a failed resolution is cached.
+    pub fn set_dns_negative_cache_ttl(&mut self, ttl: u64) {
+        self.inner.set_dns_negative_cache_ttl(ttl);
+    }
+
+    /// Set the time in milliseconds a failed resolution is cached.
+    pub fn set_dns_negative_cache_ttl_ms(&mut self, ttl: u64) {
+        self.inner.set_dns_negative_cache_ttl_ms(ttl);
+    }
+
+    /// The DNS cache of the client.
+    pub fn dns_cache(&self) -> &Arc<DnsCache> {
+        self.inner.dns_cache()
+    }
+
+    /// Set the transport opening the connections.
+    pub fn set_transport(&mut self, transport: Arc<dyn Transport>) {
+        self.inner.set_transport(transport);
+    }
+
+    /// Set the timeout for DNS resolution in seconds.
+    pub fn set_dns_timeout(&mut self, timeout: u64) {
+        self.inner.set_dns_timeout(timeout);
+    }
+
+    /// Set the connect socket timeout in seconds.
+    pub fn set_connect_timeout(&mut self, timeout: u64) {
+        self.inner.set_connect_timeout(timeout);
+    }
+
+    /// Set the read socket timeout in seconds.
+    pub fn set_read_timeout(&mut self, timeout: u64) {
+        self.inner.set_read_timeout(timeout);
+    }
+
+    /// Set the request timeout in seconds.
+    pub fn set_request_timeout(&mut self, timeout: u64) {
+        self.inner.set_request_timeout(timeout);
+    }
+
+    /// Set the timeout for DNS resolution in milliseconds.
+    pub fn set_dns_timeout_ms(&mut self, timeout: u64) {
+        self.inner.set_dns_timeout_ms(timeout);
+    }
+
+    /// Set the connect socket timeout in milliseconds.
+    pub fn set_connect_timeout_ms(&mut self, timeout: u64) {
+        self.inner.set_connect_timeout_ms(timeout);
+    }
+
+    /// Set the read socket timeout in milliseconds.
+    pub fn set_read_timeout_ms(&mut self, timeout: u64) {
+        self.inner.set_read_timeout_ms(timeout);
+    }
+
+    /// Set the request timeout in milliseconds.
+    pub fn set_request_timeout_ms(&mut self, timeout: u64) {
+        self.inner.set_request_timeout_ms(timeout);
+    }
+
+    /// Set the number of redirection to follow before giving up and return it.
+    pub fn set_max_redir(&mut self, max_redir: u8) {
+        self.inner.set_max_redir(max_redir);
+    }
+
+    /// Follow the redirections from https to http.
+    pub fn set_allow_https_downgrade(&mut self, allowed: bool) {
+        self.inner.set_allow_https_downgrade(allowed);
+    }
+
+    /// Set the policy deciding which redirections are followed.
+    pub fn set_redirect_policy(&mut self, redirect_policy: RedirectPolicy) {
+        self.inner.set_redirect_policy(redirect_policy);
+    }
+
+    /// Set the policy to retry the requests failing on transient errors.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.inner.set_retry_policy(retry_policy);
+    }
+
+    /// Ask the server to compress the response using gzip, deflate or brotli.
+    pub fn set_compressed(&mut self, compressed: bool) {
+        self.inner.set_compressed(compressed);
+    }
+
+    /// Set the max size of the response body in bytes, `0` means no limit.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.inner.set_max_body_size(max_body_size);
+    }
+
+    /// Trust the webpki-roots certificates.
+    pub fn set_default_root_certificates(&mut self, enabled: bool) {
+        self.inner.set_default_root_certificates(enabled);
+    }
+
+    /// Trust a certificate authority in the DER format.
+    pub fn add_root_certificate_der(&mut self, der: &[u8]) -> CabotResult<()> {
+        self.inner.add_root_certificate_der(der)
+    }
+
+    /// Trust the certificate authorities of a PEM bundle,
+    /// return the number of certificates added.
+    pub fn add_root_certificates_pem(&mut self, pem: &[u8]) -> CabotResult<usize> {
+        self.inner.add_root_certificates_pem(pem)
+    }
+
+    /// Trust the certificate authorities of the operating system store,
+    /// return the number of certificates added.
+    pub fn add_native_root_certificates(&mut self) -> CabotResult<usize> {
+        self.inner.add_native_root_certificates()
+    }
+
+    /// Set the certificate chain and its private key, in the PEM format,
+    /// presented to servers that require client authentication.
+    pub fn set_client_certificate(
+        &mut self,
+        cert_chain: &[u8],
+        private_key: &[u8],
+    ) -> CabotResult<()> {
+        self.inner.set_client_certificate(cert_chain, private_key)
+    }
+
+    /// Replace the TLS configuration, the root certificates and the client
+    /// certificate set on the client so far are dropped.
+    pub fn set_tls_config(&mut self, tls_config: TLSConfig) {
+        self.inner.set_tls_config(tls_config);
+    }
+
+    /// Use the given rustls configuration for HTTPS connections.
+    pub fn set_tls_client_config(&mut self, client_config: Arc<ClientConfig>) {
+        self.inner.set_tls_client_config(client_config);
+    }
+
+    /// Set the number of idle connections kept alive per authority.
+    pub fn set_max_idle_per_host(&mut self, max_idle_per_host: usize) {
+        self.inner.set_max_idle_per_host(max_idle_per_host);
+    }
+
+    /// Set the time in seconds an idle connection is kept alive.
+    pub fn set_idle_timeout(&mut self, timeout: u64) {
+        self.inner.set_idle_timeout(timeout);
+    }
+
+    /// Set the time in milliseconds an idle connection is kept alive.
+    pub fn set_idle_timeout_ms(&mut self, timeout: u64) {
+        self.inner.set_idle_timeout_ms(timeout);
+    }
+
+    /// Replace the cookie jar of the client.
+    pub fn set_cookie_jar(&mut self, cookie_jar: Arc<CookieJar>) {
+        self.inner.set_cookie_jar(cookie_jar);
+    }
+
+    /// The cookie jar storing the cookies received by the client.
+    pub fn cookie_jar(&self) -> &Arc<CookieJar> {
+        self.inner.cookie_jar()
+    }
+
+    /// Send every request through the `proxy`.
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.inner.set_proxy(proxy);
+    }
+
+    /// Set the comma separated list of hosts reached without proxy.
+    pub fn set_no_proxy(&mut self, no_proxy: &str) {
+        self.inner.set_no_proxy(no_proxy);
+    }
+
+    /// Set the proxies per scheme.
+    pub fn set_proxies(&mut self, proxies: Proxies) {
+        self.inner.set_proxies(proxies);
+    }
+
+    /// Add a middleware called for every request sent and every response
+    /// received.
+    pub fn add_middleware(&mut self, middleware: Arc<dyn Middleware>) {
+        self.inner.add_middleware(middleware);
+    }
+
+    /// Execute the [Request](../request/struct.Request.html) and
+    /// return the associate [Response](../response/struct.Response.html),
+    /// once its body has been read.
+    pub fn execute(&self, request: &Request) -> CabotResult<Response> {
+        let client = self.inner.clone();
+        let request = request.clone();
+        let (sender, receiver) = oneshot::channel();
+        self.executor.run(Box::new(move || {
+            Box::pin(async move {
+                let _ = sender.send(client.execute(&request).await);
+            })
+        }))?;
+        executor::block_on(receiver).map_err(|_| executor_stopped())?
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new()
+    }
+}
+
+impl From<AsyncClient> for Client {
+    /// Use the configuration of the async client, the clients share their
+    /// connections pool, their DNS cache and their cookie jar.
+    fn from(client: AsyncClient) -> Self {
+        Client {
+            inner: client,
+            executor: Arc::new(Executor::new()),
+        }
+    }
+}
+
+/// Run the queries in a dedicated thread, the thread is stopped when the
+/// executor is dropped.
+struct Executor {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl Executor {
+    fn new() -> Self {
+        let (jobs, mut receiver) = mpsc::unbounded::<Job>();
+        thread::Builder::new()
+            .name("cabot-blocking".to_owned())
+            .spawn(move || {
+                let mut pool = LocalPool::new();
+                let spawner = pool.spawner();
+                pool.run_until(async move {
+                    while let Some(job) = receiver.next().await {
+                        if spawner.spawn_local(job()).is_err() {
+                            break;
+                        }
+                    }
+                });
+            })
+            .expect("Unable to start the thread of the blocking client");
+        Executor { jobs }
+    }
+
+    /// Spawn the job on the executor thread.
+    fn run(&self, job: Job) -> CabotResult<()> {
+        self.jobs
+            .unbounded_send(job)
+            .map_err(|_| executor_stopped())
+    }
+}
+
+fn executor_stopped() -> CabotError {
+    CabotError::IOError(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "The executor of the blocking client has stopped",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::request::RequestBuilder;
+    use super::super::test_server::{Route, TestServerBuilder, LOCALHOST_CA};
+    use super::super::transport::MockTransport;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_client_is_send_sync() {
+        assert_send_sync::<Client>();
+    }
+
+    #[test]
+    fn test_execute() {
+        let transport = Arc::new(MockTransport::new());
+        transport.add_response(b"HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello");
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert!(transport.requests()[0].starts_with(b"GET / HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn test_execute_from_threads() {
+        let transport = Arc::new(MockTransport::new());
+        for _ in 0..8 {
+            transport.add_response(b"HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello");
+        }
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+        let client = Arc::new(client);
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    let request = RequestBuilder::new("http://localhost/").build().unwrap();
+                    client.execute(&request).unwrap()
+                })
+            })
+            .collect();
+        for thread in threads {
+            let response = thread.join().unwrap();
+            assert_eq!(response.body_as_string().unwrap(), "Hello");
+        }
+        assert_eq!(transport.requests().len(), 8);
+    }
+
+    #[test]
+    fn test_tls_config() {
+        let server = executor::block_on(
+            TestServerBuilder::new()
+                .add_route("/", Route::text("Hello"))
+                .set_localhost_tls()
+                .start(),
+        )
+        .unwrap();
+        let mut tls_config = TLSConfig::new();
+        tls_config.set_default_root_certificates(false);
+        tls_config.add_root_certificates_pem(LOCALHOST_CA).unwrap();
+        let mut client = Client::new();
+        client.set_tls_config(tls_config);
+        client.add_authority(
+            &format!("localhost:{}", server.addr().port()),
+            &server.addr(),
+        );
+        let request = RequestBuilder::new(&server.url("/")).build().unwrap();
+        let response = client.execute(&request).unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+    }
+
+    #[test]
+    fn test_execute_error() {
+        let transport = Arc::new(MockTransport::new());
+        transport.add_response(b"NOT HTTP\r\n\r\n");
+        let mut client = Client::new();
+        client.set_transport(transport);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let err = client.execute(&request).unwrap_err();
+        assert!(matches!(err, CabotError::MalformedStatusLine(_, _)));
+    }
+}
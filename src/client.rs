@@ -1,123 +1,352 @@
 //! The HTTP Client that perform query
-use std::collections::HashMap;
 use std::mem;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
+use futures::channel::{mpsc, oneshot};
 use futures::future::{BoxFuture, Future};
+use futures::sink::{Sink, SinkExt};
+use rustls::ClientConfig;
 
 use async_std::io::{self, Write};
-use async_std::task::Context;
-use async_std::task::Poll;
-
-use super::constants;
-use super::http;
+use async_std::task::{self, Context, Poll};
+
+use super::asynctls::TLSConfig;
+use super::cookies::CookieJar;
+use super::dns::{DnsCache, Resolve};
+use super::http::{self, QueryConfig, QueryInfo};
+use super::middleware::Middleware;
+use super::proxy::{Proxies, Proxy};
+use super::redirect::RedirectPolicy;
 use super::request::Request;
-use super::response::{Response, ResponseBuilder};
-use super::results::CabotResult;
+use super::response::{parse_headers, BodyStream, Response, ResponseBuilder};
+use super::results::{CabotError, CabotResult};
+use super::retry::RetryPolicy;
+use super::transport::Transport;
+
+/// Number of body chunks bufferized while the body stream is not consumed.
+const BODY_STREAM_BUFFER_SIZE: usize = 16;
 
 /// Perform the http query
-#[derive(Default)]
+///
+/// Cloned clients share their connections pool, their DNS cache and their
+/// cookie jar, the settings of the pool and of the cache are changed for
+/// every clone. The other settings are copied, changing them does not alter
+/// the clones. The client is `Send` and `Sync`, it can be shared between
+/// tasks in an `Arc` to perform concurrent queries.
+#[derive(Clone)]
 pub struct Client {
-    verbose: bool,
-    ipv4: bool,
-    ipv6: bool,
-    authorities: HashMap<String, SocketAddr>,
-    read_timeout: u64,
-    connect_timeout: u64,
-    dns_timeout: u64,
-    request_timeout: u64,
-    max_redir: u8,
+    config: QueryConfig,
 }
 
 impl<'a> Client {
     /// Construct a new `Client`
     pub fn new() -> Self {
         Client {
-            verbose: false,
-            ipv4: true,
-            ipv6: true,
-            authorities: HashMap::new(),
-            dns_timeout: constants::DNS_LOOKUP_TIMEOUT * 1000,
-            connect_timeout: constants::CONNECT_TIMEOUT * 1000,
-            read_timeout: constants::READ_TIMEOUT * 1000,
-            request_timeout: constants::REQUEST_TIMEOUT * 1000,
-            max_redir: constants::NUMBER_OF_REDIRECT,
+            config: QueryConfig::default(),
         }
     }
 
+    /// Write the progress of the queries to stderr, as the command line
+    /// does in verbose mode.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.config.verbose = verbose;
+    }
+
     /// Set Address Type authorized for DNS resolution.
     /// If every version is set to false, the resolution will failed.
     pub fn set_ip_version(&mut self, ipv4: bool, ipv6: bool) {
-        self.ipv4 = ipv4;
-        self.ipv6 = ipv6;
+        self.config.ipv4 = ipv4;
+        self.config.ipv6 = ipv6;
     }
 
     /// Avoid DNS resolution, force an address to be resolve to a given endpoint.
     /// authority has the format "host:port".
     pub fn add_authority(&mut self, authority: &str, sock_addr: &SocketAddr) {
-        self.authorities
+        self.config
+            .authorities
             .insert(authority.to_owned(), sock_addr.clone());
     }
 
+    /// Set the resolver used to resolve the host names that are not in
+    /// the authorities map, the system resolver by default.
+    pub fn set_resolver(&mut self, resolver: Arc<dyn Resolve>) {
+        self.config.resolver = resolver;
+    }
+
+    /// Set the time in seconds a resolution is cached, `0` disable the cache.
+    ///
+    /// The DNS cache is shared, the clones of the client use this ttl too.
+    pub fn set_dns_cache_ttl(&mut self, ttl: u64) {
+        self.config.dns_cache.set_ttl(ttl * 1000);
+    }
+
+    /// Set the time in milliseconds a resolution is cached, for the clones
+    /// of the client too.
+    pub fn set_dns_cache_ttl_ms(&mut self, ttl: u64) {
+        self.config.dns_cache.set_ttl(ttl);
+    }
+
+    /// Set the time in seconds a failed resolution is cached,
+    /// `0` disable the negative cache.
+    ///
+    /// The DNS cache is shared, the clones of the client use this ttl too.
+    pub fn set_dns_negative_cache_ttl(&mut self, ttl: u64) {
+        self.config.dns_cache.set_negative_ttl(ttl * 1000);
+    }
+
+    /// Set the time in milliseconds a failed resolution is cached, for the
+    /// clones of the client too.
+    pub fn set_dns_negative_cache_ttl_ms(&mut self, ttl: u64) {
+        self.config.dns_cache.set_negative_ttl(ttl);
+    }
+
+    /// The DNS cache of the client, to flush or prefill its entries.
+    pub fn dns_cache(&self) -> &Arc<DnsCache> {
+        &self.config.dns_cache
+    }
+
+    /// Set the transport opening the connections, instead of connecting
+    /// using TCP and TLS. The authorities map, the resolver and the TLS
+    /// configuration are not used by custom transports.
+    pub fn set_transport(&mut self, transport: Arc<dyn Transport>) {
+        self.config.transport = Some(transport);
+    }
+
     /// Set the timeout for DNS resolution in seconds.
     pub fn set_dns_timeout(&mut self, timeout: u64) {
-        self.dns_timeout = timeout * 1000;
+        self.config.dns_timeout = timeout * 1000;
     }
     /// Set the connect socket timeout in seconds.
     pub fn set_connect_timeout(&mut self, timeout: u64) {
-        self.connect_timeout = timeout * 1000;
+        self.config.connect_timeout = timeout * 1000;
     }
     /// Set the read socket timeout in seconds.
     pub fn set_read_timeout(&mut self, timeout: u64) {
-        self.read_timeout = timeout * 1000;
+        self.config.read_timeout = timeout * 1000;
     }
     /// Set the request timeout in milliseconds.
     pub fn set_request_timeout(&mut self, timeout: u64) {
-        self.request_timeout = timeout * 1000;
+        self.config.request_timeout = timeout * 1000;
     }
 
     /// Set the timeout for DNS resolution in milliseconds.
     pub fn set_dns_timeout_ms(&mut self, timeout: u64) {
-        self.dns_timeout = timeout;
+        self.config.dns_timeout = timeout;
     }
     /// Set the connect socket timeout in milliseconds.
     pub fn set_connect_timeout_ms(&mut self, timeout: u64) {
-        self.connect_timeout = timeout;
+        self.config.connect_timeout = timeout;
     }
     /// Set the read socket timeout in milliseconds.
     pub fn set_read_timeout_ms(&mut self, timeout: u64) {
-        self.read_timeout = timeout;
+        self.config.read_timeout = timeout;
     }
 
     /// Set the request timeout in milliseconds.
     pub fn set_request_timeout_ms(&mut self, timeout: u64) {
-        self.request_timeout = timeout;
+        self.config.request_timeout = timeout;
     }
 
     /// Set the number of redirection to follow before giving up and return it.
+    ///
+    /// The redirections from https to http are still followed if they have
+    /// been allowed.
     pub fn set_max_redir(&mut self, max_redir: u8) {
-        self.max_redir = max_redir;
+        let allow_downgrade = self.config.redirect_policy.allow_https_downgrade();
+        let mut redirect_policy = RedirectPolicy::limited(max_redir);
+        redirect_policy.set_allow_https_downgrade(allow_downgrade);
+        self.config.redirect_policy = redirect_policy;
+    }
+
+    /// Follow the redirections from https to http, they are refused by
+    /// default.
+    pub fn set_allow_https_downgrade(&mut self, allowed: bool) {
+        self.config
+            .redirect_policy
+            .set_allow_https_downgrade(allowed);
+    }
+
+    /// Set the policy deciding which redirections are followed,
+    /// replacing the max number of redirections.
+    pub fn set_redirect_policy(&mut self, redirect_policy: RedirectPolicy) {
+        self.config.redirect_policy = redirect_policy;
+    }
+
+    /// Set the policy to retry the requests failing on transient errors,
+    /// requests are not retried by default.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.config.retry_policy = retry_policy;
+    }
+
+    /// Ask the server to compress the response using gzip, deflate or brotli,
+    /// the body of the response is decompressed while it is read.
+    pub fn set_compressed(&mut self, compressed: bool) {
+        self.config.compressed = compressed;
+    }
+
+    /// Set the max size of the response body in bytes, once decompressed,
+    /// `0` means no limit. The query fails if the body is larger.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.config.max_body_size = max_body_size;
+    }
+
+    /// Trust the webpki-roots certificates, set it to false to only
+    /// trust the added certificate authorities.
+    pub fn set_default_root_certificates(&mut self, enabled: bool) {
+        self.config
+            .tls_config
+            .set_default_root_certificates(enabled);
+    }
+
+    /// Trust a certificate authority in the DER format.
+    pub fn add_root_certificate_der(&mut self, der: &[u8]) -> CabotResult<()> {
+        self.config.tls_config.add_root_certificate_der(der)
+    }
+
+    /// Trust the certificate authorities of a PEM bundle,
+    /// return the number of certificates added.
+    pub fn add_root_certificates_pem(&mut self, pem: &[u8]) -> CabotResult<usize> {
+        self.config.tls_config.add_root_certificates_pem(pem)
+    }
+
+    /// Trust the certificate authorities of the operating system store,
+    /// return the number of certificates added.
+    pub fn add_native_root_certificates(&mut self) -> CabotResult<usize> {
+        self.config.tls_config.add_native_root_certificates()
+    }
+
+    /// Set the certificate chain and its private key, in the PEM format,
+    /// presented to servers that require client authentication.
+    ///
+    /// RSA, ECDSA and Ed25519 private keys are supported, connections are
+    /// limited to TLS 1.2 while an Ed25519 key is used.
+    pub fn set_client_certificate(
+        &mut self,
+        cert_chain: &[u8],
+        private_key: &[u8],
+    ) -> CabotResult<()> {
+        self.config
+            .tls_config
+            .set_client_certificate(cert_chain, private_key)
+    }
+
+    /// Replace the TLS configuration, the root certificates and the client
+    /// certificate set on the client so far are dropped.
+    pub fn set_tls_config(&mut self, tls_config: TLSConfig) {
+        self.config.tls_config = tls_config;
+    }
+
+    /// Use the given rustls configuration for HTTPS connections,
+    /// the root certificates and the client certificate set on the
+    /// client are ignored.
+    ///
+    /// TLS sessions are resumed using the session persistence of
+    /// the configuration.
+    pub fn set_tls_client_config(&mut self, client_config: Arc<ClientConfig>) {
+        self.config.tls_config.set_client_config(client_config);
+    }
+
+    /// Set the number of idle connections kept alive per authority.
+    /// Set it to 0 to close connections after every response.
+    ///
+    /// The connections pool is shared, the clones of the client keep this
+    /// number of connections too.
+    pub fn set_max_idle_per_host(&mut self, max_idle_per_host: usize) {
+        self.config.pool.set_max_idle_per_host(max_idle_per_host);
+    }
+
+    /// Set the time in seconds an idle connection is kept alive, by the
+    /// clones of the client too, as they share the connections pool.
+    pub fn set_idle_timeout(&mut self, timeout: u64) {
+        self.config.pool.set_idle_timeout(timeout * 1000);
+    }
+
+    /// Set the time in milliseconds an idle connection is kept alive, by
+    /// the clones of the client too.
+    pub fn set_idle_timeout_ms(&mut self, timeout: u64) {
+        self.config.pool.set_idle_timeout(timeout);
+    }
+
+    /// Replace the cookie jar of the client, in order to share it
+    /// between many clients or to load cookies.
+    pub fn set_cookie_jar(&mut self, cookie_jar: Arc<CookieJar>) {
+        self.config.cookie_jar = cookie_jar;
+    }
+
+    /// The cookie jar storing the cookies received by the client.
+    pub fn cookie_jar(&self) -> &Arc<CookieJar> {
+        &self.config.cookie_jar
+    }
+
+    /// Send every request through the `proxy`, except the hosts excluded
+    /// using `set_no_proxy`.
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.config.proxies.set_proxy(proxy);
+    }
+
+    /// Set the comma separated list of hosts reached without proxy.
+    pub fn set_no_proxy(&mut self, no_proxy: &str) {
+        self.config.proxies.set_no_proxy(no_proxy);
+    }
+
+    /// Set the proxies per scheme, use `Proxies::from_env()` to honor
+    /// the `http_proxy`, `https_proxy`, `all_proxy` and `no_proxy`
+    /// environment variables.
+    pub fn set_proxies(&mut self, proxies: Proxies) {
+        self.config.proxies = proxies;
+    }
+
+    /// Add a middleware called for every request sent and every response
+    /// received, after the middlewares already added.
+    pub fn add_middleware(&mut self, middleware: Arc<dyn Middleware>) {
+        self.config.middlewares.push(middleware);
     }
 
     /// Execute the [Request](../request/struct.Request.html) and
     /// return the associate [Response](../response/struct.Response.html).
-    pub async fn execute(
-        &self,
-        request: &Request,
-    ) -> CabotResult<Response> {
+    ///
+    /// The returned future is `Send`, it can be spawned in an async-std task.
+    pub async fn execute(&self, request: &Request) -> CabotResult<Response> {
         self.execute_fut(request).await
     }
 
     /// Execute the [Request](../request/struct.Request.html) and
-    /// return the associate [Response](../response/struct.Response.html) in a box
-    /// in order to user it in a async-std task.
-    pub fn execute_box(
-        &'a self,
-        request: &'a Request,
-    ) -> BoxFuture<'a, CabotResult<Response>> {
-        let fut = Box::pin(self.execute_fut(request));
-        Box::pin(ResponseFuture { fut })
+    /// return the associate [Response](../response/struct.Response.html) in a box.
+    ///
+    /// The future returned by `execute` is `Send` too, it can be used in an
+    /// async-std task without being boxed.
+    pub fn execute_box(&'a self, request: &'a Request) -> BoxFuture<'a, CabotResult<Response>> {
+        Box::pin(self.execute_fut(request))
+    }
+
+    /// Execute the [Request](../request/struct.Request.html) and
+    /// return the associate [Response](../response/struct.Response.html)
+    /// as soon as the response headers have been read.
+    ///
+    /// The body is not bufferized in the response, it has to be consumed
+    /// using the [BodyStream](../response/struct.BodyStream.html) of the
+    /// response.
+    pub async fn execute_stream(&self, request: &Request) -> CabotResult<Response> {
+        let (mut out, headers, body_stream) = CabotStreamWrite::new();
+        let client = self.clone();
+        let request = request.clone();
+        let info = Arc::new(Mutex::new(QueryInfo::default()));
+        let query_info = info.clone();
+        task::spawn(async move {
+            let result = client.query(&request, &mut out, &query_info).await;
+            out.terminate(result).await;
+        });
+        let headers = headers.await.map_err(|_| {
+            CabotError::IOError(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "Connection closed before response",
+            ))
+        })??;
+        let response = parse_headers(headers.as_slice()).build()?;
+        let info = info.lock().unwrap().clone();
+        Ok(response.set_body_stream(body_stream).set_query_info(&info))
     }
 
     /// Execute the [Request](../request/struct.Request.html) and
@@ -125,40 +354,30 @@ impl<'a> Client {
     fn execute_fut(
         &'a self,
         request: &'a Request,
-    ) -> impl Future<Output = CabotResult<Response>> + 'a {
+    ) -> impl Future<Output = CabotResult<Response>> + Send + 'a {
         async move {
             let mut out = CabotLibWrite::new();
-            http::http_query(
-                request,
-                &mut out,
-                &self.authorities,
-                self.verbose,
-                self.ipv4,
-                self.ipv6,
-                self.dns_timeout,
-                self.connect_timeout,
-                self.read_timeout,
-                self.request_timeout,
-                self.max_redir,
-            )
-            .await?;
-            out.response()
+            let info = Mutex::new(QueryInfo::default());
+            self.query(request, &mut out, &info).await?;
+            let info = info.into_inner().unwrap();
+            Ok(out.response()?.set_query_info(&info))
         }
     }
-}
 
-/// A Future that implement Send to use Client inside task::spwan
-pub struct ResponseFuture<'a> {
-    fut: Pin<Box<dyn Future<Output = CabotResult<Response>> + 'a>>,
+    /// Perform the query and write the response to the `out` writer.
+    async fn query(
+        &self,
+        request: &Request,
+        out: &mut (dyn Write + Unpin + Send),
+        info: &Mutex<QueryInfo>,
+    ) -> CabotResult<()> {
+        http::query(request, out, &self.config, info).await
+    }
 }
 
-unsafe impl<'a> Send for ResponseFuture<'a> {}
-
-impl<'a> Future for ResponseFuture<'a> {
-    type Output = CabotResult<Response>;
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        Pin::new(&mut self.fut).poll(cx)
+impl Default for Client {
+    fn default() -> Self {
+        Client::new()
     }
 }
 
@@ -178,33 +397,7 @@ impl CabotLibWrite {
     }
 
     fn split_headers(&mut self, buf: &[u8]) {
-        let mut builder = ResponseBuilder::new();
-        if let Some(pos) = buf.iter().position(|&x| x == b'\n') {
-            let (status_line, hdrs) = buf.split_at(pos);
-            let status_line = String::from_utf8_lossy(status_line);
-            builder = builder.set_status_line(status_line.trim_end());
-            let mut header = "".to_owned();
-            for hdr in hdrs.split(|&x| x == b'\n') {
-                let hdr = String::from_utf8_lossy(hdr);
-                if hdr.starts_with(' ') || hdr.starts_with('\t') {
-                    debug!("Obsolete line folded header reveived in {}", header);
-                    header.push_str(" ");
-                    header.push_str(hdr.trim());
-                } else {
-                    let clean_hdr = header.trim();
-                    if clean_hdr.len() > 0 {
-                        builder = builder.add_header(clean_hdr.trim());
-                        header.clear();
-                    }
-                    header.push_str(hdr.trim());
-                }
-            }
-            let clean_hdr = header.trim();
-            if clean_hdr.len() > 0 {
-                builder = builder.add_header(clean_hdr.trim());
-            }
-            self.response_builder = builder;
-        }
+        self.response_builder = parse_headers(buf);
     }
 
     pub fn response(&self) -> CabotResult<Response> {
@@ -240,6 +433,72 @@ impl Write for CabotLibWrite {
     }
 }
 
+/// Write the response headers to a channel, then stream the body
+/// to the [BodyStream](../response/struct.BodyStream.html).
+struct CabotStreamWrite {
+    headers: Option<oneshot::Sender<CabotResult<Vec<u8>>>>,
+    body: mpsc::Sender<io::Result<Vec<u8>>>,
+}
+
+impl CabotStreamWrite {
+    fn new() -> (Self, oneshot::Receiver<CabotResult<Vec<u8>>>, BodyStream) {
+        let (headers_sender, headers_receiver) = oneshot::channel();
+        let (body_sender, body_receiver) = mpsc::channel(BODY_STREAM_BUFFER_SIZE);
+        let writer = CabotStreamWrite {
+            headers: Some(headers_sender),
+            body: body_sender,
+        };
+        (writer, headers_receiver, BodyStream::new(body_receiver))
+    }
+
+    /// Forward the result of the query, errors are sent to the response if
+    /// the headers have not been received, otherwise to the body stream.
+    async fn terminate(mut self, result: CabotResult<()>) {
+        if let Err(err) = result {
+            if let Some(headers) = self.headers.take() {
+                let _ = headers.send(Err(err));
+            } else {
+                let err = match err {
+                    CabotError::IOError(err) => err,
+                    CabotError::ReadError(context, err) => {
+                        let kind = err.kind();
+                        io::Error::new(kind, CabotError::ReadError(context, err))
+                    }
+                    err if err.is_timeout() => io::Error::new(io::ErrorKind::TimedOut, err),
+                    err => io::Error::other(err),
+                };
+                let _ = self.body.send(Err(err)).await;
+            }
+        }
+    }
+}
+
+impl Write for CabotStreamWrite {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let self_ = Pin::get_mut(self);
+        if let Some(headers) = self_.headers.take() {
+            let _ = headers.send(Ok(buf.to_vec()));
+            return Poll::Ready(Ok(0));
+        }
+        let body = Pin::new(&mut self_.body);
+        let ready = futures::ready!(Sink::poll_ready(body, cx));
+        ready
+            .and_then(|_| Pin::new(&mut self_.body).start_send(Ok(buf.to_vec())))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Body stream dropped"))?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        let self_ = Pin::get_mut(self);
+        self_.body.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -267,7 +526,7 @@ mod tests {
         assert_eq!(response.status_code(), 200);
         assert_eq!(response.status_line(), "200 Ok");
         let headers: &[&str] = &["Content-Type: text/plain", "Content-Length: 12"];
-        assert_eq!(response.headers(), headers);
+        assert_eq!(response.headers().lines(), headers);
         assert_eq!(
             response.body_as_string().unwrap(),
             "Hello World!".to_owned()
@@ -300,7 +559,7 @@ mod tests {
             "ows: https://tools.ietf.org/html/rfc7230 #section-3.2.4",
             "Content-Length: 12",
         ];
-        assert_eq!(response.headers(), headers);
+        assert_eq!(response.headers().lines(), headers);
         assert_eq!(
             response.body_as_string().unwrap(),
             "Hello World!".to_owned()
@@ -333,7 +592,7 @@ mod tests {
             "ows: https://tools.ietf.org/html/rfc7230 #section-3.2.4",
             "Content-Length: 12",
         ];
-        assert_eq!(response.headers(), headers);
+        assert_eq!(response.headers().lines(), headers);
         assert_eq!(
             response.body_as_string().unwrap(),
             "Hello World!".to_owned()
@@ -357,8 +616,123 @@ mod tests {
         assert_eq!(response.status_code(), 302);
         assert_eq!(response.status_line(), "302 Moved");
         let headers: &[&str] = &["Location: https://tools.ietf.org/html/rfc7230#section-3.3"];
-        assert_eq!(response.headers(), headers);
+        assert_eq!(response.headers().lines(), headers);
         assert_eq!(response.body_as_string().unwrap(), "");
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod query_tests {
+    use super::*;
+    use async_std::prelude::*;
+    use async_std::task;
+
+    use super::super::errors::Phase;
+    use super::super::request::RequestBuilder;
+    use super::super::test_server::{Route, TestServer, TestServerBuilder};
+    use super::super::transport::MockTransport;
+
+    /// Serve the responses in order, whatever the path of the requests.
+    pub(crate) async fn serve(responses: Vec<Route>) -> TestServer {
+        TestServerBuilder::new()
+            .add_route("*", Route::Sequence(responses))
+            .start()
+            .await
+            .unwrap()
+    }
+
+    pub(crate) fn client(addr: &SocketAddr) -> Client {
+        let mut client = Client::new();
+        client.add_authority("localhost:80", addr);
+        client
+    }
+
+    #[async_std::test]
+    async fn test_execute_stream() {
+        let server = serve(vec![
+            Route::raw(
+                "HTTP/1.1 200 Ok\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 6\r\nHello \r\n6\r\nWorld!\r\n0\r\n\r\n",
+            ),
+            Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 4\r\n\r\nnext"),
+        ])
+        .await;
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let mut response = client.execute_stream(&request).await.unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.headers().lines(), &["Transfer-Encoding: chunked"]);
+        assert_eq!(response.body(), None);
+        let mut body = String::new();
+        let stream = response.body_stream().unwrap();
+        stream.read_to_string(&mut body).await.unwrap();
+        assert_eq!(body, "Hello World!");
+
+        // the connection is back in the pool once the body is consumed
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "next");
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_execute_stream_truncated_body() {
+        let server = serve(vec![Route::raw_and_close(
+            "HTTP/1.1 200 Ok\r\nContent-Length: 12\r\n\r\nHello",
+        )])
+        .await;
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute_stream(&request).await.unwrap();
+        let mut stream = response.into_body_stream().unwrap();
+        let mut body = Vec::new();
+        let err = loop {
+            match stream.next().await.unwrap() {
+                Ok(chunk) => body.extend_from_slice(&chunk),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(body, b"Hello".to_vec());
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[async_std::test]
+    async fn test_execute_stream_error() {
+        let server = serve(vec![Route::raw_and_close("HTTP/1.1 200 Ok\r\n")]).await;
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let err = client.execute_stream(&request).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Read Error while reading the response headers of http://localhost/: \
+             Connection closed while reading headers"
+        );
+        assert_eq!(err.context().unwrap().phase(), Phase::ReadHeaders);
+    }
+
+    #[async_std::test]
+    async fn test_shared_client() {
+        let transport = Arc::new(MockTransport::new());
+        for _ in 0..4 {
+            transport.add_response(b"HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello");
+        }
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+        let client = Arc::new(client);
+        let tasks: Vec<_> = (0..4)
+            .map(|idx| {
+                let client = client.clone();
+                task::spawn(async move {
+                    let url = format!("http://example.com/{}", idx);
+                    let request = RequestBuilder::new(&url).build().unwrap();
+                    client.execute(&request).await
+                })
+            })
+            .collect();
+        for task in tasks {
+            let response = task.await.unwrap();
+            assert_eq!(response.body_as_string().unwrap(), "Hello");
+        }
+        assert_eq!(transport.requests().len(), 4);
+    }
+}
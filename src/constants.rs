@@ -11,6 +11,29 @@ pub const CONNECT_TIMEOUT: u64 = 15;
 pub const READ_TIMEOUT: u64 = 10;
 pub const REQUEST_TIMEOUT: u64 = 0;
 
+/// Time in seconds a resolution is cached by a client.
+pub const DNS_CACHE_TTL: u64 = 60;
+/// Time in seconds a failed resolution is cached by a client.
+pub const DNS_NEGATIVE_CACHE_TTL: u64 = 5;
+
+/// Delay in milliseconds before racing the next address of a host,
+/// see RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: u64 = 250;
+
+/// Delay in seconds before the first retry of a failed request.
+pub const RETRY_BASE_DELAY: u64 = 1;
+/// Max delay in seconds between two attempts of a request.
+pub const RETRY_MAX_DELAY: u64 = 30;
+
+pub const MAX_IDLE_CONNECTIONS_PER_HOST: usize = 8;
+pub const IDLE_CONNECTION_TIMEOUT: u64 = 90;
+
+/// Number of TLS sessions kept to be resumed.
+pub const TLS_SESSION_CACHE_SIZE: usize = 256;
+
+/// Codings accepted when the response decompression is enabled.
+pub const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+
 pub fn user_agent() -> String {
     format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
 }
@@ -0,0 +1,761 @@
+//! HTTP Cookies handling.
+//!
+//! The [CookieJar](struct.CookieJar.html) of a client stores the cookies
+//! received in `Set-Cookie` headers, as specified by
+//! [rfc6265](https://tools.ietf.org/html/rfc6265), and send them back in the
+//! `Cookie` header of the following requests.
+//!
+//! # Example
+//! ```
+//! use cabot::CookieJar;
+//!
+//! let jar = CookieJar::new();
+//! jar.set_cookie("https://www.example.org/account/login", "sid=42; Path=/account; Secure")
+//!     .unwrap();
+//! jar.set_cookie("https://www.example.org/", "lang=fr; Domain=example.org")
+//!     .unwrap();
+//!
+//! assert_eq!(
+//!     jar.cookie_header("https://www.example.org/account/").unwrap(),
+//!     Some("sid=42; lang=fr".to_owned())
+//! );
+//! assert_eq!(
+//!     jar.cookie_header("http://static.example.org/").unwrap(),
+//!     Some("lang=fr".to_owned())
+//! );
+//! ```
+
+use std::cmp::Reverse;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use url::Url;
+
+use super::request::Request;
+use super::results::CabotResult;
+
+/// The `SameSite` attribute of a cookie.
+///
+/// It is kept for information, every request sent by cabot is considered
+/// as a same-site request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie stored in a [CookieJar](struct.CookieJar.html).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    domain: String,
+    host_only: bool,
+    path: String,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// The name of the cookie.
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// The value of the cookie.
+    pub fn value(&self) -> &str {
+        self.value.as_str()
+    }
+
+    /// The domain the cookie is sent to, without leading dot.
+    pub fn domain(&self) -> &str {
+        self.domain.as_str()
+    }
+
+    /// True if the cookie is only sent to its domain,
+    /// false if it is also sent to the subdomains.
+    pub fn host_only(&self) -> bool {
+        self.host_only
+    }
+
+    /// The path prefix of the requests the cookie is sent to.
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    /// The expiry date of the cookie, None for a session cookie.
+    pub fn expires(&self) -> Option<SystemTime> {
+        self.expires
+    }
+
+    /// True if the cookie is only sent over https.
+    pub fn secure(&self) -> bool {
+        self.secure
+    }
+
+    /// True if the cookie is not exposed to scripts by browsers.
+    pub fn http_only(&self) -> bool {
+        self.http_only
+    }
+
+    /// The `SameSite` attribute of the cookie.
+    pub fn same_site(&self) -> Option<SameSite> {
+        self.same_site
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        match self.expires {
+            Some(expires) => expires <= now,
+            None => false,
+        }
+    }
+
+    /// Parse the value of a `Set-Cookie` header received from `host`,
+    /// for a request on `path`, see
+    /// [rfc6265](https://tools.ietf.org/html/rfc6265#section-5.2).
+    fn parse(set_cookie: &str, host: &str, path: &str, now: SystemTime) -> Option<Self> {
+        let mut attributes = set_cookie.split(';');
+        let pair = attributes.next().unwrap_or("");
+        let pos = pair.find('=')?;
+        let (name, value) = pair.split_at(pos);
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let host = host.to_ascii_lowercase();
+        let mut cookie = Cookie {
+            name: name.to_owned(),
+            value: value[1..].trim().to_owned(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(path),
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        };
+        let mut max_age = None;
+        for attribute in attributes {
+            let mut parts = attribute.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let val = parts.next().unwrap_or("").trim();
+            match key.as_str() {
+                "expires" => {
+                    if let Some(expires) = parse_cookie_date(val) {
+                        cookie.expires = Some(expires);
+                    }
+                }
+                "max-age" => {
+                    if let Ok(seconds) = val.parse::<i64>() {
+                        max_age = Some(if seconds <= 0 {
+                            UNIX_EPOCH
+                        } else {
+                            now.checked_add(Duration::from_secs(seconds as u64))
+                                .unwrap_or_else(|| now + Duration::from_secs(u32::MAX as u64))
+                        });
+                    }
+                }
+                "domain" if !val.trim_start_matches('.').is_empty() => {
+                    let domain = val.trim_start_matches('.').to_ascii_lowercase();
+                    if !domain_match(&host, &domain) || !domain.contains('.') && domain != host {
+                        debug!("Cookie {} rejected for domain {}", cookie.name, domain);
+                        return None;
+                    }
+                    cookie.host_only = false;
+                    cookie.domain = domain;
+                }
+                "path" if val.starts_with('/') => cookie.path = val.to_owned(),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                "samesite" => {
+                    cookie.same_site = match val.to_ascii_lowercase().as_str() {
+                        "strict" => Some(SameSite::Strict),
+                        "lax" => Some(SameSite::Lax),
+                        "none" => Some(SameSite::None),
+                        _ => cookie.same_site,
+                    }
+                }
+                _ => (),
+            }
+        }
+        if max_age.is_some() {
+            cookie.expires = max_age;
+        }
+        Some(cookie)
+    }
+
+    /// True if the cookie has to be sent in a request.
+    fn matches(&self, https: bool, host: &str, path: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let domain_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_match(&host, &self.domain)
+        };
+        domain_ok && path_match(path, &self.path) && (https || !self.secure)
+    }
+}
+
+/// True if the `host` is the `domain` or one of its subdomains.
+fn domain_match(host: &str, domain: &str) -> bool {
+    if host == domain {
+        return true;
+    }
+    let is_ip = host.starts_with('[') || host.parse::<IpAddr>().is_ok();
+    !is_ip
+        && host.len() > domain.len()
+        && host.ends_with(domain)
+        && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+}
+
+/// True if the request `path` is in the cookie path.
+fn path_match(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path.as_bytes()[cookie_path.len()] == b'/')
+}
+
+/// The path of a cookie set without `Path` attribute,
+/// the "directory" of the request path.
+fn default_path(path: &str) -> String {
+    if !path.starts_with('/') {
+        return "/".to_owned();
+    }
+    match path.rfind('/') {
+        Some(0) | None => "/".to_owned(),
+        Some(pos) => path[..pos].to_owned(),
+    }
+}
+
+/// Parse the leading digits of a token, return None if their count
+/// is not between `min` and `max`.
+fn leading_number(token: &str, min: usize, max: usize) -> Option<u32> {
+    let count = token.bytes().take_while(u8::is_ascii_digit).count();
+    if count < min || count > max {
+        return None;
+    }
+    token[..count].parse().ok()
+}
+
+/// Parse the `hh:mm:ss` time of a date.
+fn parse_time(token: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = token.splitn(3, ':');
+    let hour = parts.next()?;
+    let minute = parts.next()?;
+    let second = parts.next()?;
+    if hour.is_empty() || hour.len() > 2 || minute.is_empty() || minute.len() > 2 {
+        return None;
+    }
+    Some((
+        leading_number(hour, hour.len(), 2)?,
+        leading_number(minute, minute.len(), 2)?,
+        leading_number(second, 1, 2)?,
+    ))
+}
+
+/// Parse the date of the `Expires` attribute, using the permissive
+/// algorithm of [rfc6265](https://tools.ietf.org/html/rfc6265#section-5.1.1).
+pub(crate) fn parse_cookie_date(date: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let is_delimiter = |c: char| {
+        c == '\t'
+            || (' '..='/').contains(&c)
+            || (';'..='@').contains(&c)
+            || ('['..='`').contains(&c)
+            || ('{'..='~').contains(&c)
+    };
+    let mut time = None;
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+    for token in date.split(is_delimiter).filter(|token| !token.is_empty()) {
+        if time.is_none() {
+            if let Some(parsed) = parse_time(token) {
+                time = Some(parsed);
+                continue;
+            }
+        }
+        if day.is_none() {
+            if let Some(parsed) = leading_number(token, 1, 2) {
+                day = Some(parsed);
+                continue;
+            }
+        }
+        if month.is_none() && token.len() >= 3 {
+            let prefix = token[..3].to_ascii_lowercase();
+            if let Some(pos) = MONTHS.iter().position(|name| *name == prefix) {
+                month = Some(pos as u32 + 1);
+                continue;
+            }
+        }
+        if year.is_none() {
+            if let Some(parsed) = leading_number(token, 2, 4) {
+                year = Some(parsed);
+            }
+        }
+    }
+    let (hour, minute, second) = time?;
+    let (day, month, mut year) = (day?, month?, year?);
+    if year < 70 {
+        year += 2000;
+    } else if year < 100 {
+        year += 1900;
+    }
+    let days_in_month = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if day < 1 || day > days_in_month || year < 1601 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    // days since the epoch of the civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let (y, m, d) = if month <= 2 {
+        (year as i64 - 1, month as i64 + 9, day as i64)
+    } else {
+        (year as i64, month as i64 - 3, day as i64)
+    };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let seconds = days * 86_400 + hour as i64 * 3600 + minute as i64 * 60 + second as i64;
+    if seconds >= 0 {
+        Some(UNIX_EPOCH + Duration::from_secs(seconds as u64))
+    } else {
+        Some(UNIX_EPOCH - Duration::from_secs(-seconds as u64))
+    }
+}
+
+/// Split an url in the scheme, host and path used to match cookies.
+fn split_url(url: &str) -> CabotResult<(bool, String, String)> {
+    let url = Url::parse(url)?;
+    Ok((
+        url.scheme() == "https",
+        url.host_str().unwrap_or("").to_owned(),
+        url.path().to_owned(),
+    ))
+}
+
+/// The path of a request, without its querystring.
+fn request_path(request: &Request) -> &str {
+    let uri = request.request_uri();
+    match uri.find('?') {
+        Some(pos) => &uri[..pos],
+        None => uri,
+    }
+}
+
+/// Store the cookies received by a client.
+///
+/// The cookie jar can be shared by many clients, and can be saved and loaded
+/// using the Netscape cookie file format, used by cURL.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Mutex<Vec<Cookie>>,
+}
+
+impl CookieJar {
+    /// Construct an empty CookieJar.
+    pub fn new() -> Self {
+        CookieJar {
+            cookies: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Store the cookie of a `Set-Cookie` header received while requesting `url`.
+    ///
+    /// Return false if the cookie has been rejected or if it has expired.
+    pub fn set_cookie(&self, url: &str, set_cookie: &str) -> CabotResult<bool> {
+        let (_, host, path) = split_url(url)?;
+        Ok(self.store_at(&host, &path, set_cookie, SystemTime::now()))
+    }
+
+    /// The value of the `Cookie` header to send while requesting `url`,
+    /// None if there is no cookie to send.
+    pub fn cookie_header(&self, url: &str) -> CabotResult<Option<String>> {
+        let (https, host, path) = split_url(url)?;
+        Ok(self.header_at(https, &host, &path, SystemTime::now()))
+    }
+
+    /// The cookies that have not expired.
+    pub fn cookies(&self) -> Vec<Cookie> {
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| !cookie.is_expired(now));
+        cookies.clone()
+    }
+
+    /// Remove all the cookies.
+    pub fn clear(&self) {
+        self.cookies.lock().unwrap().clear();
+    }
+
+    /// Load the cookies of a file in the Netscape cookie file format,
+    /// return the number of cookies loaded.
+    pub fn load(&self, path: &Path) -> CabotResult<usize> {
+        let file = File::open(path)?;
+        Ok(self.read_netscape(BufReader::new(file))?)
+    }
+
+    /// Save the cookies to a file in the Netscape cookie file format.
+    pub fn save(&self, path: &Path) -> CabotResult<()> {
+        let mut file = File::create(path)?;
+        self.write_netscape(&mut file)?;
+        Ok(file.flush()?)
+    }
+
+    /// Read cookies in the Netscape cookie file format,
+    /// return the number of cookies read. Malformed lines are ignored.
+    pub fn read_netscape<R: BufRead>(&self, reader: R) -> io::Result<usize> {
+        let now = SystemTime::now();
+        let mut count = 0;
+        for line in reader.lines() {
+            let line = line?;
+            let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+                Some(line) => (line, true),
+                None => (line.as_str(), false),
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line
+                .trim_end_matches(&['\r', '\n'][..])
+                .split('\t')
+                .collect();
+            if fields.len() < 6 || fields[5].is_empty() {
+                debug!("Malformed cookie line ignored: {}", line);
+                continue;
+            }
+            let expires = match fields[4].parse::<u64>() {
+                Ok(0) => None,
+                Ok(seconds) => Some(UNIX_EPOCH + Duration::from_secs(seconds)),
+                Err(_) => {
+                    debug!("Malformed cookie line ignored: {}", line);
+                    continue;
+                }
+            };
+            let cookie = Cookie {
+                name: fields[5].to_owned(),
+                value: fields.get(6).unwrap_or(&"").to_string(),
+                domain: fields[0].trim_start_matches('.').to_ascii_lowercase(),
+                host_only: fields[1] != "TRUE",
+                path: fields[2].to_owned(),
+                expires,
+                secure: fields[3] == "TRUE",
+                http_only,
+                same_site: None,
+            };
+            if !cookie.is_expired(now) {
+                self.store_cookie(cookie, now);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Write the cookies in the Netscape cookie file format,
+    /// session cookies have an expiry date set to 0.
+    pub fn write_netscape<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(
+            b"# Netscape HTTP Cookie File\n\
+              # This file was generated by cabot, edit at your own risk.\n\n",
+        )?;
+        for cookie in self.cookies() {
+            let expires = cookie
+                .expires
+                .and_then(|expires| expires.duration_since(UNIX_EPOCH).ok())
+                .map(|expires| expires.as_secs())
+                .unwrap_or(0);
+            let line = format!(
+                "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if cookie.http_only { "#HttpOnly_" } else { "" },
+                if cookie.host_only { "" } else { "." },
+                cookie.domain,
+                if cookie.host_only { "FALSE" } else { "TRUE" },
+                cookie.path,
+                if cookie.secure { "TRUE" } else { "FALSE" },
+                expires,
+                cookie.name,
+                cookie.value,
+            );
+            writer.write_all(line.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Store the cookie of a `Set-Cookie` header received for the `request`.
+    pub(crate) fn store(&self, request: &Request, set_cookie: &str) {
+        self.store_at(
+            request.host(),
+            request_path(request),
+            set_cookie,
+            SystemTime::now(),
+        );
+    }
+
+    /// The value of the `Cookie` header to send with the `request`.
+    pub(crate) fn request_cookie(&self, request: &Request) -> Option<String> {
+        self.header_at(
+            request.scheme() == "https",
+            request.host(),
+            request_path(request),
+            SystemTime::now(),
+        )
+    }
+
+    fn store_at(&self, host: &str, path: &str, set_cookie: &str, now: SystemTime) -> bool {
+        match Cookie::parse(set_cookie, host, path, now) {
+            Some(cookie) => {
+                let expired = cookie.is_expired(now);
+                self.store_cookie(cookie, now);
+                !expired
+            }
+            None => false,
+        }
+    }
+
+    /// Replace the cookie having the same name, domain and path,
+    /// an expired cookie removes it.
+    fn store_cookie(&self, cookie: Cookie, now: SystemTime) {
+        let mut cookies = self.cookies.lock().unwrap();
+        let pos = cookies.iter().position(|stored| {
+            stored.name == cookie.name
+                && stored.domain == cookie.domain
+                && stored.path == cookie.path
+        });
+        match pos {
+            Some(pos) if cookie.is_expired(now) => {
+                cookies.remove(pos);
+            }
+            Some(pos) => cookies[pos] = cookie,
+            None if cookie.is_expired(now) => (),
+            None => cookies.push(cookie),
+        }
+    }
+
+    fn header_at(&self, https: bool, host: &str, path: &str, now: SystemTime) -> Option<String> {
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| !cookie.is_expired(now));
+        let mut matching: Vec<&Cookie> = cookies
+            .iter()
+            .filter(|cookie| cookie.matches(https, host, path))
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        // cookies with longer paths are listed first, then the oldest ones.
+        matching.sort_by_key(|cookie| Reverse(cookie.path.len()));
+        let pairs: Vec<String> = matching
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect();
+        Some(pairs.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::client::query_tests::client;
+    use super::super::request::RequestBuilder;
+    use super::super::test_server::{Route, TestServerBuilder};
+
+    fn date(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn test_parse_cookie_date() {
+        let expected = Some(date(784_111_777));
+        assert_eq!(parse_cookie_date("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(
+            parse_cookie_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            expected
+        );
+        assert_eq!(parse_cookie_date("Sun Nov  6 08:49:37 1994"), expected);
+        assert_eq!(
+            parse_cookie_date("Thu, 01 Jan 1970 00:00:00 GMT"),
+            Some(UNIX_EPOCH)
+        );
+        assert_eq!(
+            parse_cookie_date("Wed, 29 Feb 2000 12:00:00 GMT"),
+            Some(date(951_825_600))
+        );
+        assert_eq!(parse_cookie_date("Thu, 29 Feb 2001 12:00:00 GMT"), None);
+        assert_eq!(parse_cookie_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        assert_eq!(parse_cookie_date("tomorrow"), None);
+    }
+
+    #[test]
+    fn test_parse_cookie() {
+        let now = date(1_000_000);
+        let cookie = Cookie::parse(
+            "sid = 42 ; Domain=.Example.org; Path=/app; Max-Age=60; \
+             Expires=Sun, 06 Nov 1994 08:49:37 GMT; Secure; HttpOnly; SameSite=Lax",
+            "www.example.org",
+            "/login",
+            now,
+        )
+        .unwrap();
+        assert_eq!(cookie.name(), "sid");
+        assert_eq!(cookie.value(), "42");
+        assert_eq!(cookie.domain(), "example.org");
+        assert!(!cookie.host_only());
+        assert_eq!(cookie.path(), "/app");
+        assert_eq!(cookie.expires(), Some(date(1_000_060)));
+        assert!(cookie.secure());
+        assert!(cookie.http_only());
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+
+        let cookie = Cookie::parse("sid=", "WWW.example.org", "/app/login", now).unwrap();
+        assert_eq!(cookie.value(), "");
+        assert_eq!(cookie.domain(), "www.example.org");
+        assert!(cookie.host_only());
+        assert_eq!(cookie.path(), "/app");
+        assert_eq!(cookie.expires(), None);
+        assert!(!cookie.secure());
+
+        assert!(Cookie::parse("sid", "www.example.org", "/", now).is_none());
+        assert!(Cookie::parse("=42", "www.example.org", "/", now).is_none());
+        assert!(Cookie::parse("sid=42; Domain=other.org", "www.example.org", "/", now).is_none());
+        assert!(Cookie::parse("sid=42; Domain=org", "www.example.org", "/", now).is_none());
+        assert!(Cookie::parse("sid=42; Domain=0.1", "127.0.0.1", "/", now).is_none());
+    }
+
+    #[test]
+    fn test_cookie_header() {
+        let jar = CookieJar::new();
+        let now = date(1_000_000);
+        assert!(jar.store_at("www.example.org", "/", "a=1; Path=/", now));
+        assert!(jar.store_at("www.example.org", "/", "b=2; Path=/app", now));
+        assert!(jar.store_at("www.example.org", "/", "c=3; Path=/; Secure", now));
+        assert!(jar.store_at("example.org", "/", "d=4; Domain=example.org", now));
+        assert!(jar.store_at("www.example.org", "/", "e=5; Max-Age=10", now));
+        assert_eq!(
+            jar.header_at(true, "www.example.org", "/app/index", now),
+            Some("b=2; a=1; c=3; d=4; e=5".to_owned())
+        );
+        assert_eq!(
+            jar.header_at(false, "www.example.org", "/application", now),
+            Some("a=1; d=4; e=5".to_owned())
+        );
+        assert_eq!(
+            jar.header_at(false, "example.org", "/", date(1_000_010)),
+            Some("d=4".to_owned())
+        );
+        assert_eq!(jar.header_at(false, "example.com", "/", now), None);
+    }
+
+    #[test]
+    fn test_replace_cookie() {
+        let jar = CookieJar::new();
+        assert!(jar
+            .set_cookie("http://localhost/", "sid=1; Max-Age=3600")
+            .unwrap());
+        assert!(jar.set_cookie("http://localhost/", "sid=2").unwrap());
+        assert_eq!(
+            jar.cookie_header("http://localhost/").unwrap(),
+            Some("sid=2".to_owned())
+        );
+        assert!(!jar
+            .set_cookie("http://localhost/", "sid=; Max-Age=0")
+            .unwrap());
+        assert_eq!(jar.cookie_header("http://localhost/").unwrap(), None);
+        assert!(jar.cookies().is_empty());
+    }
+
+    #[test]
+    fn test_netscape_file_format() {
+        let jar = CookieJar::new();
+        jar.set_cookie(
+            "https://www.example.org/",
+            "sid=42; Domain=example.org; Expires=Fri, 01 Jan 2100 00:00:00 GMT; Secure",
+        )
+        .unwrap();
+        jar.set_cookie("http://localhost/app/", "lang=fr; HttpOnly")
+            .unwrap();
+        let mut buf = Vec::new();
+        jar.write_netscape(&mut buf).unwrap();
+        let content = String::from_utf8(buf).unwrap();
+        assert!(content.starts_with("# Netscape HTTP Cookie File\n"));
+        assert!(content.ends_with(
+            "\n.example.org\tTRUE\t/\tTRUE\t4102444800\tsid\t42\n\
+             #HttpOnly_localhost\tFALSE\t/app\tFALSE\t0\tlang\tfr\n"
+        ));
+
+        let loaded = CookieJar::new();
+        let count = loaded
+            .read_netscape(
+                format!(
+                    "{}# comment\n\nmalformed\nlocalhost\tFALSE\t/\tFALSE\t1\told\t1\n",
+                    content
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(loaded.cookies(), jar.cookies());
+    }
+
+    #[async_std::test]
+    async fn test_cookies() {
+        let server = TestServerBuilder::new()
+            .add_route(
+                "/login",
+                Route::raw(
+                    "HTTP/1.1 302 Found\r\nLocation: http://localhost/home\r\n\
+                     Set-Cookie: sid=42; Path=/; HttpOnly\r\n\
+                     Set-Cookie: step=login; Path=/login\r\nContent-Length: 0\r\n\r\n",
+                ),
+            )
+            .add_route(
+                "/home",
+                Route::raw("HTTP/1.1 200 Ok\r\nSet-Cookie: lang=fr\r\nContent-Length: 0\r\n\r\n"),
+            )
+            .add_route(
+                "/",
+                Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 0\r\n\r\n"),
+            )
+            .start()
+            .await
+            .unwrap();
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/login")
+            .set_user_agent("cabot")
+            .build()
+            .unwrap();
+        client.execute(&request).await.unwrap();
+        let request = RequestBuilder::new("http://localhost/")
+            .set_user_agent("cabot")
+            .add_header("Cookie: theme=dark")
+            .build()
+            .unwrap();
+        client.execute(&request).await.unwrap();
+        assert_eq!(
+            server.requests(),
+            vec![
+                "GET /login HTTP/1.1\r\nUser-Agent: cabot\r\nHost: localhost\r\n\
+                 Connection: keep-alive\r\n\r\n",
+                "GET /home HTTP/1.1\r\nUser-Agent: cabot\r\nCookie: sid=42\r\n\
+                 Host: localhost\r\nConnection: keep-alive\r\n\r\n",
+                "GET / HTTP/1.1\r\nCookie: theme=dark; sid=42; lang=fr\r\n\
+                 User-Agent: cabot\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n",
+            ]
+        );
+        assert_eq!(client.cookie_jar().cookies().len(), 3);
+    }
+}
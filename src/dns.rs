@@ -1,59 +1,576 @@
 //! DNS Resolution
+//!
+//! Host names are resolved by a [Resolve](trait.Resolve.html)
+//! implementation, the [SystemResolver](struct.SystemResolver.html) by
+//! default.
+//!
+//! # Example
+//! ```
+//! use std::net::SocketAddr;
+//! use std::sync::Arc;
+//!
+//! use cabot::dns::Resolve;
+//! use cabot::results::CabotResult;
+//! use cabot::Client;
+//! use futures::future::{self, BoxFuture};
+//!
+//! /// Resolve every host name to the loopback address.
+//! struct Loopback;
+//!
+//! impl Resolve for Loopback {
+//!     fn resolve<'a>(
+//!         &'a self,
+//!         _host: &'a str,
+//!         port: u16,
+//!     ) -> BoxFuture<'a, CabotResult<Vec<SocketAddr>>> {
+//!         Box::pin(future::ready(Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))])))
+//!     }
+//! }
+//!
+//! let mut client = Client::new();
+//! client.set_resolver(Arc::new(Loopback));
+//! ```
 
-use std::time::Duration;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use async_std::io::{self, stderr};
 use async_std::net::{SocketAddr, ToSocketAddrs};
 use async_std::prelude::*;
+use futures::future::BoxFuture;
 use log::Level::Info;
 
 use super::results::{CabotError, CabotResult};
 
-pub struct Resolver {
-    verbose: bool,
+/// Resolve host names to socket addresses.
+///
+/// Implement this trait to plug a custom name resolution in a
+/// [Client](../client/struct.Client.html).
+pub trait Resolve: Send + Sync {
+    /// Resolve the `host` name to the addresses to connect to reach the
+    /// `port`, by order of preference.
+    ///
+    /// IP addresses are never resolved, and IPv6 hosts are given without
+    /// brackets. The error returned is reported in the
+    /// `CabotError::DNSLookupError` of the query.
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, CabotResult<Vec<SocketAddr>>>;
 }
 
-impl Resolver {
-    pub fn new(verbose: bool) -> Self {
-        Resolver { verbose }
+/// Resolve host names using the system resolver.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl SystemResolver {
+    /// Construct a new SystemResolver.
+    pub fn new() -> Self {
+        SystemResolver
     }
-    pub async fn get_addr(
-        &self,
-        authority: &str,
-        ipv4: bool,
-        ipv6: bool,
-        dns_timeout: u64,
-    ) -> CabotResult<SocketAddr> {
-        debug!("Resolving TCP Endpoint for authority {}", authority);
-
-        let addrs = io::timeout(Duration::from_millis(dns_timeout), async {
-            authority.to_socket_addrs().await
+}
+
+impl Resolve for SystemResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, CabotResult<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            let addrs = (host, port)
+                .to_socket_addrs()
+                .await
+                .map_err(|err| match err.kind() {
+                    io::ErrorKind::Other => CabotError::IOError(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "Host does not exists",
+                    )),
+                    _ => CabotError::IOError(err),
+                })?;
+            Ok(addrs.collect())
         })
-        .await
-        .map_err(|err| match err.kind() {
-            io::ErrorKind::TimedOut => CabotError::DNSLookupError("Timeout".to_owned()),
-            io::ErrorKind::Other => CabotError::DNSLookupError("Host does not exists".to_owned()),
-            _ => CabotError::DNSLookupError(format!("{}", err)),
-        })?;
-
-        let addr = addrs
-            .filter(|addr| (ipv4 && addr.is_ipv4()) || (ipv6 && addr.is_ipv6()))
-            .next()
-            .ok_or(CabotError::DNSLookupError(
-                "No IP found for this host".to_owned(),
-            ))?;
-        if log_enabled!(Info) {
-            info!("Authority {} has been resolved to {}", authority, addr);
-        } else if self.verbose {
-            writeln!(
-                &mut stderr(),
-                "* Authority {} has been resolved to {}",
-                authority,
-                addr
-            )
+    }
+}
+
+/// The kind and the message of a failed resolution, `io::Error` is not
+/// `Clone`.
+type CachedError = (io::ErrorKind, String);
+
+struct CacheEntry {
+    addrs: Result<Vec<SocketAddr>, CachedError>,
+    expires: Option<Instant>,
+}
+
+impl CacheEntry {
+    fn is_expired(&self, now: Instant) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+}
+
+/// Resolutions of the host names, kept to be reused.
+///
+/// Entries are identified by their authority, `www.rust-lang.org:443` for
+/// instance. Failed resolutions are cached too, for a shorter time.
+pub struct DnsCache {
+    ttl: AtomicU64,
+    negative_ttl: AtomicU64,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        DnsCache::new(0, 0)
+    }
+}
+
+impl DnsCache {
+    /// Construct a new cache.
+    ///
+    /// `ttl` is the time in milliseconds a resolution is cached, and
+    /// `negative_ttl` the time in milliseconds a failed resolution is
+    /// cached, `0` disable the caching.
+    pub fn new(ttl: u64, negative_ttl: u64) -> Self {
+        DnsCache {
+            ttl: AtomicU64::new(ttl),
+            negative_ttl: AtomicU64::new(negative_ttl),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set the time in milliseconds a resolution is cached.
+    pub fn set_ttl(&self, ttl: u64) {
+        self.ttl.store(ttl, Ordering::Relaxed);
+    }
+
+    /// Set the time in milliseconds a failed resolution is cached.
+    pub fn set_negative_ttl(&self, negative_ttl: u64) {
+        self.negative_ttl.store(negative_ttl, Ordering::Relaxed);
+    }
+
+    /// Prefill the cache, the `authority` is resolved to the `addrs` until
+    /// the entry is removed.
+    pub fn insert(&self, authority: &str, addrs: &[SocketAddr]) {
+        let entry = CacheEntry {
+            addrs: Ok(addrs.to_vec()),
+            expires: None,
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(authority.to_owned(), entry);
+    }
+
+    /// Remove the entry of the `authority`, return true if it was cached.
+    pub fn remove(&self, authority: &str) -> bool {
+        self.entries.lock().unwrap().remove(authority).is_some()
+    }
+
+    /// The cached addresses of the `authority`, if any, None if the
+    /// resolution failed or is not cached.
+    pub fn get(&self, authority: &str) -> Option<Vec<SocketAddr>> {
+        match self.lookup(authority) {
+            Some(Ok(addrs)) => Some(addrs),
+            _ => None,
+        }
+    }
+
+    /// Flush the cache.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn lookup(&self, authority: &str) -> Option<Result<Vec<SocketAddr>, CachedError>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(authority)?;
+        if entry.is_expired(Instant::now()) {
+            debug!("Drop expired resolution of {}", authority);
+            entries.remove(authority);
+            None
+        } else {
+            Some(entry.addrs.clone())
+        }
+    }
+
+    fn store(&self, authority: &str, addrs: Result<Vec<SocketAddr>, CachedError>) {
+        let ttl = match addrs {
+            Ok(_) => self.ttl.load(Ordering::Relaxed),
+            Err(_) => self.negative_ttl.load(Ordering::Relaxed),
+        };
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        // Expired entries are dropped here, not only when they are looked
+        // up, for the cache not to grow with every host ever resolved.
+        entries.retain(|_, entry| !entry.is_expired(now));
+        if ttl == 0 {
+            return;
+        }
+        let entry = CacheEntry {
+            addrs,
+            expires: Some(now + Duration::from_millis(ttl)),
+        };
+        entries.insert(authority.to_owned(), entry);
+    }
+
+    /// Resolve the `host` using the cache, or the `resolver` and cache the
+    /// result, the error is the reason of the failure.
+    pub(crate) async fn resolve(
+        &self,
+        resolver: &dyn Resolve,
+        host: &str,
+        port: u16,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let authority = format!("{}:{}", host, port);
+        if let Some(addrs) = self.lookup(&authority) {
+            info!("Fetch authority {} using the DNS cache", authority);
+            return addrs.map_err(|(kind, err)| io::Error::new(kind, err));
+        }
+        let addrs = resolver.resolve(host, port).await.map_err(|err| match err {
+            CabotError::DNSLookupError(_, err) | CabotError::IOError(err) => err,
+            err => io::Error::other(err),
+        });
+        let cached = match addrs {
+            Ok(ref addrs) => Ok(addrs.clone()),
+            Err(ref err) => Err((err.kind(), err.to_string())),
+        };
+        self.store(&authority, cached);
+        addrs
+    }
+}
+
+/// Sort the addresses to connect, alternating the IP versions, starting
+/// with the version of the preferred address, as RFC 8305 recommends.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let mut sorted = Vec::with_capacity(addrs.len());
+    let (preferred, others): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let mut others = others.into_iter();
+    for addr in preferred {
+        sorted.push(addr);
+        sorted.extend(others.next());
+    }
+    sorted.extend(others);
+    sorted
+}
+
+/// Resolve the `host` to the addresses of the authorized IP versions,
+/// using the `dns_cache` or the `resolver`, in the order they have to be
+/// tried. The error is the reason of the failure.
+pub(crate) async fn get_addrs(
+    resolver: &dyn Resolve,
+    dns_cache: &DnsCache,
+    host: &str,
+    port: u16,
+    ipv4: bool,
+    ipv6: bool,
+    verbose: bool,
+) -> io::Result<Vec<SocketAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    debug!("Resolving TCP Endpoint for host {} port {}", host, port);
+
+    let (addrs, is_ip) = match host.parse::<IpAddr>() {
+        Ok(ip) => (vec![SocketAddr::new(ip, port)], true),
+        Err(_) => (dns_cache.resolve(resolver, host, port).await?, false),
+    };
+
+    let mut addrs: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| (ipv4 && addr.is_ipv4()) || (ipv6 && addr.is_ipv6()))
+        .collect();
+    addrs.dedup();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No IP found for this host",
+        ));
+    }
+    let addrs = interleave(addrs);
+    if is_ip {
+        // A literal IP address is not resolved, there is nothing to log.
+        return Ok(addrs);
+    }
+    let resolved = addrs
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if log_enabled!(Info) {
+        info!("Host {} has been resolved to {}", host, resolved);
+    } else if verbose {
+        let line = format!("* Host {} has been resolved to {}\n", host, resolved);
+        stderr().write_all(line.as_bytes()).await.unwrap();
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use futures::future;
+
+    use std::sync::{Arc, Mutex};
+
+    use super::super::client::query_tests::serve;
+    use super::super::client::Client;
+    use super::super::request::RequestBuilder;
+    use super::super::test_server::Route;
+
+    struct StaticResolver(Vec<SocketAddr>);
+
+    impl Resolve for StaticResolver {
+        fn resolve<'a>(
+            &'a self,
+            host: &'a str,
+            _port: u16,
+        ) -> BoxFuture<'a, CabotResult<Vec<SocketAddr>>> {
+            assert_eq!(host, "www.example.org");
+            Box::pin(future::ready(Ok(self.0.clone())))
+        }
+    }
+
+    /// Count the queries, resolve `known.example.org` only.
+    #[derive(Default)]
+    struct CountingResolver(AtomicU64);
+
+    impl Resolve for CountingResolver {
+        fn resolve<'a>(
+            &'a self,
+            host: &'a str,
+            port: u16,
+        ) -> BoxFuture<'a, CabotResult<Vec<SocketAddr>>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            let addrs = match host {
+                "known.example.org" => Ok(vec![SocketAddr::from(([192, 0, 2, 1], port))]),
+                _ => Err(CabotError::IOError(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Host does not exists",
+                ))),
+            };
+            Box::pin(future::ready(addrs))
+        }
+    }
+
+    #[async_std::test]
+    async fn test_dns_cache() {
+        let resolver = CountingResolver::default();
+        let count = || resolver.0.load(Ordering::Relaxed);
+        let cache = DnsCache::new(60_000, 0);
+        let addr: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        for _ in 0..2 {
+            let addrs = cache.resolve(&resolver, "known.example.org", 80).await;
+            assert_eq!(addrs.unwrap(), vec![addr]);
+        }
+        assert_eq!(count(), 1);
+        assert_eq!(cache.get("known.example.org:80"), Some(vec![addr]));
+        assert!(cache.remove("known.example.org:80"));
+        cache
+            .resolve(&resolver, "known.example.org", 80)
+            .await
+            .unwrap();
+        assert_eq!(count(), 2);
+
+        // Negative caching is disabled
+        for _ in 0..2 {
+            let err = cache.resolve(&resolver, "unknown.example.org", 80).await;
+            let err = err.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            assert_eq!(err.to_string(), "Host does not exists");
+        }
+        assert_eq!(count(), 4);
+        cache.set_negative_ttl(60_000);
+        for _ in 0..2 {
+            let err = cache.resolve(&resolver, "unknown.example.org", 80).await;
+            let err = err.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            assert_eq!(err.to_string(), "Host does not exists");
+        }
+        assert_eq!(count(), 5);
+        assert_eq!(cache.get("unknown.example.org:80"), None);
+
+        // Prefilled entries
+        cache.insert("unknown.example.org:80", &[addr]);
+        let addrs = cache.resolve(&resolver, "unknown.example.org", 80).await;
+        assert_eq!(addrs.unwrap(), vec![addr]);
+        assert_eq!(count(), 5);
+
+        cache.clear();
+        cache.set_ttl(1);
+        cache
+            .resolve(&resolver, "known.example.org", 80)
             .await
             .unwrap();
+        async_std::task::sleep(Duration::from_millis(5)).await;
+        assert_eq!(cache.get("known.example.org:80"), None);
+        cache
+            .resolve(&resolver, "known.example.org", 80)
+            .await
+            .unwrap();
+        assert_eq!(count(), 7);
+    }
+
+    #[async_std::test]
+    async fn test_dns_cache_evict_expired() {
+        let resolver = CountingResolver::default();
+        let cache = DnsCache::new(1, 60_000);
+        let addr: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        cache.insert("prefilled.example.org:80", &[addr]);
+        cache
+            .resolve(&resolver, "known.example.org", 80)
+            .await
+            .unwrap();
+        async_std::task::sleep(Duration::from_millis(5)).await;
+        // storing another resolution drops the expired entries.
+        let err = cache.resolve(&resolver, "unknown.example.org", 80).await;
+        assert!(err.is_err());
+        let mut authorities: Vec<String> = cache.entries.lock().unwrap().keys().cloned().collect();
+        authorities.sort();
+        assert_eq!(
+            authorities,
+            vec!["prefilled.example.org:80", "unknown.example.org:80"]
+        );
+    }
+
+    #[test]
+    fn test_interleave() {
+        let addrs: Vec<SocketAddr> = vec![
+            "192.0.2.1:80".parse().unwrap(),
+            "192.0.2.2:80".parse().unwrap(),
+            "192.0.2.3:80".parse().unwrap(),
+            "[2001:db8::1]:80".parse().unwrap(),
+            "[2001:db8::2]:80".parse().unwrap(),
+        ];
+        let expected: Vec<SocketAddr> = vec![
+            "192.0.2.1:80".parse().unwrap(),
+            "[2001:db8::1]:80".parse().unwrap(),
+            "192.0.2.2:80".parse().unwrap(),
+            "[2001:db8::2]:80".parse().unwrap(),
+            "192.0.2.3:80".parse().unwrap(),
+        ];
+        assert_eq!(interleave(addrs), expected);
+        assert_eq!(interleave(Vec::new()), Vec::new());
+    }
+
+    #[async_std::test]
+    async fn test_get_addrs() {
+        let cache = DnsCache::default();
+        let resolver = StaticResolver(vec![
+            "[2001:db8::1]:80".parse().unwrap(),
+            "[2001:db8::2]:80".parse().unwrap(),
+            "192.0.2.1:80".parse().unwrap(),
+        ]);
+        let addrs = get_addrs(&resolver, &cache, "www.example.org", 80, true, true, false).await;
+        let expected: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:80".parse().unwrap(),
+            "192.0.2.1:80".parse().unwrap(),
+            "[2001:db8::2]:80".parse().unwrap(),
+        ];
+        assert_eq!(addrs.unwrap(), expected);
+        let addrs = get_addrs(&resolver, &cache, "www.example.org", 80, true, false, false).await;
+        assert_eq!(addrs.unwrap(), vec!["192.0.2.1:80".parse().unwrap()]);
+        // IP addresses are not resolved
+        let addrs = get_addrs(&resolver, &cache, "[::1]", 443, true, true, false).await;
+        assert_eq!(addrs.unwrap(), vec!["[::1]:443".parse().unwrap()]);
+
+        let resolver = StaticResolver(vec![]);
+        let err = get_addrs(&resolver, &cache, "www.example.org", 80, true, true, false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "No IP found for this host");
+    }
+
+    pub(crate) struct RecordingResolver {
+        pub(crate) addrs: Vec<SocketAddr>,
+        pub(crate) queries: Mutex<Vec<String>>,
+    }
+
+    impl Resolve for RecordingResolver {
+        fn resolve<'a>(
+            &'a self,
+            host: &'a str,
+            port: u16,
+        ) -> BoxFuture<'a, CabotResult<Vec<SocketAddr>>> {
+            self.queries
+                .lock()
+                .unwrap()
+                .push(format!("{}:{}", host, port));
+            Box::pin(futures::future::ready(Ok(self.addrs.clone())))
+        }
+    }
+
+    #[async_std::test]
+    async fn test_resolver() {
+        let server = serve(vec![Route::raw(
+            "HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello",
+        )])
+        .await;
+        let resolver = Arc::new(RecordingResolver {
+            addrs: vec![server.addr()],
+            queries: Mutex::new(Vec::new()),
+        });
+        let mut client = Client::new();
+        client.set_resolver(resolver.clone());
+        let request = RequestBuilder::new("http://service.test:8080/")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert_eq!(*resolver.queries.lock().unwrap(), vec!["service.test:8080"]);
+
+        // The authorities map takes precedence over the resolver
+        let mut client = Client::new();
+        client.set_resolver(resolver.clone());
+        client.add_authority("localhost:1", &"127.0.0.1:1".parse().unwrap());
+        let request = RequestBuilder::new("http://localhost:1/").build().unwrap();
+        assert!(client.execute(&request).await.is_err());
+        assert_eq!(resolver.queries.lock().unwrap().len(), 1);
+
+        // The resolution errors have the url of the request
+        let mut client = Client::new();
+        client.set_resolver(Arc::new(RecordingResolver {
+            addrs: Vec::new(),
+            queries: Mutex::new(Vec::new()),
+        }));
+        let request = RequestBuilder::new("http://service.test/").build().unwrap();
+        let err = client.execute(&request).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "DNS Lookup Error while resolving http://service.test/: No IP found for this host"
+        );
+        assert_eq!(err.context().unwrap().url(), "http://service.test/");
+    }
+
+    #[async_std::test]
+    async fn test_client_dns_cache() {
+        let server = serve(vec![Route::raw_and_close(
+            "HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello",
+        )])
+        .await;
+        let addr = server.addr();
+        let resolver = Arc::new(RecordingResolver {
+            addrs: vec![addr],
+            queries: Mutex::new(Vec::new()),
+        });
+        let mut client = Client::new();
+        client.set_resolver(resolver.clone());
+        client.set_max_idle_per_host(0);
+        let request = RequestBuilder::new("http://service.test/").build().unwrap();
+        for _ in 0..2 {
+            client.execute(&request).await.unwrap();
         }
-        Ok(addr)
+        assert_eq!(resolver.queries.lock().unwrap().len(), 1);
+        assert_eq!(client.dns_cache().get("service.test:80"), Some(vec![addr]));
+
+        client.dns_cache().clear();
+        client.execute(&request).await.unwrap();
+        assert_eq!(resolver.queries.lock().unwrap().len(), 2);
+        assert_eq!(server.requests().len(), 3);
     }
 }
@@ -0,0 +1,369 @@
+//! Decompression of the response body.
+//!
+//! The body is decoded while it is read, using the codings found in the
+//! `Transfer-Encoding` and `Content-Encoding` headers.
+use std::io::{self, Write};
+use std::mem;
+
+use brotli::DecompressorWriter;
+use flate2::write::{GzDecoder, ZlibDecoder};
+
+use super::constants;
+
+/// A coding applied to the body of the response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Coding {
+    /// gzip, or its x-gzip alias
+    Gzip,
+    /// zlib format
+    Deflate,
+    /// br
+    Brotli,
+    /// no transformation
+    Identity,
+}
+
+impl Coding {
+    /// Parse a coding name, return None if it is not supported.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "deflate" => Some(Coding::Deflate),
+            "br" => Some(Coding::Brotli),
+            "identity" => Some(Coding::Identity),
+            _ => None,
+        }
+    }
+}
+
+/// Decoder of a single coding, writing the decoded bytes to a buffer.
+enum Decoder {
+    Gzip(GzDecoder<Vec<u8>>),
+    Deflate(ZlibDecoder<Vec<u8>>),
+    Brotli(Box<DecompressorWriter<Vec<u8>>>),
+}
+
+impl Decoder {
+    fn new(coding: Coding) -> Option<Self> {
+        match coding {
+            Coding::Gzip => Some(Decoder::Gzip(GzDecoder::new(Vec::new()))),
+            Coding::Deflate => Some(Decoder::Deflate(ZlibDecoder::new(Vec::new()))),
+            Coding::Brotli => Some(Decoder::Brotli(Box::new(DecompressorWriter::new(
+                Vec::new(),
+                constants::BUFFER_PAGE_SIZE,
+            )))),
+            Coding::Identity => None,
+        }
+    }
+
+    /// Decode the given bytes, return the bytes decoded so far.
+    fn decode(&mut self, buf: &[u8]) -> io::Result<Vec<u8>> {
+        let decoded = match self {
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(buf)?;
+                decoder.get_mut()
+            }
+            Decoder::Deflate(decoder) => {
+                decoder.write_all(buf)?;
+                decoder.get_mut()
+            }
+            Decoder::Brotli(decoder) => {
+                decoder.write_all(buf)?;
+                decoder.get_mut()
+            }
+        };
+        Ok(mem::take(decoded))
+    }
+
+    /// Terminate the decoding, fails if the stream is truncated.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Deflate(decoder) => decoder.finish(),
+            Decoder::Brotli(mut decoder) => {
+                decoder.close()?;
+                decoder.into_inner().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Invalid brotli stream")
+                })
+            }
+        }
+    }
+}
+
+/// Decode a body that may have been encoded many times.
+pub(crate) struct BodyDecoder {
+    /// decoders, in the order they have to be applied.
+    decoders: Vec<Decoder>,
+    /// some bytes have been decoded, an empty body is not an encoded body.
+    started: bool,
+}
+
+impl BodyDecoder {
+    /// Create the decoder of a body encoded using the `codings`, listed in
+    /// the order they have been applied by the server.
+    ///
+    /// Return None if there is nothing to decode.
+    pub(crate) fn new(codings: &[Coding]) -> Option<Self> {
+        let decoders: Vec<Decoder> = codings
+            .iter()
+            .rev()
+            .filter_map(|coding| Decoder::new(*coding))
+            .collect();
+        if decoders.is_empty() {
+            None
+        } else {
+            Some(BodyDecoder {
+                decoders,
+                started: false,
+            })
+        }
+    }
+
+    /// Decode a part of the body, return the decoded bytes available.
+    pub(crate) fn decode(&mut self, buf: &[u8]) -> io::Result<Vec<u8>> {
+        self.started = self.started || !buf.is_empty();
+        let mut buf = buf.to_vec();
+        for decoder in self.decoders.iter_mut() {
+            if buf.is_empty() {
+                break;
+            }
+            buf = decoder.decode(&buf)?;
+        }
+        Ok(buf)
+    }
+
+    /// Decode the end of the body.
+    pub(crate) fn finish(self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        if !self.started {
+            return Ok(buf);
+        }
+        for mut decoder in self.decoders {
+            if !buf.is_empty() {
+                buf = decoder.decode(&buf)?;
+            }
+            let mut end = decoder.finish()?;
+            buf.append(&mut end);
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    use super::super::client::query_tests::{client, serve};
+    use super::super::request::RequestBuilder;
+    use super::super::test_server::Route;
+
+    pub(crate) fn gzip(buf: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(buf).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    pub(crate) fn deflate(buf: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(buf).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    pub(crate) fn brotli(buf: &[u8]) -> Vec<u8> {
+        let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        encoder.write_all(buf).unwrap();
+        encoder.into_inner()
+    }
+
+    fn decode_by_parts(decoder: Option<BodyDecoder>, buf: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoder = decoder.unwrap();
+        let mut decoded = Vec::new();
+        for part in buf.chunks(3) {
+            decoded.append(&mut decoder.decode(part)?);
+        }
+        decoded.append(&mut decoder.finish()?);
+        Ok(decoded)
+    }
+
+    #[test]
+    fn test_coding_from_name() {
+        assert_eq!(Coding::from_name("gzip"), Some(Coding::Gzip));
+        assert_eq!(Coding::from_name(" X-GZIP "), Some(Coding::Gzip));
+        assert_eq!(Coding::from_name("deflate"), Some(Coding::Deflate));
+        assert_eq!(Coding::from_name("br"), Some(Coding::Brotli));
+        assert_eq!(Coding::from_name("identity"), Some(Coding::Identity));
+        assert_eq!(Coding::from_name("compress"), None);
+    }
+
+    #[test]
+    fn test_decode() {
+        let body = b"Hello World! Hello World! Hello World!";
+        let decoded = decode_by_parts(BodyDecoder::new(&[Coding::Gzip]), &gzip(body)).unwrap();
+        assert_eq!(decoded, body.to_vec());
+        let decoded =
+            decode_by_parts(BodyDecoder::new(&[Coding::Deflate]), &deflate(body)).unwrap();
+        assert_eq!(decoded, body.to_vec());
+        let decoded = decode_by_parts(BodyDecoder::new(&[Coding::Brotli]), &brotli(body)).unwrap();
+        assert_eq!(decoded, body.to_vec());
+    }
+
+    #[test]
+    fn test_decode_many_codings() {
+        let body = b"Hello World!";
+        let decoder = BodyDecoder::new(&[Coding::Deflate, Coding::Identity, Coding::Gzip]);
+        let decoded = decode_by_parts(decoder, &gzip(&deflate(body))).unwrap();
+        assert_eq!(decoded, body.to_vec());
+    }
+
+    #[test]
+    fn test_decode_identity() {
+        assert!(BodyDecoder::new(&[Coding::Identity]).is_none());
+        assert!(BodyDecoder::new(&[]).is_none());
+    }
+
+    #[test]
+    fn test_decode_empty_body() {
+        let decoder = BodyDecoder::new(&[Coding::Gzip]).unwrap();
+        assert_eq!(decoder.finish().unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let body = gzip(b"Hello World!");
+        let res = decode_by_parts(BodyDecoder::new(&[Coding::Gzip]), &body[..body.len() - 4]);
+        assert!(res.is_err());
+    }
+
+    /// A response with the given headers and body.
+    pub(crate) fn raw_response(headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 200 Ok\r\n{}\r\n", headers).into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[async_std::test]
+    async fn test_compressed() {
+        let body = b"Hello World! Hello World!";
+        let gzipped = gzip(body);
+        let deflated = deflate(body);
+        let brotlied = brotli(body);
+        let mut responses = Vec::new();
+        for (coding, encoded) in &[
+            ("gzip", &gzipped),
+            ("deflate", &deflated),
+            ("br", &brotlied),
+        ] {
+            let headers = format!(
+                "Content-Encoding: {}\r\nContent-Length: {}\r\n",
+                coding,
+                encoded.len()
+            );
+            responses.push(Route::raw(raw_response(&headers, encoded)));
+        }
+        let server = serve(responses).await;
+        let mut client = client(&server.addr());
+        client.set_compressed(true);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        for _ in 0..3 {
+            let response = client.execute(&request).await.unwrap();
+            assert_eq!(
+                response.body_as_string().unwrap(),
+                "Hello World! Hello World!"
+            );
+            // the headers of the encoded body are removed.
+            assert_eq!(response.headers().get("Content-Encoding"), None);
+            assert_eq!(response.headers().get("Content-Length"), None);
+        }
+        assert_eq!(server.connections(), 1);
+        assert!(server.requests()[0].contains("\r\nAccept-Encoding: gzip, deflate, br\r\n"));
+    }
+
+    #[async_std::test]
+    async fn test_compressed_disabled() {
+        let gzipped = gzip(b"Hello World!");
+        let headers = format!(
+            "Content-Encoding: gzip\r\nContent-Length: {}\r\n",
+            gzipped.len()
+        );
+        let server = serve(vec![Route::raw(raw_response(&headers, &gzipped))]).await;
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body(), Some(gzipped.as_slice()));
+        assert_eq!(response.headers().get("Content-Encoding"), Some("gzip"));
+        assert!(!server.requests()[0].contains("Accept-Encoding"));
+    }
+
+    #[async_std::test]
+    async fn test_compressed_custom_accept_encoding() {
+        let server = serve(vec![Route::raw(raw_response(
+            "Content-Length: 2\r\n",
+            b"Hi",
+        ))])
+        .await;
+        let mut client = client(&server.addr());
+        client.set_compressed(true);
+        let request = RequestBuilder::new("http://localhost/")
+            .add_header("Accept-Encoding: gzip")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hi");
+        let requests = server.requests();
+        assert!(requests[0].contains("\r\nAccept-Encoding: gzip\r\n"));
+        assert!(!requests[0].contains("br"));
+    }
+
+    #[async_std::test]
+    async fn test_transfer_encoding_gzip_chunked() {
+        let gzipped = gzip(b"Hello World!");
+        let (first, last) = gzipped.split_at(5);
+        let mut body = format!("{:x}\r\n", first.len()).into_bytes();
+        body.extend_from_slice(first);
+        body.extend_from_slice(format!("\r\n{:x}\r\n", last.len()).as_bytes());
+        body.extend_from_slice(last);
+        body.extend_from_slice(b"\r\n0\r\n\r\n");
+        let server = serve(vec![Route::raw(raw_response(
+            "Transfer-Encoding: gzip, chunked\r\n",
+            &body,
+        ))])
+        .await;
+        // transfer codings are decoded even if compression is not requested
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello World!");
+    }
+
+    #[async_std::test]
+    async fn test_compressed_invalid_body() {
+        let server = serve(vec![Route::raw(raw_response(
+            "Content-Encoding: gzip\r\nContent-Length: 12\r\n",
+            b"Hello World!",
+        ))])
+        .await;
+        let mut client = client(&server.addr());
+        client.set_compressed(true);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let err = client.execute(&request).await;
+        assert!(err.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_compressed_unsupported_coding() {
+        let server = serve(vec![Route::raw(raw_response(
+            "Content-Encoding: compress\r\nContent-Length: 5\r\n",
+            b"Hello",
+        ))])
+        .await;
+        let mut client = client(&server.addr());
+        client.set_compressed(true);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert_eq!(response.headers().get("Content-Encoding"), Some("compress"));
+        assert_eq!(response.headers().get("Content-Length"), Some("5"));
+    }
+}
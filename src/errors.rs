@@ -3,19 +3,99 @@ use std::error::Error;
 use std::fmt::{self, Display};
 use std::string::FromUtf8Error;
 
-use async_std::io::Error as IOError;
+use async_std::io::{Error as IOError, ErrorKind};
 use rustls::TLSError;
 use url::ParseError as UrlParseError;
 
+/// The phase of a query during which an error happened.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// Resolving the host name.
+    Resolve,
+    /// Connecting to the server, or to the proxy.
+    Connect,
+    /// Performing the TLS handshake.
+    TLSHandshake,
+    /// Sending the request.
+    SendRequest,
+    /// Reading the status line and the headers of the response.
+    ReadHeaders,
+    /// Reading the body of the response.
+    ReadBody,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            Phase::Resolve => "resolving",
+            Phase::Connect => "connecting to",
+            Phase::TLSHandshake => "performing the TLS handshake with",
+            Phase::SendRequest => "sending the request to",
+            Phase::ReadHeaders => "reading the response headers of",
+            Phase::ReadBody => "reading the response body of",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// The url of the request and the phase of the query that failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorContext {
+    url: String,
+    phase: Phase,
+}
+
+impl ErrorContext {
+    /// Construct a new ErrorContext.
+    pub fn new(url: &str, phase: Phase) -> Self {
+        ErrorContext {
+            url: url.to_owned(),
+            phase,
+        }
+    }
+
+    /// The url of the request.
+    pub fn url(&self) -> &str {
+        self.url.as_str()
+    }
+
+    /// The phase of the query that failed.
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+}
+
+impl Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "while {} {}", self.phase, self.url)
+    }
+}
+
 #[derive(Debug)]
 /// Errors in cabot
 pub enum CabotError {
-    DNSLookupError(String),
+    DNSLookupError(ErrorContext, IOError),
+    DNSTimeout(ErrorContext),
+    ConnectError(ErrorContext, IOError),
+    ConnectTimeout(ErrorContext),
+    TLSHandshakeError(ErrorContext, Box<CabotError>),
+    SendError(ErrorContext, IOError),
+    ReadError(ErrorContext, IOError),
+    ReadTimeout(ErrorContext),
+    RequestTimeout(ErrorContext),
+    MalformedStatusLine(ErrorContext, String),
+    MalformedChunk(ErrorContext, String),
+    BodyTooLarge(ErrorContext, usize),
     HostnameParseError(String),
+    CertificateParseError(String),
+    CertificateKeyMismatch,
+    PrivateKeyParseError(String),
     HttpResponseParseError(String),
     OpaqueUrlError(String),
     SchemeError(String),
+    ProxyError(ErrorContext, String),
     MaxRedirectionAttempt(u8),
+    InsecureRedirection(ErrorContext, String),
     // Wrapped errors
     CertificateError(TLSError),
     EncodingError(FromUtf8Error),
@@ -26,13 +106,45 @@ pub enum CabotError {
 impl Display for CabotError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let description = match self {
-            CabotError::DNSLookupError(err) => format!("DNS Lookup Error: {}", err),
+            CabotError::DNSLookupError(context, err) => {
+                format!("DNS Lookup Error {}: {}", context, err)
+            }
+            CabotError::DNSTimeout(context) => format!("DNS Lookup Timeout {}", context),
+            CabotError::ConnectError(context, err) => {
+                format!("Connection Error {}: {}", context, err)
+            }
+            CabotError::ConnectTimeout(context) => format!("Connection Timeout {}", context),
+            CabotError::TLSHandshakeError(context, err) => {
+                format!("TLS Handshake Error {}: {}", context, err)
+            }
+            CabotError::SendError(context, err) => format!("Send Error {}: {}", context, err),
+            CabotError::ReadError(context, err) => format!("Read Error {}: {}", context, err),
+            CabotError::ReadTimeout(context) => format!("Read Timeout {}", context),
+            CabotError::RequestTimeout(context) => format!("Request Timeout {}", context),
+            CabotError::MalformedStatusLine(context, line) => {
+                format!("Malformed Status Line {}: {:?}", context, line)
+            }
+            CabotError::MalformedChunk(context, err) => {
+                format!("Malformed Chunk {}: {}", context, err)
+            }
+            CabotError::BodyTooLarge(context, max_size) => {
+                format!("Body Too Large {}: larger than {} bytes", context, max_size)
+            }
             CabotError::HostnameParseError(name) => format!("Invalid Hostname: {}", name),
+            CabotError::CertificateParseError(err) => {
+                format!("Certificate Parse Error: {}", err)
+            }
+            CabotError::CertificateKeyMismatch => {
+                "Certificate Key Mismatch: the private key does not match the certificate"
+                    .to_owned()
+            }
+            CabotError::PrivateKeyParseError(err) => format!("Private Key Parse Error: {}", err),
             CabotError::HttpResponseParseError(err) => {
                 format!("HTTP Response Parse Error: {}", err)
             }
             CabotError::OpaqueUrlError(url) => format!("Opaque URL Error: {}", url),
             CabotError::SchemeError(scheme) => format!("Unmanaged Scheme: {}", scheme),
+            CabotError::ProxyError(context, err) => format!("Proxy Error {}: {}", context, err),
             // Wrapped errors
             CabotError::CertificateError(err) => format!("Certificate Error: {}", err),
             CabotError::EncodingError(err) => format!("Utf8 Encoding Error: {}", err),
@@ -41,14 +153,79 @@ impl Display for CabotError {
             CabotError::MaxRedirectionAttempt(max_redir) => {
                 format!("Maximum redirection attempt: {}", max_redir)
             }
+            CabotError::InsecureRedirection(context, location) => {
+                format!("Insecure Redirection {}: to {}", context, location)
+            }
         };
         write!(f, "{}", description)
     }
 }
 
+impl CabotError {
+    /// The url of the request and the phase of the query that failed,
+    /// if known.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            CabotError::DNSLookupError(context, _)
+            | CabotError::DNSTimeout(context)
+            | CabotError::ConnectError(context, _)
+            | CabotError::ConnectTimeout(context)
+            | CabotError::TLSHandshakeError(context, _)
+            | CabotError::SendError(context, _)
+            | CabotError::ReadError(context, _)
+            | CabotError::ReadTimeout(context)
+            | CabotError::RequestTimeout(context)
+            | CabotError::MalformedStatusLine(context, _)
+            | CabotError::MalformedChunk(context, _)
+            | CabotError::BodyTooLarge(context, _)
+            | CabotError::ProxyError(context, _)
+            | CabotError::InsecureRedirection(context, _) => Some(context),
+            _ => None,
+        }
+    }
+
+    /// True if the query failed because a timeout has been reached.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            CabotError::DNSTimeout(_)
+            | CabotError::ConnectTimeout(_)
+            | CabotError::ReadTimeout(_)
+            | CabotError::RequestTimeout(_) => true,
+            CabotError::IOError(err) => err.kind() == ErrorKind::TimedOut,
+            _ => false,
+        }
+    }
+
+    /// True if the connection to the server could not be established,
+    /// including the failures of the name resolution.
+    pub fn is_connect(&self) -> bool {
+        matches!(
+            self,
+            CabotError::DNSLookupError(_, _)
+                | CabotError::DNSTimeout(_)
+                | CabotError::ConnectError(_, _)
+                | CabotError::ConnectTimeout(_)
+        )
+    }
+
+    /// True if the TLS handshake failed, the server certificate may be
+    /// invalid.
+    pub fn is_tls(&self) -> bool {
+        matches!(
+            self,
+            CabotError::TLSHandshakeError(_, _) | CabotError::CertificateError(_)
+        )
+    }
+}
+
 impl Error for CabotError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         let err: Option<&(dyn Error + 'static)> = match self {
+            CabotError::DNSLookupError(_, err) => Some(err),
+            CabotError::ConnectError(_, err) => Some(err),
+            CabotError::TLSHandshakeError(_, err) => Some(err.as_ref()),
+            CabotError::SendError(_, err) => Some(err),
+            CabotError::ReadError(_, err) => Some(err),
             CabotError::CertificateError(err) => Some(err),
             CabotError::EncodingError(err) => Some(err),
             CabotError::IOError(err) => Some(err),
@@ -82,3 +259,97 @@ impl From<UrlParseError> for CabotError {
         CabotError::UrlParseError(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::client::query_tests::{client, serve};
+    use super::super::client::Client;
+    use super::super::request::RequestBuilder;
+    use super::super::test_server::{Route, TestServerBuilder};
+
+    #[async_std::test]
+    async fn test_read_timeout_error() {
+        let server = TestServerBuilder::new()
+            .add_route("/s", Route::delayed(500, Route::text("late")))
+            .start()
+            .await
+            .unwrap();
+        let mut client = client(&server.addr());
+        client.set_read_timeout_ms(50);
+        let request = RequestBuilder::new("http://localhost/s").build().unwrap();
+        let err = client.execute(&request).await.unwrap_err();
+        assert!(err.is_timeout());
+        assert!(!err.is_connect());
+        let context = err.context().unwrap();
+        assert_eq!(context.url(), "http://localhost/s");
+        assert_eq!(context.phase(), Phase::ReadHeaders);
+        assert_eq!(
+            err.to_string(),
+            "Read Timeout while reading the response headers of http://localhost/s"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_connect_error() {
+        let mut client = Client::new();
+        client.add_authority("localhost:80", &"127.0.0.1:1".parse().unwrap());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let err = client.execute(&request).await.unwrap_err();
+        assert!(err.is_connect());
+        assert!(!err.is_timeout());
+        assert_eq!(err.context().unwrap().phase(), Phase::Connect);
+        assert!(err.source().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_malformed_status_line() {
+        let server = serve(vec![Route::raw("SPAM 200 Ok\r\n\r\n")]).await;
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let err = client.execute(&request).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Malformed Status Line while reading the response headers of \
+             http://localhost/: \"SPAM 200 Ok\""
+        );
+    }
+
+    #[async_std::test]
+    async fn test_malformed_chunk() {
+        let server = serve(vec![Route::raw(
+            "HTTP/1.1 200 Ok\r\nTransfer-Encoding: chunked\r\n\r\nZZ\r\nHello\r\n0\r\n\r\n",
+        )])
+        .await;
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let err = client.execute(&request).await.unwrap_err();
+        match err {
+            CabotError::MalformedChunk(ref ctx, _) => {
+                assert_eq!(ctx.phase(), Phase::ReadBody);
+            }
+            _ => panic!("Unexpected error {:?}", err),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_max_body_size() {
+        let server = serve(vec![
+            Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 11\r\n\r\nHello World"),
+            Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello"),
+        ])
+        .await;
+        let mut client = client(&server.addr());
+        client.set_max_body_size(5);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let err = client.execute(&request).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Body Too Large while reading the response body of \
+             http://localhost/: larger than 5 bytes"
+        );
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+    }
+}
@@ -0,0 +1,206 @@
+//! HTTP Headers handling.
+//!
+//! # Example
+//! ```
+//! use cabot::HeaderMap;
+//!
+//! let mut headers = HeaderMap::new();
+//! headers.append_line("Content-Type: application/json");
+//! headers.append("Accept-Language", "fr");
+//! headers.append("accept-language", "en");
+//!
+//! assert_eq!(headers.get("content-type"), Some("application/json"));
+//! assert_eq!(headers.get_all("Accept-Language"), vec!["fr", "en"]);
+//! assert_eq!(
+//!     headers.lines(),
+//!     vec![
+//!         "Content-Type: application/json",
+//!         "Accept-Language: fr",
+//!         "accept-language: en",
+//!     ]
+//! );
+//! ```
+
+use std::fmt;
+
+/// A multi-valued map of HTTP headers.
+///
+/// Header names are case insensitive, but are kept as they have been
+/// added, in the order they have been added, in order to display them
+/// as they have been sent or received.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    /// Construct an empty HeaderMap.
+    pub fn new() -> Self {
+        HeaderMap {
+            entries: Vec::new(),
+        }
+    }
+
+    /// The number of header lines.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// True if there is no header.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// True if a header `name` is present.
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// The value of the first header `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All the values of the header `name`, in order.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    /// Set the header `name` to `value`, replacing all its values.
+    ///
+    /// The header keeps the position of its first value, and that value is
+    /// returned, if any.
+    pub fn insert(&mut self, name: &str, value: &str) -> Option<String> {
+        let previous = self.get(name).map(|value| value.to_owned());
+        if previous.is_none() {
+            self.append(name, value);
+            return None;
+        }
+        let mut found = false;
+        self.entries.retain(|(key, _)| {
+            if key.eq_ignore_ascii_case(name) {
+                let keep = !found;
+                found = true;
+                keep
+            } else {
+                true
+            }
+        });
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        {
+            *entry = (name.trim().to_owned(), value.trim().to_owned());
+        }
+        previous
+    }
+
+    /// Add a value to the header `name`, after the existing ones.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries
+            .push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    /// Add a header from a raw `Name: value` line.
+    ///
+    /// A line without `:` is a header with an empty value.
+    pub fn append_line(&mut self, line: &str) {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("");
+        self.append(name, value);
+    }
+
+    /// Remove all the values of the header `name`, return the first one.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let mut removed = None;
+        self.entries.retain(|(key, value)| {
+            if key.eq_ignore_ascii_case(name) {
+                if removed.is_none() {
+                    removed = Some(value.clone());
+                }
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+
+    /// Iterate over the `(name, value)` of the headers, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// The headers as `Name: value` lines, in order.
+    pub fn lines(&self) -> Vec<String> {
+        self.iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect()
+    }
+}
+
+impl fmt::Display for HeaderMap {
+    /// Format the headers as they are sent on the wire.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in self.iter() {
+            write!(f, "{}: {}\r\n", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_case_insensitive() {
+        let mut headers = HeaderMap::new();
+        headers.append_line("Content-Type:text/plain ");
+        headers.append_line("X-Empty");
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("text/plain"));
+        assert_eq!(headers.get("x-empty"), Some(""));
+        assert_eq!(headers.get("Content-Length"), None);
+        assert!(headers.contains_key("content-type"));
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut headers = HeaderMap::new();
+        headers.append("Accept", "text/html");
+        headers.append("Host", "localhost");
+        headers.append("accept", "text/plain");
+        assert_eq!(
+            headers.insert("Accept", "*/*"),
+            Some("text/html".to_owned())
+        );
+        assert_eq!(headers.lines(), vec!["Accept: */*", "Host: localhost"]);
+        assert_eq!(headers.insert("User-Agent", "cabot"), None);
+        assert_eq!(headers.get("user-agent"), Some("cabot"));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut headers = HeaderMap::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Host", "localhost");
+        headers.append("set-cookie", "b=2");
+        assert_eq!(headers.get_all("Set-Cookie"), vec!["a=1", "b=2"]);
+        assert_eq!(headers.remove("SET-COOKIE"), Some("a=1".to_owned()));
+        assert_eq!(headers.remove("Set-Cookie"), None);
+        assert!(headers.get_all("Set-Cookie").is_empty());
+        assert_eq!(headers.to_string(), "Host: localhost\r\n");
+    }
+}
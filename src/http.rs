@@ -2,19 +2,43 @@
 use std::cmp;
 use std::collections::HashMap;
 use std::mem;
-use std::time::Duration;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use std::vec::Vec;
 
+use async_std::future;
 use async_std::io::{self, stderr, Read, Result as IoResult, Write};
 use async_std::net::{SocketAddr, TcpStream};
 use async_std::prelude::*;
+use async_std::task::{self, Context, Poll};
+use futures::channel::mpsc;
+use futures::future::Either;
+use futures::stream::FuturesUnordered;
 use log::Level::{Info, Warn};
+use url::Url;
 
-use super::asynctls::TLSStream;
+use super::asynctls::{TLSConfig, TLSStream};
+use super::auth::DigestChallenge;
 use super::constants;
-use super::dns::Resolver;
-use super::request::{Request, RequestBuilder};
+use super::cookies::CookieJar;
+use super::dns::{self, DnsCache, Resolve, SystemResolver};
+use super::encoding::{BodyDecoder, Coding};
+use super::errors::{ErrorContext, Phase};
+use super::middleware::{self, Middleware};
+use super::pool::{Connection, ConnectionPool};
+use super::proxy::{Proxies, Proxy, ProxyKind};
+use super::redirect::{self, RedirectAction, RedirectPolicy, Redirection};
+use super::request::{BodyReader, Request, RequestBuilder};
+use super::response::{parse_headers, Response};
 use super::results::{CabotError, CabotResult};
+use super::retry::{self, RetryPolicy};
+use super::socks::{self, Target};
+use super::transport::Transport;
+
+/// Number of chunks written by the query of `http_query` and not yet
+/// written to its output.
+const OUTPUT_CHANNEL_SIZE: usize = 16;
 
 /// How do we have to decode the http response.
 #[derive(Debug, PartialEq)]
@@ -32,17 +56,26 @@ enum TransferEncoding {
 /// Internal status used while decoding a chunked http resonse.
 #[derive(Debug, PartialEq)]
 enum TransferEncodingStatus {
-    ReadingHeader,
-    ReadingBody(usize),
+    /// Reading the chunk size line.
+    Header,
+    /// Reading the chunk data, the number of bytes remaining in the chunk.
+    Body(usize),
+    /// Reading the CRLF that ends the chunk data.
+    BodyEnd,
+    /// Reading the trailer part, after the last chunk.
+    Trailer,
 }
 
 /// 3xx implemented representations for redirection.
+///
+/// The other 3xx responses are never followed, they are returned:
+///   - 300 Multiple Choices lets the user choose between the alternatives,
+///     see `Response::alternatives`.
+///   - 304 Not Modified has no body, the cached representation is valid.
+///   - 305 Use Proxy is deprecated for security reasons, see
+///     [rfc7231](https://tools.ietf.org/html/rfc7231#section-6.4.5).
 #[derive(Debug, PartialEq)]
 enum HTTPRedirect {
-    // unimplemented.
-    //HTTPMultipleChoices(String),
-    //HTTPNotModified(String),
-    //HTTPUseProxy(String),
     /// 302 Found (temporary redirect)
     HTTPFound(String),
     /// 301 Moved Permanently
@@ -55,44 +88,177 @@ enum HTTPRedirect {
     HTTPTemporaryRedirect(String),
 }
 
-/// Wraps errors to handle http redirections.
-#[derive(Debug)]
-enum RedirectError {
-    CabotError(CabotError),
-    IOError(io::Error),
-    Redirect(HTTPRedirect),
+impl HTTPRedirect {
+    /// The status code of the redirection.
+    fn status(&self) -> u16 {
+        match self {
+            HTTPRedirect::HTTPMovedPermanently(_) => 301,
+            HTTPRedirect::HTTPFound(_) => 302,
+            HTTPRedirect::HTTPSeeOther(_) => 303,
+            HTTPRedirect::HTTPTemporaryRedirect(_) => 307,
+            HTTPRedirect::HTTPPermanentRedirect(_) => 308,
+        }
+    }
+
+    /// The location of the redirection.
+    fn location(&self) -> &str {
+        match self {
+            HTTPRedirect::HTTPMovedPermanently(loc)
+            | HTTPRedirect::HTTPFound(loc)
+            | HTTPRedirect::HTTPSeeOther(loc)
+            | HTTPRedirect::HTTPTemporaryRedirect(loc)
+            | HTTPRedirect::HTTPPermanentRedirect(loc) => loc.as_str(),
+        }
+    }
+
+    /// True if the request is sent again with the same method and body.
+    fn preserve_method(&self) -> bool {
+        matches!(
+            self,
+            HTTPRedirect::HTTPTemporaryRedirect(_) | HTTPRedirect::HTTPPermanentRedirect(_)
+        )
+    }
+}
+
+/// Informations about the connection used to perform a query,
+/// the one of the last request when redirections have been followed.
+#[derive(Debug, Default, Clone)]
+pub struct QueryInfo {
+    tls_session_resumed: Option<bool>,
+    url: String,
+    redirects: Vec<(u16, String)>,
 }
 
-/// Http redirections handler.
-type RedirectResult<T> = Result<T, RedirectError>;
+impl QueryInfo {
+    /// True if the TLS session has been resumed while connecting,
+    /// None if TLS is not used.
+    pub fn tls_session_resumed(&self) -> Option<bool> {
+        self.tls_session_resumed
+    }
+
+    /// The url of the last request sent.
+    pub fn url(&self) -> &str {
+        self.url.as_str()
+    }
 
-impl From<CabotError> for RedirectError {
-    fn from(err: CabotError) -> RedirectError {
-        RedirectError::CabotError(err)
+    /// The redirections followed, the status code of the response and the
+    /// url of the request of every one.
+    pub fn redirects(&self) -> &[(u16, String)] {
+        self.redirects.as_slice()
     }
 }
 
-impl From<io::Error> for RedirectError {
-    fn from(err: io::Error) -> RedirectError {
-        RedirectError::IOError(err)
+/// Result of a request sent on a connection.
+#[derive(Debug, PartialEq)]
+struct Exchange {
+    /// The response is a redirection that has to be followed.
+    redirect: Option<HTTPRedirect>,
+    /// The response has a retryable status, the request has to be sent again.
+    retry: bool,
+    /// The delay asked by the `Retry-After` header of the response.
+    retry_after: Option<Duration>,
+    /// The Digest challenge to answer by sending the request again.
+    challenge: Option<DigestChallenge>,
+    /// The response has been fully read and the connection can be reused.
+    keep_alive: bool,
+}
+
+/// Settings of a [Client](../client/struct.Client.html), used by all
+/// the queries it performs.
+#[derive(Clone)]
+pub(crate) struct QueryConfig {
+    /// write the progress of the query to stderr.
+    pub(crate) verbose: bool,
+    pub(crate) ipv4: bool,
+    pub(crate) ipv6: bool,
+    /// addresses of the `host:port` authorities, used before the resolver.
+    pub(crate) authorities: HashMap<String, SocketAddr>,
+    pub(crate) resolver: Arc<dyn Resolve>,
+    pub(crate) dns_cache: Arc<DnsCache>,
+    /// open the connections instead of TCP and TLS, if any.
+    pub(crate) transport: Option<Arc<dyn Transport>>,
+    /// timeouts in milliseconds, a request timeout of 0 means no timeout.
+    pub(crate) dns_timeout: u64,
+    pub(crate) connect_timeout: u64,
+    pub(crate) read_timeout: u64,
+    pub(crate) request_timeout: u64,
+    pub(crate) redirect_policy: RedirectPolicy,
+    pub(crate) retry_policy: RetryPolicy,
+    /// ask the server to compress the response body and decode it.
+    pub(crate) compressed: bool,
+    /// max size of the decoded body, 0 means no limit.
+    pub(crate) max_body_size: usize,
+    pub(crate) tls_config: TLSConfig,
+    pub(crate) pool: Arc<ConnectionPool>,
+    pub(crate) cookie_jar: Arc<CookieJar>,
+    pub(crate) proxies: Proxies,
+    pub(crate) middlewares: Vec<Arc<dyn Middleware>>,
+}
+
+impl QueryConfig {
+    /// The read timeout, never greater than the request timeout.
+    fn effective_read_timeout(&self) -> u64 {
+        if self.request_timeout > 0 {
+            cmp::min(self.read_timeout, self.request_timeout)
+        } else {
+            self.read_timeout
+        }
     }
 }
 
-/// drain the buffer safely, do not panic if the size is greater than the buffer.
-fn drain_buffer<T>(buffer: &mut Vec<T>, size: usize) -> Vec<T> {
-    if buffer.len() >= size {
-        buffer.drain(size..).collect()
-    } else {
-        error!("invalid size in drained buffer");
-        buffer.clear();
-        buffer.drain(..).collect()
+impl Default for QueryConfig {
+    fn default() -> Self {
+        QueryConfig {
+            verbose: false,
+            ipv4: true,
+            ipv6: true,
+            authorities: HashMap::new(),
+            resolver: Arc::new(SystemResolver::new()),
+            dns_cache: Arc::new(DnsCache::new(
+                constants::DNS_CACHE_TTL * 1000,
+                constants::DNS_NEGATIVE_CACHE_TTL * 1000,
+            )),
+            transport: None,
+            dns_timeout: constants::DNS_LOOKUP_TIMEOUT * 1000,
+            connect_timeout: constants::CONNECT_TIMEOUT * 1000,
+            read_timeout: constants::READ_TIMEOUT * 1000,
+            request_timeout: constants::REQUEST_TIMEOUT * 1000,
+            redirect_policy: RedirectPolicy::default(),
+            retry_policy: RetryPolicy::default(),
+            compressed: false,
+            max_body_size: 0,
+            tls_config: TLSConfig::new(),
+            pool: Arc::new(ConnectionPool::new(
+                constants::MAX_IDLE_CONNECTIONS_PER_HOST,
+                constants::IDLE_CONNECTION_TIMEOUT * 1000,
+            )),
+            cookie_jar: Arc::new(CookieJar::new()),
+            proxies: Proxies::new(),
+            middlewares: Vec::new(),
+        }
     }
 }
 
+/// The state of the query while sending one of its requests.
+#[derive(Default)]
+struct Attempt<'a> {
+    /// the proxy the request is sent through.
+    proxy: Option<&'a Proxy>,
+    /// status codes of the responses to discard in order to retry the request.
+    retry_statuses: &'a [u16],
+    /// the request has credentials to answer a Digest challenge.
+    digest_auth: bool,
+    /// the redirections already followed.
+    redirects: &'a [(u16, String)],
+}
+
 impl From<&[u8]> for TransferEncoding {
     fn from(hdr: &[u8]) -> Self {
         let hdr = String::from_utf8_lossy(hdr);
-        let hdrup = hdr.to_ascii_uppercase();
+        // chunked is always the last coding applied, the other
+        // ones are codings of the body.
+        let last = hdr.rsplit(',').next().unwrap_or("");
+        let hdrup = last.trim().to_ascii_uppercase();
         match hdrup.as_str() {
             "CHUNKED" => TransferEncoding::Chunked,
             _ => TransferEncoding::Unkown,
@@ -100,14 +266,39 @@ impl From<&[u8]> for TransferEncoding {
     }
 }
 
+/// Parse a list of codings, return None if one of them is not supported.
+fn parse_codings(hdr: &str) -> Option<Vec<Coding>> {
+    hdr.split(',')
+        .map(|coding| coding.trim())
+        .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("chunked"))
+        .map(Coding::from_name)
+        .collect()
+}
+
+/// Remove the headers having one of the `names` from the status line and
+/// headers of a response.
+fn remove_headers(head: &[u8], names: &[&str]) -> Vec<u8> {
+    head.split_inclusive(|&x| x == b'\n')
+        .filter(|line| {
+            let line = String::from_utf8_lossy(line);
+            let name = line.split(':').next().unwrap_or("").trim();
+            !names
+                .iter()
+                .any(|removed| removed.eq_ignore_ascii_case(name))
+        })
+        .flatten()
+        .cloned()
+        .collect()
+}
+
 /// HTTP Response decoder.
 struct HttpDecoder<'a> {
     //// read the http response stream/
-    reader: &'a mut (dyn Read + Unpin), // tls require Write
+    reader: &'a mut (dyn Read + Unpin + Send), // tls require Write
     /// write the decoded http response.
     /// note that the first write called contains the header
     /// then write response by chunked.
-    writer: &'a mut (dyn Write + Unpin),
+    writer: &'a mut (dyn Write + Unpin + Send),
     /// internal buffer to decode the response.
     buffer: Vec<u8>,
     /// response decoding strategy
@@ -118,40 +309,92 @@ struct HttpDecoder<'a> {
     read_timeout: Duration,
     /// status code
     status_code: [u8; 3],
+    /// the request has been sent using the HEAD method, the response has no body.
+    head_request: bool,
+    /// the redirection found in the response headers.
+    redirect: Option<HTTPRedirect>,
+    /// status codes of the responses to discard in order to retry the request.
+    retry_statuses: &'a [u16],
+    /// the response has a retryable status.
+    retry: bool,
+    /// the delay found in the Retry-After header.
+    retry_after: Option<Duration>,
+    /// the request has credentials to answer a Digest challenge.
+    digest_auth: bool,
+    /// the Digest challenge found in the WWW-Authenticate headers.
+    challenge: Option<DigestChallenge>,
+    /// the connection can be reused once the response is read.
+    keep_alive: bool,
+    /// decode the body using the Content-Encoding header.
+    decompress: bool,
+    /// codings of the body, in the order they have been applied,
+    /// None if a coding is not supported.
+    codings: Option<Vec<Coding>>,
+    /// decoder of the body, if it has been encoded.
+    body_decoder: Option<BodyDecoder>,
+    /// values of the Set-Cookie headers.
+    set_cookies: Vec<String>,
+    /// url of the request, to report errors.
+    url: String,
+    /// the part of the response being read, to report errors.
+    phase: Phase,
+    /// max size of the body, 0 means no limit.
+    max_body_size: usize,
+    /// size of the body written so far.
+    body_size: usize,
 }
 
 impl<'a> HttpDecoder<'a> {
-    /// Create the new http decoder.
+    /// Create the new http decoder, reading the response to the `request`.
     fn new(
-        writer: &'a mut (dyn Write + Unpin),
-        reader: &'a mut (dyn Read + Unpin),
-        read_timeout: u64,
+        writer: &'a mut (dyn Write + Unpin + Send),
+        reader: &'a mut (dyn Read + Unpin + Send),
+        request: &Request,
+        config: &QueryConfig,
+        attempt: &Attempt<'a>,
     ) -> Self {
         HttpDecoder {
             writer,
             reader,
             buffer: Vec::with_capacity(constants::BUFFER_PAGE_SIZE),
             transfer_encoding: TransferEncoding::None,
-            transfer_encoding_status: TransferEncodingStatus::ReadingHeader,
-            read_timeout: Duration::from_millis(read_timeout),
+            transfer_encoding_status: TransferEncodingStatus::Header,
+            read_timeout: Duration::from_millis(config.effective_read_timeout()),
             status_code: b"000".to_owned(),
+            head_request: request.http_method() == "HEAD",
+            redirect: None,
+            retry_statuses: attempt.retry_statuses,
+            retry: false,
+            retry_after: None,
+            digest_auth: attempt.digest_auth,
+            challenge: None,
+            keep_alive: config.pool.keep_alive(),
+            decompress: config.compressed,
+            codings: Some(Vec::new()),
+            body_decoder: None,
+            set_cookies: Vec::new(),
+            url: request.url(),
+            phase: Phase::ReadHeaders,
+            max_body_size: config.max_body_size,
+            body_size: 0,
         }
     }
 
+    /// The context of the errors happening now.
+    fn context(&self) -> ErrorContext {
+        ErrorContext::new(&self.url, self.phase)
+    }
+
     /// extract the first line of the buffer in case there is some
     fn drain_line(&mut self) -> Option<Vec<u8>> {
         debug!("Drain line...");
         if let Some(pos) = self.buffer.iter().position(|&x| x == b'\n') {
-            if pos > 0 && self.buffer.get(pos - 1) == Some(&b'\r') {
-                let buffer = self.buffer.drain((pos + 1)..).collect();
-                let res = mem::replace(&mut self.buffer, buffer);
-                return Some(res);
-            } else {
+            if pos == 0 || self.buffer.get(pos - 1) != Some(&b'\r') {
                 warn!("Missing \\r");
-                let buffer = self.buffer.drain((pos + 1)..).collect();
-                let res = mem::replace(&mut self.buffer, buffer);
-                return Some(res);
             }
+            let buffer = self.buffer.drain((pos + 1)..).collect();
+            let res = mem::replace(&mut self.buffer, buffer);
+            return Some(res);
         } else {
             debug!("Not \\n yet");
         }
@@ -159,7 +402,7 @@ impl<'a> HttpDecoder<'a> {
     }
 
     /// Read a chunk from the reader to the buffer.
-    async fn chunk_read(&mut self) -> IoResult<usize> {
+    async fn chunk_read(&mut self) -> CabotResult<usize> {
         let ret = io::timeout(self.read_timeout, async {
             let mut buf = [0; constants::BUFFER_PAGE_SIZE];
             let ret = self.reader.read(&mut buf[..]).await;
@@ -171,8 +414,8 @@ impl<'a> HttpDecoder<'a> {
             ret
         });
         ret.await.map_err(|err| match err.kind() {
-            io::ErrorKind::TimedOut => io::Error::new(err.kind(), "Read Timeout".to_owned()),
-            _ => err,
+            io::ErrorKind::TimedOut => CabotError::ReadTimeout(self.context()),
+            _ => CabotError::ReadError(self.context(), err),
         })
     }
 
@@ -180,81 +423,178 @@ impl<'a> HttpDecoder<'a> {
     async fn read_status_line(&mut self) -> CabotResult<Vec<u8>> {
         info!("Reading status line...");
         loop {
-            let _count = self.chunk_read().await?;
+            let count = self.chunk_read().await?;
             if let Some(line) = self.drain_line() {
-                if let Some(pos) = line.iter().position(|&x| x == b' ') {
-                    if pos + 3 < line.len() {
-                        self.status_code = [
-                            line[pos + 1].clone(),
-                            line[pos + 2].clone(),
-                            line[pos + 3].clone(),
-                        ];
-                    }
-                    // else error!
-                } // else error
+                let status_line = String::from_utf8_lossy(&line);
+                let status_line = status_line.trim_end();
+                let mut parts = status_line.splitn(3, ' ');
+                let http_version = parts.next().unwrap_or("");
+                let status_code = parts.next().unwrap_or("");
+                if !http_version.starts_with("HTTP/")
+                    || status_code.len() != 3
+                    || !status_code.bytes().all(|c| c.is_ascii_digit())
+                {
+                    return Err(CabotError::MalformedStatusLine(
+                        self.context(),
+                        status_line.to_owned(),
+                    ));
+                }
+                self.status_code.copy_from_slice(status_code.as_bytes());
+                self.retry = status_code
+                    .parse::<u16>()
+                    .map(|status| self.retry_statuses.contains(&status))
+                    .unwrap_or(false);
+                if line.starts_with(b"HTTP/1.0") {
+                    // HTTP/1.0 connections are closed by default
+                    self.keep_alive = false;
+                }
                 return Ok(line);
             }
+            if count == 0 {
+                return Err(CabotError::ReadError(
+                    self.context(),
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Connection closed before response",
+                    ),
+                ));
+            }
         }
     }
 
-    /// read http headers
-    async fn read_headers(&mut self) -> RedirectResult<()> {
-        info!("Reading response headers...");
-        let mut headers_buf = self.read_status_line().await?;
+    /// read http headers, following the status line, return the
+    /// status line and the headers.
+    async fn read_headers(&mut self, status_line: Vec<u8>) -> CabotResult<Vec<u8>> {
+        let mut headers_buf = status_line;
         info!("Reading response headers...");
         'outer: loop {
             while let Some(line) = self.drain_line() {
-                self.process_header(line.as_slice())?; // a bit wrong, header can be multiline
+                self.process_header(line.as_slice()); // a bit wrong, header can be multiline
                 headers_buf.extend_from_slice(line.as_slice());
                 debug!("line {}", String::from_utf8_lossy(line.as_slice()));
-                if line.len() == 2 {
+                if line.len() <= 2 {
                     break 'outer; // CRLF
                 }
             }
-            let _count = self.chunk_read().await?;
+            let count = self.chunk_read().await?;
+            if count == 0 {
+                return Err(CabotError::ReadError(
+                    self.context(),
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Connection closed while reading headers",
+                    ),
+                ));
+            }
+        }
+        Ok(headers_buf)
+    }
+
+    /// Write the status line and the headers to the writer, unless the
+    /// response is discarded.
+    ///
+    /// If the body is decoded, the `Content-Encoding` and `Content-Length`
+    /// headers are removed, they don't describe the written body.
+    async fn write_headers(&mut self, headers: &[u8]) -> CabotResult<()> {
+        if !self.discard_response() {
+            match self.codings.take() {
+                Some(codings) => self.body_decoder = BodyDecoder::new(codings.as_slice()),
+                None => warn!("Unsupported coding, the body is not decoded"),
+            }
+            if self.body_decoder.is_some() {
+                let headers = remove_headers(headers, &["Content-Encoding", "Content-Length"]);
+                self.writer.write(headers.as_slice()).await?;
+            } else {
+                self.writer.write(headers).await?;
+            }
         }
-        self.writer.write(headers_buf.as_slice()).await.unwrap();
         Ok(())
     }
+
     fn process_transfer_encoding(&mut self, header_value: &str) {
         let tenc = header_value.trim();
         debug!("transfer encoding: {:?}", tenc);
         self.transfer_encoding = TransferEncoding::from(tenc.as_bytes());
+        // transfer codings have to be decoded, the server applies them
+        // to the message, not to the resource.
+        self.add_codings(tenc);
+    }
+
+    fn process_content_encoding(&mut self, header_value: &str) {
+        let cenc = header_value.trim();
+        debug!("content encoding: {:?}", cenc);
+        if self.decompress {
+            self.add_codings(cenc);
+        }
+    }
+
+    fn add_codings(&mut self, header_value: &str) {
+        match (self.codings.as_mut(), parse_codings(header_value)) {
+            (Some(codings), Some(mut new_codings)) => codings.append(&mut new_codings),
+            _ => self.codings = None,
+        }
     }
 
     fn process_content_length(&mut self, header_value: &str) {
         let clength = header_value.trim();
         debug!("content length: {:?}", clength);
-        let clength = usize::from_str_radix(clength, 10).unwrap();
-        self.transfer_encoding = TransferEncoding::ContentLength(clength);
+        if self.transfer_encoding == TransferEncoding::Chunked {
+            // Transfer-Encoding overrides the Content-Length
+            return;
+        }
+        match clength.parse::<usize>() {
+            Ok(clength) => self.transfer_encoding = TransferEncoding::ContentLength(clength),
+            Err(_) => {
+                warn!("Invalid Content-Length: {}", clength);
+                self.transfer_encoding = TransferEncoding::Unkown;
+            }
+        }
+    }
+
+    fn process_connection(&mut self, header_value: &str) {
+        let conn = header_value.trim().to_ascii_lowercase();
+        debug!("connection: {:?}", conn);
+        if conn.split(',').any(|token| token.trim() == "close") {
+            self.keep_alive = false;
+        }
     }
 
-    fn process_location(&self, header_value: &str) -> RedirectResult<()> {
-        let loc = header_value.trim().to_owned();
+    /// The location is resolved against the url of the request, see
+    /// [rfc7231](https://tools.ietf.org/html/rfc7231#section-7.1.2).
+    fn process_location(&mut self, header_value: &str) {
+        let loc = header_value.trim();
+        let loc = match Url::parse(&self.url).and_then(|url| url.join(loc)) {
+            Ok(url) => url.into_string(),
+            Err(_) => loc.to_owned(),
+        };
         debug!("location: {:?}", loc);
-        match &self.status_code {
-            b"301" => {
-                return Err(RedirectError::Redirect(HTTPRedirect::HTTPMovedPermanently(
-                    loc,
-                )))
-            }
-            b"302" => return Err(RedirectError::Redirect(HTTPRedirect::HTTPFound(loc))),
-            b"303" => return Err(RedirectError::Redirect(HTTPRedirect::HTTPSeeOther(loc))),
-            b"307" => {
-                return Err(RedirectError::Redirect(
-                    HTTPRedirect::HTTPTemporaryRedirect(loc),
-                ))
-            }
-            b"308" => {
-                return Err(RedirectError::Redirect(
-                    HTTPRedirect::HTTPPermanentRedirect(loc),
-                ))
+        self.redirect = match &self.status_code {
+            b"301" => Some(HTTPRedirect::HTTPMovedPermanently(loc)),
+            b"302" => Some(HTTPRedirect::HTTPFound(loc)),
+            b"303" => Some(HTTPRedirect::HTTPSeeOther(loc)),
+            b"307" => Some(HTTPRedirect::HTTPTemporaryRedirect(loc)),
+            b"308" => Some(HTTPRedirect::HTTPPermanentRedirect(loc)),
+            b"305" => {
+                warn!("Ignoring the 305 Use Proxy redirection to {}", loc);
+                None
             }
-            _ => Ok(()),
+            _ => None,
         }
     }
 
-    fn process_header(&mut self, header: &[u8]) -> RedirectResult<()> {
+    /// Keep the first Digest challenge of a `401 Unauthorized` response,
+    /// if the request has the credentials to answer it.
+    fn process_www_authenticate(&mut self, header_value: &str) {
+        if self.digest_auth
+            && &self.status_code == b"401"
+            && !self.retry
+            && self.challenge.is_none()
+        {
+            self.challenge = DigestChallenge::parse(header_value);
+        }
+    }
+
+    fn process_header(&mut self, header: &[u8]) {
         if let Some(pos) = header.iter().position(|&x| x == b':') {
             let header = String::from_utf8_lossy(header);
             let (key, val) = header.split_at(pos);
@@ -267,22 +607,84 @@ impl<'a> HttpDecoder<'a> {
                 "CONTENT_LENGTH" => {
                     self.process_content_length(hdr);
                 }
+                "CONTENT_ENCODING" => {
+                    self.process_content_encoding(hdr);
+                }
+                "CONNECTION" => {
+                    self.process_connection(hdr);
+                }
+                "SET_COOKIE" => {
+                    self.set_cookies.push(hdr.trim().to_owned());
+                }
                 "LOCATION" => {
-                    if self.status_code[0] == b'3' {
-                        self.process_location(hdr)?;
+                    if self.status_code[0] == b'3' && !self.retry {
+                        self.process_location(hdr);
                     }
                 }
+                "RETRY_AFTER" => {
+                    self.retry_after = retry::parse_retry_after(hdr, SystemTime::now());
+                }
+                "WWW_AUTHENTICATE" => {
+                    self.process_www_authenticate(hdr);
+                }
                 _ => (),
             }
         }
+    }
+
+    /// Redirections, retried responses and answered challenges are read
+    /// but not written.
+    fn discard_response(&self) -> bool {
+        self.redirect.is_some() || self.retry || self.challenge.is_some()
+    }
+
+    /// Responses to HEAD request, 1xx, 204 and 304 responses never have a body.
+    fn has_body(&self) -> bool {
+        !(self.head_request
+            || self.status_code[0] == b'1'
+            || &self.status_code == b"204"
+            || &self.status_code == b"304")
+    }
+
+    /// Count the `size` bytes about to be written to the writer, fail if
+    /// the body is larger than the max body size.
+    fn add_body_size(&mut self, size: usize) -> CabotResult<()> {
+        self.body_size += size;
+        if self.max_body_size > 0 && self.body_size > self.max_body_size {
+            self.keep_alive = false;
+            return Err(CabotError::BodyTooLarge(self.context(), self.max_body_size));
+        }
+        Ok(())
+    }
+
+    /// Write the `count` first bytes of the buffer to the writer, or discard
+    /// them if the response is a redirection or is retried.
+    async fn write_body(&mut self, count: usize) -> CabotResult<()> {
+        if !self.discard_response() && count > 0 {
+            match self.body_decoder.as_mut() {
+                Some(decoder) => {
+                    let decoded = decoder.decode(&self.buffer[..count])?;
+                    if !decoded.is_empty() {
+                        self.add_body_size(decoded.len())?;
+                        self.writer.write_all(decoded.as_slice()).await?;
+                    }
+                }
+                None => {
+                    self.add_body_size(count)?;
+                    self.writer.write_all(&self.buffer[..count]).await?;
+                }
+            }
+        }
+        self.buffer.drain(..count);
         Ok(())
     }
 
     /// read the body, write to the given writer with when no strategy found
-    async fn read_write_no_transfer_encoding(&mut self) -> IoResult<()> {
+    async fn read_write_no_transfer_encoding(&mut self) -> CabotResult<()> {
+        // the end of the body is the end of the connection
+        self.keep_alive = false;
         loop {
-            self.writer.write(self.buffer.as_slice()).await.unwrap();
-            self.buffer.clear();
+            self.write_body(self.buffer.len()).await?;
             let cnt = self.chunk_read().await?;
             if cnt == 0 {
                 break;
@@ -293,24 +695,34 @@ impl<'a> HttpDecoder<'a> {
 
     /// read the body, write to the given writer with when the strategy
     /// is based on the http header Content-Length.
-    async fn read_content_length(&mut self, size: usize) -> IoResult<()> {
-        let mut read_count = self.buffer.len();
+    async fn read_content_length(&mut self, size: usize) -> CabotResult<()> {
+        let mut remaining = size;
         loop {
-            self.writer.write(self.buffer.as_slice()).await.unwrap();
-            self.buffer.clear();
-
-            if read_count >= size {
+            let count = cmp::min(remaining, self.buffer.len());
+            self.write_body(count).await?;
+            remaining -= count;
+            if remaining == 0 {
                 break;
             }
-            read_count = read_count + self.chunk_read().await?;
-            warn!("< {}", read_count);
+            let cnt = self.chunk_read().await?;
+            debug!("< {}", size - remaining);
+            if cnt == 0 {
+                self.keep_alive = false;
+                return Err(CabotError::ReadError(
+                    self.context(),
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Connection closed before the end of the body",
+                    ),
+                ));
+            }
         }
         Ok(())
     }
 
     /// read the body, write to the given writer with when the strategy
     /// is based on the http header Transfer-Encoding: chunked.
-    async fn read_write_chunk(&mut self) -> IoResult<()> {
+    async fn read_write_chunk(&mut self) -> CabotResult<()> {
         loop {
             // we have data in the buffer while reading the headers
             let done = self.process_chunk().await?;
@@ -320,6 +732,7 @@ impl<'a> HttpDecoder<'a> {
             let cnt = self.chunk_read().await?;
             if cnt == 0 {
                 error!("No more chunk data to read");
+                self.keep_alive = false;
                 break;
             }
         }
@@ -327,96 +740,131 @@ impl<'a> HttpDecoder<'a> {
     }
 
     /// read the body, write to the given writer
-    async fn stream_response(&mut self) -> IoResult<()> {
-        info!("Reading body");
-        match self.transfer_encoding {
-            TransferEncoding::ContentLength(size) => {
-                self.read_content_length(size).await?;
-            }
-            TransferEncoding::Chunked => {
-                self.read_write_chunk().await?;
-            }
-            _ => {
-                warn!("Neither Content-Length, not chunk is response header");
-                self.read_write_no_transfer_encoding().await?;
+    async fn stream_response(&mut self) -> CabotResult<()> {
+        self.phase = Phase::ReadBody;
+        if !self.has_body() {
+            info!("Response without body");
+        } else if self.discard_response() && self.transfer_encoding == TransferEncoding::None {
+            // The body of the redirection is not worth reading until the
+            // connection is closed.
+            info!("Skip reading discarded body");
+            self.keep_alive = false;
+        } else {
+            info!("Reading body");
+            match self.transfer_encoding {
+                TransferEncoding::ContentLength(size) => {
+                    self.read_content_length(size).await?;
+                }
+                TransferEncoding::Chunked => {
+                    self.read_write_chunk().await?;
+                }
+                _ => {
+                    warn!("Neither Content-Length, not chunk is response header");
+                    self.read_write_no_transfer_encoding().await?;
+                }
             }
         }
 
-        if self.buffer.len() > 0 {
+        if !self.buffer.is_empty() {
             let b = String::from_utf8_lossy(self.buffer.as_slice());
             error!("Buffer not clear: {}", b);
+            self.keep_alive = false;
         }
 
-        self.writer.flush().await
+        if !self.discard_response() {
+            if let Some(decoder) = self.body_decoder.take() {
+                let decoded = decoder.finish()?;
+                if !decoded.is_empty() {
+                    self.add_body_size(decoded.len())?;
+                    self.writer.write_all(decoded.as_slice()).await?;
+                }
+            }
+            self.writer.flush().await?;
+        }
+        Ok(())
     }
 
     /// Process the data in the buffer.
-    async fn process_chunk(&mut self) -> IoResult<bool> {
-        debug!(
-            "transfer_encoding_status: {:?}",
-            self.transfer_encoding_status
-        );
-        if self.buffer.len() == 0 {
-            return Ok(false);
-        }
+    ///
+    /// Return true when the last chunk and the trailer part have been read.
+    async fn process_chunk(&mut self) -> CabotResult<bool> {
         loop {
-            if self.transfer_encoding_status == TransferEncodingStatus::ReadingHeader {
-                debug!("Reading header in Transfer-Encoding chunked");
-                // we read the chunk size to drain
-                let header = self.drain_line();
-                if header.is_none() {
-                    break;
+            debug!(
+                "transfer_encoding_status: {:?}",
+                self.transfer_encoding_status
+            );
+            match self.transfer_encoding_status {
+                TransferEncodingStatus::Header => {
+                    debug!("Reading header in Transfer-Encoding chunked");
+                    // we read the chunk size to drain
+                    let header = match self.drain_line() {
+                        Some(header) => header,
+                        None => return Ok(false),
+                    };
+                    let size = String::from_utf8_lossy(header.as_slice());
+                    // chunk extensions are ignored
+                    let size = size.split(';').next().unwrap_or("").trim();
+                    if size.is_empty() {
+                        error!("Chunk Header has improper size: {:?}", size);
+                        return Err(CabotError::MalformedChunk(
+                            self.context(),
+                            "Chunk part header is empty, shoule be an hexa".to_owned(),
+                        ));
+                    }
+                    let body_chunk_size = usize::from_str_radix(size, 16).map_err(|_err| {
+                        CabotError::MalformedChunk(
+                            self.context(),
+                            format!("Chunk part should be an hexa string, got {:?}", size),
+                        )
+                    })?;
+                    self.transfer_encoding_status = if body_chunk_size == 0 {
+                        debug!("Reading last chars...");
+                        TransferEncodingStatus::Trailer
+                    } else {
+                        TransferEncodingStatus::Body(body_chunk_size)
+                    };
                 }
-                let header = header.unwrap();
-                let size = String::from_utf8_lossy(header.as_slice());
-                if size.len() > 2 {
-                    let body_chunk_size =
-                        usize::from_str_radix(size.trim(), 16).map_err(|_err| {
-                            io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                "Chunk part should be an hexa string",
-                            )
-                        })?;
-                    if body_chunk_size == 0 {
-                        error!("Reading last chars...");
-                        self.drain_line();
-                        return Ok(true);
+                TransferEncodingStatus::Body(remaining) => {
+                    if self.buffer.is_empty() {
+                        return Ok(false);
                     }
-                    self.transfer_encoding_status =
-                        TransferEncodingStatus::ReadingBody(body_chunk_size);
-                } else {
-                    error!("Chunk Header has improper size: {:?}, {}", size, size.len());
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Chunk part header is empty, shoule be an hexa",
-                    ));
+                    let count = cmp::min(remaining, self.buffer.len());
+                    self.write_body(count).await?;
+                    self.transfer_encoding_status = if count == remaining {
+                        TransferEncodingStatus::BodyEnd
+                    } else {
+                        TransferEncodingStatus::Body(remaining - count)
+                    };
                 }
-            }
-
-            if let TransferEncodingStatus::ReadingBody(buf_size) = self.transfer_encoding_status {
-                if buf_size >= constants::BUFFER_PAGE_SIZE {
-                    let buf_size = buf_size - self.buffer.len();
-                    self.writer.write(self.buffer.as_slice()).await?;
-                    self.buffer.clear();
-                    self.transfer_encoding_status = TransferEncodingStatus::ReadingBody(buf_size);
-                    break;
+                TransferEncodingStatus::BodyEnd => {
+                    // CRLF
+                    if self.drain_line().is_none() {
+                        return Ok(false);
+                    }
+                    self.transfer_encoding_status = TransferEncodingStatus::Header;
                 }
-                if self.buffer.len() > (buf_size + 2) {
-                    let mut buffer: Vec<u8> = self.buffer.drain(buf_size..).collect();
-                    self.writer.write(self.buffer.as_slice()).await?;
-                    self.buffer = drain_buffer(&mut buffer, 2); // CRLF
-                    self.transfer_encoding_status = TransferEncodingStatus::ReadingHeader;
-                    if self.buffer.len() < 4 {
-                        break;
+                TransferEncodingStatus::Trailer => {
+                    let line = match self.drain_line() {
+                        Some(line) => line,
+                        None => return Ok(false),
+                    };
+                    if line.len() <= 2 {
+                        // CRLF
+                        return Ok(true);
                     }
-                } else {
-                    break;
+                    debug!("Ignore trailer {}", String::from_utf8_lossy(&line));
                 }
-            } else {
-                break;
             }
         }
-        return Ok(false);
+    }
+}
+
+async fn log_info(line: &str, verbose: bool) {
+    if log_enabled!(Info) {
+        info!("{}", line);
+    } else if verbose {
+        let line = format!("* {}\n", line);
+        stderr().write_all(line.as_bytes()).await.unwrap();
     }
 }
 
@@ -426,7 +874,8 @@ async fn log_req_line(line: &str, verbose: bool) {
     if log_enabled!(Info) {
         info!("{}", line);
     } else if verbose {
-        writeln!(&mut stderr(), "{}", line).await.unwrap();
+        let line = format!("{}\n", line);
+        stderr().write_all(line.as_bytes()).await.unwrap();
     }
 }
 
@@ -455,64 +904,524 @@ async fn log_request(request: &[u8], verbose: bool) {
     }
 }
 
+/// True if the error means that an idle connection has been closed by the
+/// server before we reuse it.
+fn is_closed_connection(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Send the body of the request from its reader, using the chunked
+/// transfer encoding if the length of the body is unknown.
+async fn send_body_reader(
+    stream: &mut Connection,
+    body_reader: &BodyReader,
+    verbose: bool,
+) -> IoResult<()> {
+    let mut reader = body_reader.take().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The request body has already been sent",
+        )
+    })?;
+    let mut buf = [0; constants::BUFFER_PAGE_SIZE];
+    let mut remaining = body_reader.length();
+    let mut sent = 0;
+    loop {
+        let max = match remaining {
+            Some(remaining) => cmp::min(remaining, buf.len()),
+            None => buf.len(),
+        };
+        if max == 0 {
+            break;
+        }
+        let count = reader.read(&mut buf[..max]).await?;
+        if count == 0 {
+            if remaining.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The request body is shorter than its Content-Length",
+                ));
+            }
+            stream.write_all(b"0\r\n\r\n").await?;
+            break;
+        }
+        match remaining.as_mut() {
+            Some(remaining) => {
+                stream.write_all(&buf[..count]).await?;
+                *remaining -= count;
+            }
+            None => {
+                stream
+                    .write_all(format!("{:x}\r\n", count).as_bytes())
+                    .await?;
+                stream.write_all(&buf[..count]).await?;
+                stream.write_all(b"\r\n").await?;
+            }
+        }
+        sent += count;
+    }
+    log_req_line(&format!("[{} bytes]", sent), verbose).await;
+    Ok(())
+}
+
 /// Send the http request to the stream, and write the response back
 /// to the out parameter.
+///
+/// Responses having one of the retry statuses of the `attempt` are not
+/// written.
+///
+/// The response headers are passed to the middlewares before being written.
+///
+/// Redirections are followed if the redirect policy allows it, the
+/// redirections of the `attempt` are passed to the policy, otherwise the
+/// redirection response is written.
+///
+/// Return `None` in case the connection has been closed by the server
+/// before receiving any byte of the response.
 async fn process_request(
     request: &Request,
-    stream: &mut TcpStream,
-    out: &mut (dyn Write + Unpin),
-    verbose: bool,
-    read_timeout: u64,
-    request_timeout: u64,
-    https: bool,
-) -> RedirectResult<()> {
-    let request_bytes = request.to_bytes();
+    stream: &mut Connection,
+    out: &mut (dyn Write + Unpin + Send),
+    config: &QueryConfig,
+    attempt: &Attempt<'_>,
+) -> CabotResult<Option<Exchange>> {
+    let verbose = config.verbose;
+    let cookie = config.cookie_jar.request_cookie(request);
+    let request_bytes = request.encode(
+        config.pool.keep_alive(),
+        config.compressed,
+        cookie.as_deref(),
+        attempt.proxy,
+    );
     let raw_request = request_bytes.as_slice();
-    log_request(&raw_request, verbose).await;
-
-    let mut ownable_tls_client: Option<TLSStream>;
-    let client: &mut (dyn Read + Unpin) = if https {
-        let mut tls_client = TLSStream::new(stream, request.host())?;
-        tls_client.starttls().await?;
-        debug!("Sending request...");
-        tls_client.write_all(&raw_request).await?;
-        ownable_tls_client = Some(tls_client);
-        ownable_tls_client.as_mut().unwrap()
-    } else {
-        debug!("Sending request...");
-        stream.write_all(&raw_request).await?;
-        stream
+    log_request(raw_request, verbose).await;
+
+    debug!("Sending request...");
+    let sent = async {
+        stream.write_all(raw_request).await?;
+        if let Some(body_reader) = request.body_reader() {
+            send_body_reader(stream, body_reader, verbose).await?;
+        }
+        stream.flush().await
     };
+    match sent.await {
+        Err(err) if is_closed_connection(&err) => return Ok(None),
+        Err(err) => {
+            let context = ErrorContext::new(&request.url(), Phase::SendRequest);
+            return Err(CabotError::SendError(context, err));
+        }
+        Ok(()) => {}
+    }
     debug!("Request sent");
     debug!("Decoding response...");
-    let mut http_decoder = HttpDecoder::new(out, client, read_timeout);
-    http_decoder.read_headers().await?;
+    let mut http_decoder = HttpDecoder::new(out, stream, request, config, attempt);
+    let status_line = match http_decoder.read_status_line().await {
+        Err(CabotError::ReadError(_, err)) if is_closed_connection(&err) => return Ok(None),
+        res => res?,
+    };
+    let mut headers = http_decoder.read_headers(status_line).await?;
+    for set_cookie in http_decoder.set_cookies.iter() {
+        config.cookie_jar.store(request, set_cookie);
+    }
+    if !config.middlewares.is_empty() {
+        let mut response = parse_headers(headers.as_slice()).build()?;
+        middleware::after_response(&config.middlewares, request, &mut response).await?;
+        headers = response.encode_head();
+    }
+    let action = http_decoder.redirect.as_ref().map(|redir| {
+        let url = request.url();
+        let redirection =
+            Redirection::new(redir.status(), &url, redir.location(), attempt.redirects);
+        config.redirect_policy.redirect(&redirection)
+    });
+    match action {
+        None | Some(RedirectAction::Follow) => {}
+        Some(RedirectAction::Stop) => http_decoder.redirect = None,
+        Some(RedirectAction::Error(err)) => {
+            if let CabotError::MaxRedirectionAttempt(max_redir) = err {
+                if log_enabled!(Warn) {
+                    warn!("Maximum redirects followed ({})", max_redir);
+                } else if verbose {
+                    let line = format!("* Maximum redirects followed ({})\n", max_redir);
+                    stderr().write_all(line.as_bytes()).await.unwrap();
+                }
+            }
+            return Err(err);
+        }
+    }
+    http_decoder.write_headers(headers.as_slice()).await?;
 
-    if request_timeout > 0 {
-        io::timeout(Duration::from_millis(request_timeout), async {
-            http_decoder.stream_response().await
-        })
+    if config.request_timeout > 0 {
+        future::timeout(
+            Duration::from_millis(config.request_timeout),
+            http_decoder.stream_response(),
+        )
         .await
-        .map_err(|err| match err.kind() {
-            io::ErrorKind::TimedOut => {
-                if err.to_string() == "Read Timeout" {
-                    err
-                } else {
-                    io::Error::new(err.kind(), "Request Timeout".to_owned())
+        .map_err(|_| {
+            CabotError::RequestTimeout(ErrorContext::new(&request.url(), Phase::ReadBody))
+        })??;
+    } else {
+        http_decoder.stream_response().await?;
+    }
+    Ok(Some(Exchange {
+        redirect: http_decoder.redirect,
+        retry: http_decoder.retry,
+        retry_after: http_decoder.retry_after,
+        challenge: http_decoder.challenge,
+        keep_alive: http_decoder.keep_alive,
+    }))
+}
+
+/// Open a tunnel to the authority of the request through the proxy,
+/// using the CONNECT method.
+async fn open_tunnel(
+    stream: &mut TcpStream,
+    request: &Request,
+    proxy: &Proxy,
+    verbose: bool,
+) -> CabotResult<()> {
+    log_info(
+        &format!("Establish HTTP proxy tunnel to {}", request.authority()),
+        verbose,
+    )
+    .await;
+    let mut connect = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", request.authority());
+    if let Some(authorization) = proxy.authorization() {
+        connect.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+    }
+    connect.push_str("\r\n");
+    log_request(connect.as_bytes(), verbose).await;
+    stream.write_all(connect.as_bytes()).await?;
+
+    // The response is read byte per byte to leave the tunnelled stream untouched.
+    let context = || ErrorContext::new(&request.url(), Phase::Connect);
+    let mut response = Vec::new();
+    let mut byte = [0; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await? == 0 {
+            return Err(CabotError::ProxyError(
+                context(),
+                "Connection closed by the proxy".to_owned(),
+            ));
+        }
+        response.push(byte[0]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("").trim();
+    log_info(&format!("Proxy replied {}", status_line), verbose).await;
+    match status_line.split_whitespace().nth(1) {
+        Some(status_code) if status_code.starts_with('2') => Ok(()),
+        _ => Err(CabotError::ProxyError(
+            context(),
+            format!("CONNECT tunnel failed, response {}", status_line),
+        )),
+    }
+}
+
+/// Resolve the `host` and `port` to the addresses to connect, using the
+/// authorities map of the `config`, its DNS cache or its resolver.
+async fn resolve(host: &str, port: u16, config: &QueryConfig) -> IoResult<Vec<SocketAddr>> {
+    let authority = format!("{}:{}", host, port);
+    match config.authorities.get(&authority) {
+        Some(val) => {
+            info!("Fetch authority {} using autorities map", authority);
+            Ok(vec![*val])
+        }
+        None => {
+            info!("Fetch authority {} using resolver", authority);
+            dns::get_addrs(
+                config.resolver.as_ref(),
+                &config.dns_cache,
+                host,
+                port,
+                config.ipv4,
+                config.ipv6,
+                config.verbose,
+            )
+            .await
+        }
+    }
+}
+
+/// Connect to the first reachable address, in the given order.
+///
+/// Connection attempts are raced as RFC 8305 describes: the next address
+/// is tried if the current attempts have not succeeded after a delay, or
+/// as soon as they all failed.
+async fn connect_addrs(addrs: &[SocketAddr], verbose: bool) -> IoResult<TcpStream> {
+    let mut addrs = addrs.iter().cloned();
+    let mut attempts = FuturesUnordered::new();
+    let mut error = None;
+    let mut start_next = true;
+    loop {
+        if start_next {
+            match addrs.next() {
+                Some(addr) => {
+                    info!("Connecting to {}", addr);
+                    attempts.push(async move { (addr, TcpStream::connect(addr).await) });
+                }
+                None if attempts.is_empty() => {
+                    return Err(error.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::AddrNotAvailable, "No address to connect")
+                    }));
+                }
+                None => {}
+            }
+        }
+        let delay = Box::pin(task::sleep(Duration::from_millis(
+            constants::CONNECTION_ATTEMPT_DELAY,
+        )));
+        start_next = match futures::future::select(attempts.next(), delay).await {
+            Either::Left((Some((addr, Ok(stream))), _)) => {
+                log_info(&format!("Connected to {}", addr), verbose).await;
+                return Ok(stream);
+            }
+            Either::Left((Some((addr, Err(err))), _)) => {
+                log_info(&format!("Connection to {} failed: {}", addr, err), verbose).await;
+                error = Some(err);
+                true
+            }
+            Either::Left((None, _)) | Either::Right(_) => true,
+        };
+    }
+}
+
+/// Open a new connection to the authority of the request.
+///
+/// The connection goes through the `proxy` if any: a tunnel is opened to
+/// HTTP proxies for https requests, and SOCKS5 proxies always connect to
+/// the authority of the request.
+async fn connect(
+    request: &Request,
+    config: &QueryConfig,
+    proxy: Option<&Proxy>,
+) -> CabotResult<Connection> {
+    let verbose = config.verbose;
+    let https = match request.scheme() {
+        "http" => false,
+        "https" => true,
+        _ => {
+            return Err(CabotError::SchemeError(format!(
+                "Unrecognized scheme {}",
+                request.scheme()
+            )))
+        }
+    };
+    let url = request.url();
+    let context = |phase| ErrorContext::new(&url, phase);
+    let dns_timeout = Duration::from_millis(config.dns_timeout);
+    let connect_timeout = Duration::from_millis(config.connect_timeout);
+
+    let (host, port) = match proxy {
+        Some(proxy) => (proxy.host(), proxy.port()),
+        None => (request.host(), request.port()),
+    };
+    let addrs = resolve(host, port, config);
+    let addrs = future::timeout(dns_timeout, addrs)
+        .await
+        .map_err(|_| CabotError::DNSTimeout(context(Phase::Resolve)))?
+        .map_err(|err| CabotError::DNSLookupError(context(Phase::Resolve), err))?;
+
+    let connect_stream = || async {
+        io::timeout(connect_timeout, connect_addrs(&addrs, verbose))
+            .await
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::TimedOut => CabotError::ConnectTimeout(context(Phase::Connect)),
+                _ => CabotError::ConnectError(context(Phase::Connect), err),
+            })
+    };
+    let mut stream = connect_stream().await?;
+
+    let tunnel = async {
+        match proxy {
+            Some(proxy) if proxy.kind() != ProxyKind::Http => {
+                log_info(
+                    &format!(
+                        "Establish SOCKS5 connection to {} through {}",
+                        request.authority(),
+                        proxy.authority()
+                    ),
+                    verbose,
+                )
+                .await;
+                let connect_context = context(Phase::Connect);
+                if proxy.kind() == ProxyKind::Socks5h {
+                    let target = Target::Domain(request.host(), request.port());
+                    let credentials = proxy.credentials();
+                    socks::handshake(&mut stream, &target, credentials, &connect_context).await?;
+                    return Ok(());
+                }
+                // The authority is resolved locally, in the order of the
+                // address family preference, and the proxy is asked for
+                // every address until it accepts one. The proxy closes the
+                // connection when it refuses an address.
+                let targets = resolve(request.host(), request.port(), config);
+                let targets = future::timeout(dns_timeout, targets)
+                    .await
+                    .map_err(|_| CabotError::DNSTimeout(context(Phase::Resolve)))?
+                    .map_err(|err| CabotError::DNSLookupError(context(Phase::Resolve), err))?;
+                for (idx, target) in targets.iter().enumerate() {
+                    if idx > 0 {
+                        stream = connect_stream().await?;
+                    }
+                    socks::negotiate(&mut stream, proxy.credentials(), &connect_context).await?;
+                    match socks::connect(&mut stream, &Target::Addr(*target), &connect_context)
+                        .await
+                    {
+                        Ok(()) => break,
+                        Err(err) if idx + 1 < targets.len() => {
+                            log_info(
+                                &format!("SOCKS5 connection to {} failed: {}", target, err),
+                                verbose,
+                            )
+                            .await;
+                        }
+                        Err(err) => return Err(err),
+                    }
                 }
             }
-            _ => err,
+            Some(proxy) if https => open_tunnel(&mut stream, request, proxy, verbose).await?,
+            _ => {}
+        }
+        Ok::<(), CabotError>(())
+    };
+    future::timeout(connect_timeout, tunnel)
+        .await
+        .map_err(|_| CabotError::ConnectTimeout(context(Phase::Connect)))?
+        .map_err(|err| match err {
+            CabotError::IOError(err) => CabotError::ConnectError(context(Phase::Connect), err),
+            err => err,
         })?;
+
+    if https {
+        let tls_error =
+            |err| CabotError::TLSHandshakeError(context(Phase::TLSHandshake), Box::new(err));
+        let mut tls_stream =
+            TLSStream::new(stream, request.host(), &config.tls_config).map_err(tls_error)?;
+        tls_stream.starttls().await.map_err(tls_error)?;
+        if tls_stream.is_resumed() {
+            log_info("TLS session resumed", verbose).await;
+        }
+        Ok(Connection::Tls(Box::new(tls_stream)))
     } else {
-        http_decoder.stream_response().await?;
+        Ok(Connection::Plain(stream))
+    }
+}
+
+/// Log the failure of an attempt, then wait the `delay` before the retry,
+/// without exceeding the `remaining_time` to retry.
+async fn wait_retry(
+    reason: &str,
+    delay: Duration,
+    remaining_time: Option<Duration>,
+    retries_left: u32,
+    verbose: bool,
+) {
+    let delay = match remaining_time {
+        Some(remaining_time) => cmp::min(delay, remaining_time),
+        None => delay,
+    };
+    log_info(
+        &format!(
+            "{}, retrying in {}ms, {} retries left",
+            reason,
+            delay.as_millis(),
+            retries_left
+        ),
+        verbose,
+    )
+    .await;
+    task::sleep(delay).await;
+}
+
+/// Write a response returned by a middleware to the `out` writer.
+async fn write_response(
+    out: &mut (dyn Write + Unpin + Send),
+    response: &Response,
+) -> CabotResult<()> {
+    out.write(response.encode_head().as_slice()).await?;
+    if let Some(body) = response.body() {
+        out.write_all(body).await?;
     }
     Ok(())
 }
 
+/// Writer that records if something has been written to the inner writer,
+/// a request is not retried once its response has been written.
+struct TrackedWrite<'a> {
+    inner: &'a mut (dyn Write + Unpin + Send),
+    written: bool,
+}
+
+impl<'a> TrackedWrite<'a> {
+    fn new(inner: &'a mut (dyn Write + Unpin + Send)) -> Self {
+        TrackedWrite {
+            inner,
+            written: false,
+        }
+    }
+}
+
+impl<'a> Write for TrackedWrite<'a> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let self_ = Pin::get_mut(self);
+        let res = Pin::new(&mut *self_.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(_)) = res {
+            self_.written = self_.written || !buf.is_empty();
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        Pin::new(&mut *Pin::get_mut(self).inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        Pin::new(&mut *Pin::get_mut(self).inner).poll_close(cx)
+    }
+}
+
+/// A `Send` writer forwarding the bytes written to a channel, in order to
+/// run a query writing to a writer that is not `Send`.
+struct ChannelWrite {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl Write for ChannelWrite {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let self_ = Pin::get_mut(self);
+        let broken = |_| io::Error::new(io::ErrorKind::BrokenPipe, "Output writer failed");
+        futures::ready!(self_.sender.poll_ready(cx)).map_err(broken)?;
+        self_.sender.start_send(buf.to_vec()).map_err(broken)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+        Pin::get_mut(self).sender.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
 /// Process the given http query, write response to the `out` writer.
+///
+/// Timeouts are in milliseconds, a request timeout of 0 means no timeout,
+/// and up to `max_redir` redirections are followed. The other settings of
+/// the query are the defaults of a [Client](../client/struct.Client.html),
+/// which should be preferred to customize them.
+#[allow(clippy::too_many_arguments)]
 pub async fn http_query(
     request: &Request,
-    mut out: &mut (dyn Write + Unpin),
+    out: &mut (dyn Write + Unpin),
     authorities: &HashMap<String, SocketAddr>,
     verbose: bool,
     ipv4: bool,
@@ -522,129 +1431,529 @@ pub async fn http_query(
     read_timeout: u64,
     request_timeout: u64,
     max_redir: u8,
+) -> CabotResult<()> {
+    let config = QueryConfig {
+        verbose,
+        ipv4,
+        ipv6,
+        authorities: authorities.clone(),
+        dns_timeout,
+        connect_timeout,
+        read_timeout,
+        request_timeout,
+        redirect_policy: RedirectPolicy::limited(max_redir),
+        ..QueryConfig::default()
+    };
+    let info = Mutex::new(QueryInfo::default());
+    // The query runs with a Send writer, `out` is written by this future.
+    let (sender, mut receiver) = mpsc::channel(OUTPUT_CHANNEL_SIZE);
+    let queried = async move {
+        let mut writer = ChannelWrite { sender };
+        query(request, &mut writer, &config, &info).await
+    };
+    let forwarded = async move {
+        while let Some(chunk) = receiver.next().await {
+            out.write_all(&chunk).await?;
+        }
+        out.flush().await
+    };
+    let (result, forwarded) = futures::future::join(queried, forwarded).await;
+    // the query fails with a broken pipe if `out` failed.
+    forwarded?;
+    result
+}
+
+/// Process the given http query, write response to the `out` writer.
+///
+/// Connections are taken from the pool of the `config` if an idle
+/// connection to the authority exists, and are given back to the pool
+/// when the response has been fully read.
+///
+/// If compression is enabled, the server is asked to compress the response
+/// body, which is decoded before being written to `out`. The query fails
+/// if the body is larger than the max body size, unless it is `0`.
+///
+/// Requests are sent through the proxy selected for their scheme and host,
+/// if any.
+///
+/// The cookies of the cookie jar are sent with the request and every
+/// redirection, and the cookies set by the responses are stored in it.
+///
+/// Host names are resolved using the authorities map, then the DNS cache
+/// or the resolver, and connections are opened using TCP and TLS, unless
+/// a transport is given to open them.
+///
+/// Failed requests are retried according to the retry policy, as long as
+/// nothing has been written to `out`.
+///
+/// Every request sent, including the redirections and the retries, and every
+/// response received are passed to the middlewares.
+///
+/// The `info` about the connection are set before the response is written.
+pub(crate) async fn query(
+    request: &Request,
+    out: &mut (dyn Write + Unpin + Send),
+    config: &QueryConfig,
+    info: &Mutex<QueryInfo>,
 ) -> CabotResult<()> {
     debug!(
         "HTTP Query {} {}",
         request.http_method(),
         request.request_uri()
     );
-    let mut redir_req: Option<Request>;
+    let mut next_req: Option<Request>;
     let mut request = request;
-    let mut followed_redir = max_redir;
-    let read_timeout = if request_timeout > 0 {
-        if verbose && read_timeout > request_timeout {
-            writeln!(
-                &mut stderr(),
-                "* Read timeout is greater than request timeout, overridden ({}ms)",
-                request_timeout,
-            )
-            .await
-            .unwrap();
+    let mut redirects: Vec<(u16, String)> = Vec::new();
+    // The Digest challenge of the request has been answered.
+    let mut challenged = false;
+    let verbose = config.verbose;
+    let retry_policy = &config.retry_policy;
+    if verbose && config.effective_read_timeout() < config.read_timeout {
+        let line = format!(
+            "* Read timeout is greater than request timeout, overridden ({}ms)\n",
+            config.request_timeout,
+        );
+        stderr().write_all(line.as_bytes()).await.unwrap();
+    }
+    let mut out = TrackedWrite::new(out);
+    let started = Instant::now();
+    let mut retries = 0;
+    let result: CabotResult<()> = loop {
+        let remaining_time = retry_policy.remaining_time(started.elapsed());
+        let can_retry = retries < retry_policy.max_retries()
+            && retry_policy.can_retry(request)
+            && remaining_time != Some(Duration::from_millis(0));
+        let retry_statuses: &[u16] = if can_retry {
+            retry_policy.retry_statuses()
+        } else {
+            &[]
+        };
+        let mut sent_req;
+        let sent = if config.middlewares.is_empty() {
+            request
+        } else {
+            sent_req = request.clone();
+            match middleware::before_request(&config.middlewares, &mut sent_req).await {
+                Ok(None) => {}
+                Ok(Some(response)) => break write_response(&mut out, &response).await,
+                Err(err) => break Err(err),
+            }
+            &sent_req
+        };
+        let digest_auth =
+            !challenged && !sent.has_body_reader() && sent.digest_credentials().is_some();
+        {
+            let mut info = info.lock().unwrap();
+            info.url = sent.url();
+            info.redirects = redirects.clone();
         }
-        cmp::min(read_timeout, request_timeout)
-    } else {
-        read_timeout
-    };
-    let mut result: CabotResult<()> = Ok(());
-    loop {
-        let authority = request.authority();
-        let addr = match authorities.get(authority) {
-            Some(val) => {
-                info!("Fetch authority {} using autorities map", authority);
-                *val
+        let proxy = config.proxies.proxy_for(sent.scheme(), sent.host());
+        let pool_key = match proxy {
+            Some(proxy) => format!(
+                "{}://{} via {}",
+                sent.scheme(),
+                sent.authority(),
+                proxy.authority()
+            ),
+            None => format!("{}://{}", sent.scheme(), sent.authority()),
+        };
+        let attempt = Attempt {
+            proxy,
+            retry_statuses,
+            digest_auth,
+            redirects: &redirects,
+        };
+        // A streamed body can't be sent twice, it is never sent on an idle
+        // connection that may have been closed by the server.
+        let idle_conn = if sent.has_body_reader() {
+            None
+        } else {
+            config.pool.checkout(&pool_key)
+        };
+        let resp = match idle_conn {
+            Some(mut conn) => {
+                log_info(
+                    &format!("Re-using existing connection to {}", pool_key),
+                    verbose,
+                )
+                .await;
+                info.lock().unwrap().tls_session_resumed = conn.tls_session_resumed();
+                let resp = process_request(sent, &mut conn, &mut out, config, &attempt).await;
+                match resp {
+                    Ok(None) => {
+                        log_info("Connection closed by the server, reconnecting", verbose).await;
+                        None
+                    }
+                    resp => Some(resp.map(|exchange| (conn, exchange))),
+                }
             }
+            None => None,
+        };
+        let resp = match resp {
+            Some(resp) => resp,
             None => {
-                info!("Fetch authority {} using resolver", authority);
-                let resolver = Resolver::new(verbose);
-                resolver
-                    .get_addr(authority, ipv4, ipv6, dns_timeout)
-                    .await?
+                let conn = match config.transport.as_ref() {
+                    Some(transport) => transport.connect(sent, proxy).await.map(Connection::Custom),
+                    None => connect(sent, config, proxy).await,
+                };
+                match conn {
+                    Ok(mut conn) => {
+                        info.lock().unwrap().tls_session_resumed = conn.tls_session_resumed();
+                        process_request(sent, &mut conn, &mut out, config, &attempt)
+                            .await
+                            .map(|exchange| (conn, exchange))
+                    }
+                    Err(err) => Err(err),
+                }
             }
         };
 
-        info!("Connecting to {}", addr);
-        let mut client = io::timeout(Duration::from_millis(connect_timeout), async {
-            TcpStream::connect(addr).await
-        })
-        .await
-        .map_err(|err| match err.kind() {
-            io::ErrorKind::TimedOut => io::Error::new(err.kind(), "Connection Timeout".to_owned()),
-            _ => err,
-        })?;
+        let resp = match resp {
+            Ok((conn, Some(exchange))) => Ok((conn, exchange)),
+            Ok((_, None)) => Err(CabotError::ReadError(
+                ErrorContext::new(&sent.url(), Phase::ReadHeaders),
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed before response",
+                ),
+            )),
+            Err(err) => Err(err),
+        };
+        let exchange = match resp {
+            Ok((conn, exchange)) => {
+                if exchange.keep_alive {
+                    debug!("Keep connection to {} alive", pool_key);
+                    config.pool.checkin(&pool_key, conn);
+                }
+                exchange
+            }
+            // Nothing can be retried once the response has been written.
+            Err(err) if can_retry && !out.written && retry_policy.is_retryable_error(&err) => {
+                let delay = retry_policy.delay(retries, None);
+                let retries_left = retry_policy.max_retries() - retries - 1;
+                wait_retry(
+                    &err.to_string(),
+                    delay,
+                    remaining_time,
+                    retries_left,
+                    verbose,
+                )
+                .await;
+                retries += 1;
+                continue;
+            }
+            Err(err) => break Err(err),
+        };
+
+        if exchange.retry {
+            let delay = retry_policy.delay(retries, exchange.retry_after);
+            let retries_left = retry_policy.max_retries() - retries - 1;
+            let reason = "The response status is retryable";
+            wait_retry(reason, delay, remaining_time, retries_left, verbose).await;
+            retries += 1;
+            continue;
+        }
 
-        let resp = process_request(
-            request,
-            &mut client,
-            &mut out,
-            verbose,
-            read_timeout,
-            request_timeout,
-            match request.scheme() {
-                "http" => false,
-                "https" => true,
+        if let (Some(challenge), Some((username, password))) =
+            (exchange.challenge, request.digest_credentials())
+        {
+            log_info("Answering the Digest authentication challenge", verbose).await;
+            let authorization = challenge.authorization(
+                username,
+                password,
+                request.http_method(),
+                request.request_uri(),
+            );
+            let mut authorized_req = request.clone();
+            authorized_req
+                .headers_mut()
+                .insert("Authorization", &authorization);
+            challenged = true;
+            next_req = Some(authorized_req);
+            request = next_req.as_ref().unwrap();
+            continue;
+        }
+
+        let redir = match exchange.redirect {
+            Some(redir) => redir,
+            None => break Ok(()),
+        };
+        let mut redir_req_builder = RequestBuilder::new(redir.location());
+        if redir.preserve_method() {
+            if request.has_body_reader() {
+                break Err(CabotError::IOError(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Unable to send the request body again to follow the redirection",
+                )));
+            }
+            redir_req_builder = redir_req_builder.set_http_method(request.http_method());
+            if let Some(body) = request.body() {
+                redir_req_builder = redir_req_builder.set_body(body);
+            }
+        }
+        // The credentials are not sent to another origin.
+        let same_origin = redirect::is_same_origin(&request.url(), redir.location());
+        // A Digest response is only valid for the uri of the request, the
+        // challenge of the redirection is answered instead.
+        let digest_auth = request.digest_credentials().is_some();
+        for (name, value) in request.headers().iter() {
+            match name.to_lowercase().as_str() {
+                "user-agent" => redir_req_builder = redir_req_builder.set_user_agent(value),
+                "host" | "content-length" | "transfer-encoding" => {}
+                "content-type" if !redir.preserve_method() => {}
+                "authorization" | "cookie" if !same_origin => {}
+                "authorization" if digest_auth => {}
                 _ => {
-                    return Err(CabotError::SchemeError(format!(
-                        "Unrecognized scheme {}",
-                        request.scheme()
-                    )))
+                    redir_req_builder =
+                        redir_req_builder.add_header(&format!("{}: {}", name, value))
                 }
-            },
+            }
+        }
+        if let (true, Some((username, password))) = (same_origin, request.digest_credentials()) {
+            redir_req_builder = redir_req_builder.digest_auth(username, password);
+        }
+        redirects.push((redir.status(), request.url()));
+        challenged = false;
+        next_req = Some(redir_req_builder.build()?);
+        request = next_req.as_ref().unwrap();
+    };
+    // the error of the query prevails over the one of the flush.
+    let flushed = out.flush().await;
+    result?;
+    flushed?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor;
+
+    use super::super::client::query_tests::serve;
+    use super::super::client::Client;
+    use super::super::dns::tests::RecordingResolver;
+    use super::super::test_server::Route;
+
+    /// Decode the `response` to a request to `http://localhost/a/b`,
+    /// return the redirection found, if the response has a body, and the
+    /// bytes written.
+    async fn decode(response: &str) -> (Option<HTTPRedirect>, bool, String) {
+        let mut out = Cursor::new(Vec::new());
+        let mut reader = response.as_bytes();
+        let request = RequestBuilder::new("http://localhost/a/b").build().unwrap();
+        let config = QueryConfig::default();
+        let attempt = Attempt::default();
+        let mut decoder = HttpDecoder::new(&mut out, &mut reader, &request, &config, &attempt);
+        let status_line = decoder.read_status_line().await.unwrap();
+        let headers = decoder.read_headers(status_line).await.unwrap();
+        decoder.write_headers(headers.as_slice()).await.unwrap();
+        decoder.stream_response().await.unwrap();
+        let has_body = decoder.has_body();
+        let redirect = decoder.redirect.take();
+        (
+            redirect,
+            has_body,
+            String::from_utf8(out.into_inner()).unwrap(),
         )
-        .await;
+    }
 
-        match resp {
-            Err(RedirectError::Redirect(redir)) => {
-                if followed_redir <= 0 {
-                    if log_enabled!(Warn) {
-                        warn!("Maximum redirects followed ({})", max_redir);
-                    } else if verbose {
-                        writeln!(
-                            &mut stderr(),
-                            "* Maximum redirects followed ({})",
-                            max_redir
-                        )
-                        .await
-                        .unwrap();
-                    }
-                    return Err(CabotError::MaxRedirectionAttempt(max_redir));
-                }
-                let mut redir_req_builder = match redir {
-                    HTTPRedirect::HTTPMovedPermanently(url)
-                    | HTTPRedirect::HTTPFound(url)
-                    | HTTPRedirect::HTTPSeeOther(url) => RequestBuilder::new(url.as_str()),
-                    HTTPRedirect::HTTPPermanentRedirect(url)
-                    | HTTPRedirect::HTTPTemporaryRedirect(url) => {
-                        let mut req = RequestBuilder::new(url.as_str())
-                            .set_http_method(request.http_method());
-                        if let Some(body) = request.body() {
-                            req = req.set_body(body);
-                        }
-                        req
-                    }
-                };
-                for header in request.headers() {
-                    if header.to_ascii_uppercase().starts_with("USER-AGENT:") {
-                        let (_, ua) = header.split_at(11);
-                        redir_req_builder = redir_req_builder.set_user_agent(ua.trim());
-                    } else if header.to_ascii_uppercase().starts_with("SET-COOKIE:") {
-                        redir_req_builder = redir_req_builder.add_header(header);
-                    }
-                }
-                redir_req = Some(redir_req_builder.build()?);
-                request = redir_req.as_ref().unwrap();
-                followed_redir = followed_redir - 1;
+    #[async_std::test]
+    async fn test_process_header_redirections() {
+        let redirections = vec![
+            (
+                "301",
+                HTTPRedirect::HTTPMovedPermanently("http://localhost/a/c".to_owned()),
+            ),
+            (
+                "302",
+                HTTPRedirect::HTTPFound("http://localhost/a/c".to_owned()),
+            ),
+            (
+                "303",
+                HTTPRedirect::HTTPSeeOther("http://localhost/a/c".to_owned()),
+            ),
+            (
+                "307",
+                HTTPRedirect::HTTPTemporaryRedirect("http://localhost/a/c".to_owned()),
+            ),
+            (
+                "308",
+                HTTPRedirect::HTTPPermanentRedirect("http://localhost/a/c".to_owned()),
+            ),
+        ];
+        for (status, expected) in redirections {
+            let response = format!(
+                "HTTP/1.1 {} Redirect\r\nLocation: c\r\nContent-Length: 2\r\n\r\nGo",
+                status
+            );
+            let (redirect, has_body, written) = decode(&response).await;
+            assert_eq!(redirect, Some(expected));
+            assert!(has_body);
+            assert_eq!(written, "", "{} responses are discarded", status);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_process_header_not_followed() {
+        for status in &["300 Multiple Choices", "305 Use Proxy", "302 Found"] {
+            let location = if status.starts_with("302") {
+                ""
+            } else {
+                "Location: http://localhost/c\r\n"
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\n{}Content-Length: 2\r\n\r\nGo",
+                status, location
+            );
+            let (redirect, has_body, written) = decode(&response).await;
+            assert_eq!(redirect, None);
+            assert!(has_body);
+            assert_eq!(written, response);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_process_header_digest_challenge() {
+        let response = "HTTP/1.1 401 Unauthorized\r\n\
+                        WWW-Authenticate: Basic realm=\"test\"\r\n\
+                        WWW-Authenticate: Digest realm=\"test\", nonce=\"abc\"\r\n\
+                        Content-Length: 6\r\n\r\nDenied";
+        for digest_auth in &[true, false] {
+            let mut out = Cursor::new(Vec::new());
+            let mut reader = response.as_bytes();
+            let request = RequestBuilder::new("http://localhost/").build().unwrap();
+            let config = QueryConfig::default();
+            let attempt = Attempt {
+                digest_auth: *digest_auth,
+                ..Attempt::default()
+            };
+            let mut decoder = HttpDecoder::new(&mut out, &mut reader, &request, &config, &attempt);
+            let status_line = decoder.read_status_line().await.unwrap();
+            let headers = decoder.read_headers(status_line).await.unwrap();
+            decoder.write_headers(headers.as_slice()).await.unwrap();
+            decoder.stream_response().await.unwrap();
+            let challenge = decoder.challenge.take();
+            assert!(decoder.keep_alive);
+            let written = String::from_utf8(out.into_inner()).unwrap();
+            if *digest_auth {
+                assert!(challenge.is_some());
+                assert_eq!(
+                    challenge,
+                    DigestChallenge::parse("Digest realm=\"test\", nonce=\"abc\"")
+                );
+                assert_eq!(written, "", "answered challenges are discarded");
+            } else {
+                assert_eq!(challenge, None);
+                assert_eq!(written, response);
             }
-            Err(RedirectError::IOError(err)) => {
-                result = Err(CabotError::IOError(err));
-                break;
+        }
+    }
+
+    #[async_std::test]
+    async fn test_process_header_not_modified() {
+        // the Content-Length is the one of the cached representation.
+        let response = "HTTP/1.1 304 Not Modified\r\nContent-Length: 42\r\n\r\n";
+        let (redirect, has_body, written) = decode(response).await;
+        assert_eq!(redirect, None);
+        assert!(!has_body);
+        assert_eq!(written, response);
+    }
+
+    #[async_std::test]
+    async fn test_http_query() {
+        use super::super::test_server::{Route, TestServerBuilder};
+        use std::marker::PhantomData;
+        use std::rc::Rc;
+
+        /// A writer that is not `Send`, as `http_query` accepts them.
+        #[derive(Default)]
+        struct LocalWrite {
+            buf: Vec<u8>,
+            _local: PhantomData<Rc<()>>,
+        }
+
+        impl Write for LocalWrite {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                _cx: &mut Context,
+                buf: &[u8],
+            ) -> Poll<IoResult<usize>> {
+                Pin::get_mut(self).buf.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
             }
-            Err(RedirectError::CabotError(err)) => {
-                result = Err(err);
-                break;
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+                Poll::Ready(Ok(()))
             }
-            _ => break,
+        }
+
+        let server = TestServerBuilder::new()
+            .add_route("/", Route::redirect(302, "/next"))
+            .add_route("/next", Route::text("It is working."))
+            .start()
+            .await
+            .unwrap();
+        let mut authorities = HashMap::new();
+        authorities.insert("localhost:80".to_owned(), server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let query = |max_redir| {
+            let authorities = &authorities;
+            let request = &request;
+            async move {
+                let mut out = LocalWrite::default();
+                let result = http_query(
+                    request,
+                    &mut out,
+                    authorities,
+                    false,
+                    true,
+                    true,
+                    5000,
+                    5000,
+                    5000,
+                    0,
+                    max_redir,
+                )
+                .await;
+                result.map(|_| String::from_utf8(out.buf).unwrap())
+            }
+        };
+        let written = query(1).await.unwrap();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"), "{}", written);
+        assert!(written.ends_with("\r\n\r\nIt is working."), "{}", written);
+        match query(0).await {
+            Err(CabotError::MaxRedirectionAttempt(0)) => {}
+            other => panic!("Unexpected result {:?}", other),
         }
     }
-    out.flush().await.unwrap();
-    result
+
+    #[async_std::test]
+    async fn test_happy_eyeballs() {
+        let server = serve(vec![Route::raw(
+            "HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello",
+        )])
+        .await;
+        // The first address does not answer, the second one is refused.
+        let resolver = Arc::new(RecordingResolver {
+            addrs: vec![
+                "[100::1]:80".parse().unwrap(),
+                "127.0.0.1:1".parse().unwrap(),
+                server.addr(),
+            ],
+            queries: Mutex::new(Vec::new()),
+        });
+        let mut client = Client::new();
+        client.set_resolver(resolver);
+        client.set_connect_timeout(5);
+        let request = RequestBuilder::new("http://service.test/").build().unwrap();
+        let start = std::time::Instant::now();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+        assert_eq!(server.connections(), 1);
+    }
 }
@@ -31,6 +31,19 @@
 //!
 //! ```
 //!
+//! ## Blocking Library:
+//!
+//! ```no_run
+//! use cabot::blocking::Client;
+//! use cabot::RequestBuilder;
+//!
+//! let request = RequestBuilder::new("https://www.rust-lang.org/")
+//!     .build()
+//!     .unwrap();
+//! let response = Client::new().execute(&request).unwrap();
+//! println!("{}", response.body_as_string().unwrap());
+//! ```
+//!
 //! # Why cabot ?
 //!
 //! To get a simple rust native https client. No binding to OpenSSL.
@@ -44,22 +57,42 @@
 extern crate log;
 
 extern crate rustls;
+extern crate rustls_native_certs;
 extern crate url;
 extern crate webpki;
 extern crate webpki_roots;
 
-mod dns;
-
 mod asynctls;
 
+mod auth;
+
+mod encoding;
+
+mod socks;
+
+pub mod blocking;
 pub mod client;
 pub mod constants;
+pub mod cookies;
+pub mod dns;
 pub mod errors;
+pub mod headers;
 pub mod http;
+pub mod middleware;
+pub mod pool;
+pub mod proxy;
+pub mod redirect;
 pub mod request;
 pub mod response;
 pub mod results;
+pub mod retry;
+#[cfg(feature = "test-server")]
+pub mod test_server;
+pub mod transport;
 
 // Rexport
+pub use asynctls::TLSConfig;
 pub use client::Client;
+pub use cookies::CookieJar;
+pub use headers::HeaderMap;
 pub use request::RequestBuilder;
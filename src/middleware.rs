@@ -0,0 +1,232 @@
+//! Hooks to inspect or modify the requests sent and the responses received
+//! by a client.
+//!
+//! Middlewares are registered on the client in order, they are called for
+//! every request sent, including the redirections and the retries.
+//!
+//! # Example
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use futures::future::{self, BoxFuture};
+//!
+//! use cabot::middleware::Middleware;
+//! use cabot::request::Request;
+//! use cabot::results::CabotResult;
+//! use cabot::response::Response;
+//! use cabot::Client;
+//!
+//! struct BearerToken(String);
+//!
+//! impl Middleware for BearerToken {
+//!     fn before_request<'a>(
+//!         &'a self,
+//!         request: &'a mut Request,
+//!     ) -> BoxFuture<'a, CabotResult<Option<Response>>> {
+//!         let authorization = format!("Bearer {}", self.0);
+//!         request.headers_mut().insert("Authorization", &authorization);
+//!         Box::pin(future::ready(Ok(None)))
+//!     }
+//! }
+//!
+//! let mut client = Client::new();
+//! client.add_middleware(Arc::new(BearerToken("secret".to_owned())));
+//! ```
+use std::sync::Arc;
+
+use futures::future::{self, BoxFuture};
+
+use super::request::Request;
+use super::response::Response;
+use super::results::CabotResult;
+
+/// Inspect or modify the requests before they are sent, and the responses
+/// once their headers have been received.
+///
+/// The `before_request` hooks are called in the order the middlewares have
+/// been registered, and the `after_response` hooks in the reverse order.
+pub trait Middleware: Send + Sync {
+    /// Called before sending every request, the request can be modified or
+    /// replaced.
+    ///
+    /// Returning a response short-circuits the request, it is not sent and
+    /// the response is returned instead, after being passed to the
+    /// `after_response` hooks of the previous middlewares.
+    fn before_request<'a>(
+        &'a self,
+        _request: &'a mut Request,
+    ) -> BoxFuture<'a, CabotResult<Option<Response>>> {
+        Box::pin(future::ready(Ok(None)))
+    }
+
+    /// Called once the headers of every response have been received, the body
+    /// of the response has not been read yet.
+    ///
+    /// The headers of the response can be modified, the redirections and the
+    /// retries are decided using the received headers. Returning an error
+    /// aborts the query.
+    fn after_response<'a>(
+        &'a self,
+        _request: &'a Request,
+        _response: &'a mut Response,
+    ) -> BoxFuture<'a, CabotResult<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Call the `before_request` hooks of the `middlewares`, return the response
+/// of the middleware that short-circuits the request, if any.
+pub(crate) async fn before_request(
+    middlewares: &[Arc<dyn Middleware>],
+    request: &mut Request,
+) -> CabotResult<Option<Response>> {
+    for (pos, middleware) in middlewares.iter().enumerate() {
+        if let Some(mut response) = middleware.before_request(request).await? {
+            after_response(&middlewares[..pos], request, &mut response).await?;
+            return Ok(Some(response));
+        }
+    }
+    Ok(None)
+}
+
+/// Call the `after_response` hooks of the `middlewares`, in reverse order.
+pub(crate) async fn after_response(
+    middlewares: &[Arc<dyn Middleware>],
+    request: &Request,
+    response: &mut Response,
+) -> CabotResult<()> {
+    for middleware in middlewares.iter().rev() {
+        middleware.after_response(request, response).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use super::super::client::Client;
+    use super::super::request::RequestBuilder;
+    use super::super::response::ResponseBuilder;
+    use super::super::retry::tests::retry_client;
+    use super::super::test_server::{Route, TestServerBuilder};
+
+    /// Record the requests and responses, and tag them with a header.
+    struct TaggingMiddleware {
+        name: &'static str,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for TaggingMiddleware {
+        fn before_request<'a>(
+            &'a self,
+            request: &'a mut Request,
+        ) -> BoxFuture<'a, CabotResult<Option<Response>>> {
+            let event = format!("{} > {}", self.name, request.url());
+            self.events.lock().unwrap().push(event);
+            request.headers_mut().append("X-Middleware", self.name);
+            Box::pin(future::ready(Ok(None)))
+        }
+
+        fn after_response<'a>(
+            &'a self,
+            _request: &'a Request,
+            response: &'a mut Response,
+        ) -> BoxFuture<'a, CabotResult<()>> {
+            let event = format!("{} < {}", self.name, response.status_code());
+            self.events.lock().unwrap().push(event);
+            response.headers_mut().append("X-Middleware", self.name);
+            Box::pin(future::ready(Ok(())))
+        }
+    }
+
+    /// Reply to every request without sending it.
+    struct ShortCircuitMiddleware;
+
+    impl Middleware for ShortCircuitMiddleware {
+        fn before_request<'a>(
+            &'a self,
+            _request: &'a mut Request,
+        ) -> BoxFuture<'a, CabotResult<Option<Response>>> {
+            let response = ResponseBuilder::new()
+                .set_status_line("HTTP/1.1 200 Ok")
+                .set_body(b"cached")
+                .build();
+            Box::pin(future::ready(response.map(Some)))
+        }
+    }
+
+    #[async_std::test]
+    async fn test_middleware() {
+        let server = TestServerBuilder::new()
+            .add_route(
+                "/",
+                Route::raw(
+                    "HTTP/1.1 302 Found\r\nLocation: http://localhost/next\r\n\
+                     Content-Length: 0\r\n\r\n",
+                ),
+            )
+            .add_route(
+                "/next",
+                Route::Sequence(vec![
+                    Route::raw("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n"),
+                    Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello"),
+                ]),
+            )
+            .start()
+            .await
+            .unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut client = retry_client(&server.addr(), 1);
+        for name in &["a", "b"] {
+            client.add_middleware(Arc::new(TaggingMiddleware {
+                name,
+                events: events.clone(),
+            }));
+        }
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert_eq!(response.headers().get_all("X-Middleware"), vec!["b", "a"]);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests
+            .iter()
+            .all(|req| req.contains("X-Middleware: a\r\nX-Middleware: b\r\n")));
+        let mut expected = Vec::new();
+        for (url, status) in &[("/", 302), ("/next", 503), ("/next", 200)] {
+            expected.push(format!("a > http://localhost{}", url));
+            expected.push(format!("b > http://localhost{}", url));
+            expected.push(format!("b < {}", status));
+            expected.push(format!("a < {}", status));
+        }
+        assert_eq!(*events.lock().unwrap(), expected);
+    }
+
+    #[async_std::test]
+    async fn test_middleware_short_circuit() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut client = Client::new();
+        client.add_authority("localhost:80", &"127.0.0.1:1".parse().unwrap());
+        client.add_middleware(Arc::new(TaggingMiddleware {
+            name: "a",
+            events: events.clone(),
+        }));
+        client.add_middleware(Arc::new(ShortCircuitMiddleware));
+        client.add_middleware(Arc::new(TaggingMiddleware {
+            name: "b",
+            events: events.clone(),
+        }));
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "cached");
+        assert_eq!(response.headers().get_all("X-Middleware"), vec!["a"]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["a > http://localhost/", "a < 200"]
+        );
+    }
+}
@@ -0,0 +1,355 @@
+//! Keep-alive connections pool.
+//!
+//! Connections are stored per authority once a response has been
+//! fully read, in order to be reused by the next query to the same
+//! authority, including redirections.
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_std::io::{Read, Result as IoResult, Write};
+use async_std::net::TcpStream;
+use async_std::task::{Context, Poll};
+
+use super::asynctls::TLSStream;
+use super::transport::TransportStream;
+
+/// A connection to an authority, ciphered or not.
+pub(crate) enum Connection {
+    Plain(TcpStream),
+    Tls(Box<TLSStream>),
+    /// A connection opened by a custom transport.
+    Custom(Box<dyn TransportStream>),
+}
+
+impl Read for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        match Pin::get_mut(self) {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Custom(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        match Pin::get_mut(self) {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Custom(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        match Pin::get_mut(self) {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Custom(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        match Pin::get_mut(self) {
+            Connection::Plain(stream) => Pin::new(stream).poll_close(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_close(cx),
+            Connection::Custom(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+impl Connection {
+    /// True if the TLS session has been resumed, None for plain connections
+    /// and the connections of custom transports.
+    pub(crate) fn tls_session_resumed(&self) -> Option<bool> {
+        match self {
+            Connection::Plain(_) | Connection::Custom(_) => None,
+            Connection::Tls(stream) => Some(stream.is_resumed()),
+        }
+    }
+}
+
+struct IdleConnection {
+    connection: Connection,
+    idle_since: Instant,
+}
+
+/// Idle connections, kept alive to be reused.
+///
+/// Connections are identified by their scheme and their authority,
+/// `https://www.rust-lang.org:443` for instance.
+pub struct ConnectionPool {
+    max_idle_per_host: AtomicUsize,
+    idle_timeout: AtomicU64,
+    idle: Mutex<HashMap<String, Vec<IdleConnection>>>,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        ConnectionPool::new(0, 0)
+    }
+}
+
+impl ConnectionPool {
+    /// Construct a new pool.
+    ///
+    /// `max_idle_per_host` is the number of idle connections kept per
+    /// authority, `0` disable keep-alive.
+    /// `idle_timeout` is the time in milliseconds a connection can be idle
+    /// before being closed, `0` means no timeout.
+    pub fn new(max_idle_per_host: usize, idle_timeout: u64) -> Self {
+        ConnectionPool {
+            max_idle_per_host: AtomicUsize::new(max_idle_per_host),
+            idle_timeout: AtomicU64::new(idle_timeout),
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set the number of idle connections kept per authority.
+    pub fn set_max_idle_per_host(&self, max_idle_per_host: usize) {
+        self.max_idle_per_host
+            .store(max_idle_per_host, Ordering::Relaxed);
+        let mut idle = self.idle.lock().unwrap();
+        for conns in idle.values_mut() {
+            // keep the most recent ones
+            let excess = conns.len().saturating_sub(max_idle_per_host);
+            conns.drain(..excess);
+        }
+    }
+
+    /// Set the time in milliseconds a connection can be idle in the pool.
+    pub fn set_idle_timeout(&self, idle_timeout: u64) {
+        self.idle_timeout.store(idle_timeout, Ordering::Relaxed);
+    }
+
+    /// True if connections may be kept alive.
+    pub fn keep_alive(&self) -> bool {
+        self.max_idle_per_host() > 0
+    }
+
+    fn max_idle_per_host(&self) -> usize {
+        self.max_idle_per_host.load(Ordering::Relaxed)
+    }
+
+    /// Number of idle connections for the given key.
+    pub fn idle_count(&self, key: &str) -> usize {
+        let idle = self.idle.lock().unwrap();
+        idle.get(key).map(|conns| conns.len()).unwrap_or(0)
+    }
+
+    /// Close every idle connections.
+    pub fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+
+    fn is_expired(&self, conn: &IdleConnection) -> bool {
+        let idle_timeout = self.idle_timeout.load(Ordering::Relaxed);
+        idle_timeout > 0 && conn.idle_since.elapsed() > Duration::from_millis(idle_timeout)
+    }
+
+    /// Take the most recently used connection for the given key.
+    pub(crate) fn checkout(&self, key: &str) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.get_mut(key)?;
+        let mut found = None;
+        while let Some(conn) = conns.pop() {
+            if self.is_expired(&conn) {
+                debug!("Drop expired connection to {}", key);
+                continue;
+            }
+            found = Some(conn.connection);
+            break;
+        }
+        if conns.is_empty() {
+            idle.remove(key);
+        }
+        found
+    }
+
+    /// Give back a connection that can be reused.
+    pub(crate) fn checkin(&self, key: &str, connection: Connection) {
+        if !self.keep_alive() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.entry(key.to_owned()).or_default();
+        conns.retain(|conn| !self.is_expired(conn));
+        if conns.len() >= self.max_idle_per_host() {
+            // drop the oldest one
+            conns.remove(0);
+        }
+        conns.push(IdleConnection {
+            connection,
+            idle_since: Instant::now(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::task;
+
+    use super::super::client::query_tests::{client, serve};
+    use super::super::request::RequestBuilder;
+    use super::super::test_server::{Route, TestServerBuilder};
+
+    async fn connect(listener: &TcpListener) -> Connection {
+        let addr = listener.local_addr().unwrap();
+        Connection::Plain(TcpStream::connect(addr).await.unwrap())
+    }
+
+    #[async_std::test]
+    async fn test_checkout_checkin() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = ConnectionPool::new(2, 0);
+        assert!(pool.checkout("http://127.0.0.1:80").is_none());
+
+        for _ in 0..3 {
+            pool.checkin("http://127.0.0.1:80", connect(&listener).await);
+        }
+        assert_eq!(pool.idle_count("http://127.0.0.1:80"), 2);
+        assert_eq!(pool.idle_count("https://127.0.0.1:80"), 0);
+
+        assert!(pool.checkout("http://127.0.0.1:80").is_some());
+        assert!(pool.checkout("http://127.0.0.1:80").is_some());
+        assert!(pool.checkout("http://127.0.0.1:80").is_none());
+    }
+
+    #[async_std::test]
+    async fn test_keep_alive_disabled() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = ConnectionPool::new(0, 0);
+        assert!(!pool.keep_alive());
+        pool.checkin("http://127.0.0.1:80", connect(&listener).await);
+        assert_eq!(pool.idle_count("http://127.0.0.1:80"), 0);
+    }
+
+    #[async_std::test]
+    async fn test_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = ConnectionPool::new(4, 10);
+        pool.checkin("http://127.0.0.1:80", connect(&listener).await);
+        task::sleep(Duration::from_millis(20)).await;
+        assert!(pool.checkout("http://127.0.0.1:80").is_none());
+        assert_eq!(pool.idle_count("http://127.0.0.1:80"), 0);
+    }
+
+    #[async_std::test]
+    async fn test_connection_reused() {
+        let server = serve(vec![
+            Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nfirst"),
+            Route::raw(
+                "HTTP/1.1 200 Ok\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 3\r\nsec\r\n3\r\nond\r\n0\r\n\r\n",
+            ),
+        ])
+        .await;
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "first");
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "second");
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_connection_closed_by_server() {
+        let server = serve(vec![
+            Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 5\r\nConnection: close\r\n\r\nfirst"),
+            Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 6\r\n\r\nsecond"),
+        ])
+        .await;
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "first");
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "second");
+        assert_eq!(server.connections(), 2);
+    }
+
+    #[async_std::test]
+    async fn test_idle_connection_closed() {
+        let server = serve(vec![
+            Route::raw_and_close("HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nfirst"),
+            Route::raw_and_close("HTTP/1.1 200 Ok\r\nContent-Length: 6\r\n\r\nsecond"),
+        ])
+        .await;
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "first");
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "second");
+        assert_eq!(server.connections(), 2);
+    }
+
+    #[async_std::test]
+    async fn test_redirect_reuse_connection() {
+        let server = TestServerBuilder::new()
+            .add_route(
+                "/",
+                Route::raw(
+                    "HTTP/1.1 302 Found\r\nLocation: http://localhost/next\r\n\
+                     Content-Length: 9\r\n\r\nGo next !",
+                ),
+            )
+            .add_route(
+                "/next",
+                Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 4\r\n\r\nnext"),
+            )
+            .start()
+            .await
+            .unwrap();
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body_as_string().unwrap(), "next");
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_not_modified_reuse_connection() {
+        let server = serve(vec![
+            Route::raw("HTTP/1.1 304 Not Modified\r\nContent-Length: 42\r\n\r\n"),
+            Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 4\r\n\r\nnext"),
+        ])
+        .await;
+        let client = client(&server.addr());
+        let request = RequestBuilder::new("http://localhost/")
+            .add_header("If-None-Match: \"v1\"")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 304);
+        assert_eq!(response.body_as_string().unwrap(), "");
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "next");
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_client_keep_alive_disabled() {
+        let server = serve(vec![
+            Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nfirst"),
+            Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 6\r\n\r\nsecond"),
+        ])
+        .await;
+        let mut client = client(&server.addr());
+        client.set_max_idle_per_host(0);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        client.execute(&request).await.unwrap();
+        client.execute(&request).await.unwrap();
+        assert_eq!(server.connections(), 2);
+    }
+}
@@ -0,0 +1,499 @@
+//! Decide which redirections are followed.
+//!
+//! By default, the client follows up to 16 redirections and then fails.
+//! A `RedirectPolicy` is set on the client to change that:
+//!
+//! ```
+//! use cabot::redirect::{RedirectAction, RedirectPolicy};
+//! use cabot::Client;
+//!
+//! let mut client = Client::new();
+//! // stay on the same host.
+//! client.set_redirect_policy(RedirectPolicy::custom(|redirection| {
+//!     if redirection.location().starts_with("https://www.rust-lang.org/") {
+//!         RedirectAction::Follow
+//!     } else {
+//!         RedirectAction::Stop
+//!     }
+//! }));
+//! ```
+//!
+//! The redirections followed, and the final url, are available on the
+//! [Response](../response/struct.Response.html).
+use std::fmt;
+use std::sync::Arc;
+
+use url::Url;
+
+use super::constants;
+use super::errors::{ErrorContext, Phase};
+use super::results::CabotError;
+
+/// What to do with a redirection response.
+#[derive(Debug)]
+pub enum RedirectAction {
+    /// Send a request to the location of the redirection.
+    Follow,
+    /// Return the redirection response.
+    Stop,
+    /// Fail with the error.
+    Error(CabotError),
+}
+
+/// A redirection response received, about to be followed.
+#[derive(Debug)]
+pub struct Redirection<'a> {
+    status: u16,
+    url: &'a str,
+    location: &'a str,
+    previous: &'a [(u16, String)],
+}
+
+impl<'a> Redirection<'a> {
+    pub(crate) fn new(
+        status: u16,
+        url: &'a str,
+        location: &'a str,
+        previous: &'a [(u16, String)],
+    ) -> Self {
+        Redirection {
+            status,
+            url,
+            location,
+            previous,
+        }
+    }
+
+    /// The status code of the redirection response.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// The url of the request that has been redirected.
+    pub fn url(&self) -> &str {
+        self.url
+    }
+
+    /// The location the request is redirected to, resolved against the url
+    /// of the request.
+    pub fn location(&self) -> &str {
+        self.location
+    }
+
+    /// The redirections already followed, the status code of the response
+    /// and the url of the request of every one.
+    pub fn previous(&self) -> &[(u16, String)] {
+        self.previous
+    }
+}
+
+type RedirectCallback = dyn Fn(&Redirection) -> RedirectAction + Send + Sync;
+
+#[derive(Clone)]
+enum Policy {
+    None,
+    Limited(u8),
+    Custom(Arc<RedirectCallback>),
+}
+
+/// Decide which redirections are followed.
+#[derive(Clone)]
+pub struct RedirectPolicy {
+    policy: Policy,
+    allow_downgrade: bool,
+}
+
+impl RedirectPolicy {
+    /// Never follow the redirections, the redirection responses are returned.
+    pub fn none() -> Self {
+        RedirectPolicy {
+            policy: Policy::None,
+            allow_downgrade: false,
+        }
+    }
+
+    /// Follow up to `max_redir` redirections, and then fail with
+    /// `CabotError::MaxRedirectionAttempt`.
+    pub fn limited(max_redir: u8) -> Self {
+        RedirectPolicy {
+            policy: Policy::Limited(max_redir),
+            allow_downgrade: false,
+        }
+    }
+
+    /// Call the `callback` for every redirection, to decide if it is followed.
+    pub fn custom<F>(callback: F) -> Self
+    where
+        F: Fn(&Redirection) -> RedirectAction + Send + Sync + 'static,
+    {
+        RedirectPolicy {
+            policy: Policy::Custom(Arc::new(callback)),
+            allow_downgrade: false,
+        }
+    }
+
+    /// Follow the redirections from https to http. Disabled by default,
+    /// the query fails with `CabotError::InsecureRedirection`.
+    pub fn set_allow_https_downgrade(&mut self, allowed: bool) {
+        self.allow_downgrade = allowed;
+    }
+
+    /// True if the redirections from https to http are followed.
+    pub fn allow_https_downgrade(&self) -> bool {
+        self.allow_downgrade
+    }
+
+    /// Decide what to do with the `redirection`.
+    pub fn redirect(&self, redirection: &Redirection) -> RedirectAction {
+        if let Policy::None = self.policy {
+            return RedirectAction::Stop;
+        }
+        if !self.allow_downgrade && is_downgrade(redirection.url(), redirection.location()) {
+            return RedirectAction::Error(CabotError::InsecureRedirection(
+                ErrorContext::new(redirection.url(), Phase::ReadHeaders),
+                redirection.location().to_owned(),
+            ));
+        }
+        match &self.policy {
+            Policy::None => RedirectAction::Stop,
+            Policy::Limited(max_redir) => {
+                if redirection.previous().len() < *max_redir as usize {
+                    RedirectAction::Follow
+                } else {
+                    RedirectAction::Error(CabotError::MaxRedirectionAttempt(*max_redir))
+                }
+            }
+            Policy::Custom(callback) => callback(redirection),
+        }
+    }
+}
+
+impl Default for RedirectPolicy {
+    /// Follow up to 16 redirections.
+    fn default() -> Self {
+        RedirectPolicy::limited(constants::NUMBER_OF_REDIRECT)
+    }
+}
+
+impl fmt::Debug for RedirectPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.policy {
+            Policy::None => write!(f, "RedirectPolicy::None"),
+            Policy::Limited(max_redir) => write!(f, "RedirectPolicy::Limited({})", max_redir),
+            Policy::Custom(_) => write!(f, "RedirectPolicy::Custom"),
+        }
+    }
+}
+
+/// True if the redirection leads from https to http.
+fn is_downgrade(url: &str, location: &str) -> bool {
+    url.starts_with("https://") && location.starts_with("http://")
+}
+
+/// True if both urls have the same scheme, host and port.
+pub(crate) fn is_same_origin(url: &str, other: &str) -> bool {
+    match (Url::parse(url), Url::parse(other)) {
+        (Ok(url), Ok(other)) => url.origin() == other.origin(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::client::Client;
+    use super::super::request::RequestBuilder;
+    use super::super::transport::MockTransport;
+
+    fn history(count: usize) -> Vec<(u16, String)> {
+        (0..count)
+            .map(|idx| (302, format!("http://localhost/{}", idx)))
+            .collect()
+    }
+
+    #[test]
+    fn test_limited() {
+        let policy = RedirectPolicy::limited(2);
+        for count in 0..2 {
+            let previous = history(count);
+            let redirection = Redirection::new(302, "http://localhost/", "/next", &previous);
+            assert!(matches!(
+                policy.redirect(&redirection),
+                RedirectAction::Follow
+            ));
+        }
+        let previous = history(2);
+        let redirection = Redirection::new(302, "http://localhost/", "/next", &previous);
+        match policy.redirect(&redirection) {
+            RedirectAction::Error(err) => {
+                assert_eq!(err.to_string(), "Maximum redirection attempt: 2")
+            }
+            action => panic!("Unexpected action: {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_none_and_custom() {
+        let previous = history(0);
+        let redirection =
+            Redirection::new(301, "http://localhost/", "https://localhost/", &previous);
+        assert!(matches!(
+            RedirectPolicy::none().redirect(&redirection),
+            RedirectAction::Stop
+        ));
+
+        let policy = RedirectPolicy::custom(|redirection| {
+            if redirection.status() == 301 && redirection.location().starts_with("https://") {
+                RedirectAction::Follow
+            } else {
+                RedirectAction::Stop
+            }
+        });
+        assert!(matches!(
+            policy.redirect(&redirection),
+            RedirectAction::Follow
+        ));
+        let redirection =
+            Redirection::new(302, "http://localhost/", "https://localhost/", &previous);
+        assert!(matches!(
+            policy.redirect(&redirection),
+            RedirectAction::Stop
+        ));
+    }
+
+    #[test]
+    fn test_https_downgrade() {
+        let previous = history(0);
+        let redirection =
+            Redirection::new(302, "https://localhost/", "http://localhost/", &previous);
+        let mut policy = RedirectPolicy::default();
+        match policy.redirect(&redirection) {
+            RedirectAction::Error(err) => assert_eq!(
+                err.to_string(),
+                "Insecure Redirection while reading the response headers of \
+                 https://localhost/: to http://localhost/"
+            ),
+            action => panic!("Unexpected action: {:?}", action),
+        }
+        policy.set_allow_https_downgrade(true);
+        assert!(matches!(
+            policy.redirect(&redirection),
+            RedirectAction::Follow
+        ));
+
+        let redirection =
+            Redirection::new(302, "http://localhost/", "https://localhost/", &previous);
+        policy.set_allow_https_downgrade(false);
+        assert!(matches!(
+            policy.redirect(&redirection),
+            RedirectAction::Follow
+        ));
+    }
+
+    #[test]
+    fn test_is_same_origin() {
+        assert!(is_same_origin(
+            "http://localhost/a",
+            "http://localhost:80/b?c"
+        ));
+        assert!(!is_same_origin("http://localhost/", "https://localhost/"));
+        assert!(!is_same_origin(
+            "http://localhost/",
+            "http://localhost:8080/"
+        ));
+        assert!(!is_same_origin("http://localhost/", "http://example.com/"));
+    }
+
+    #[async_std::test]
+    async fn test_multiple_choices() {
+        let transport = Arc::new(MockTransport::new());
+        transport.add_response(
+            b"HTTP/1.1 300 Multiple Choices\r\nLocation: /doc.en.html\r\n\
+              Link: </doc.fr.html>; rel=alternate\r\nContent-Length: 6\r\n\r\nChoose",
+        );
+        transport.add_response(
+            b"HTTP/1.1 305 Use Proxy\r\nLocation: http://proxy.example.com/\r\n\
+              Content-Length: 0\r\n\r\n",
+        );
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+        let request = RequestBuilder::new("http://example.com/doc")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 300);
+        assert_eq!(response.body_as_string().unwrap(), "Choose");
+        assert_eq!(
+            response.alternatives(),
+            vec!["/doc.en.html", "/doc.fr.html"]
+        );
+
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 305);
+        assert!(response.redirects().is_empty());
+        assert_eq!(transport.connections(), vec!["example.com:80"]);
+    }
+
+    #[async_std::test]
+    async fn test_redirect_history() {
+        let transport = Arc::new(MockTransport::new());
+        transport.add_response(
+            b"HTTP/1.1 301 Moved Permanently\r\nLocation: http://example.com/b\r\n\
+              Content-Length: 0\r\n\r\n",
+        );
+        transport.add_response(
+            b"HTTP/1.1 307 Temporary Redirect\r\nLocation: http://other.com/c\r\n\
+              Content-Length: 0\r\n\r\n",
+        );
+        transport.add_response(b"HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello");
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+        let request = RequestBuilder::new("http://example.com/a")
+            .add_header("Authorization: Bearer secret")
+            .add_header("Cookie: sid=42")
+            .add_header("X-Trace: 1")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert_eq!(response.url(), Some("http://other.com/c"));
+        assert_eq!(
+            response.redirects(),
+            &[
+                (301, "http://example.com/a".to_owned()),
+                (307, "http://example.com/b".to_owned()),
+            ]
+        );
+
+        let requests: Vec<String> = transport
+            .requests()
+            .into_iter()
+            .map(|req| String::from_utf8(req).unwrap())
+            .collect();
+        // same origin, the credentials are kept.
+        assert!(requests[1].starts_with("GET /b HTTP/1.1\r\n"));
+        assert!(requests[1].contains("\r\nAuthorization: Bearer secret\r\n"));
+        assert!(requests[1].contains("\r\nCookie: sid=42\r\n"));
+        assert!(requests[1].contains("\r\nX-Trace: 1\r\n"));
+        // another origin, the credentials are stripped.
+        assert!(requests[2].starts_with("GET /c HTTP/1.1\r\n"));
+        assert!(!requests[2].contains("Authorization"));
+        assert!(!requests[2].contains("Cookie"));
+        assert!(requests[2].contains("\r\nX-Trace: 1\r\n"));
+    }
+
+    #[async_std::test]
+    async fn test_redirect_relative_location() {
+        let transport = Arc::new(MockTransport::new());
+        for location in &["../next", "//other.com/path", "/login"] {
+            let response = format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+                location
+            );
+            transport.add_response(response.as_bytes());
+        }
+        transport.add_response(b"HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello");
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+        let request = RequestBuilder::new("http://example.com/a/b/c")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert_eq!(response.url(), Some("http://other.com/login"));
+        assert_eq!(
+            response.redirects(),
+            &[
+                (302, "http://example.com/a/b/c".to_owned()),
+                (302, "http://example.com/a/next".to_owned()),
+                (302, "http://other.com/path".to_owned()),
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_redirect_https_downgrade() {
+        let transport = Arc::new(MockTransport::new());
+        for _ in 0..2 {
+            transport.add_response(
+                b"HTTP/1.1 301 Moved Permanently\r\nLocation: http://example.com/\r\n\
+                  Content-Length: 0\r\n\r\n",
+            );
+        }
+        transport.add_response(b"HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello");
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+        let request = RequestBuilder::new("https://example.com/").build().unwrap();
+        let err = client.execute(&request).await.unwrap_err();
+        assert!(matches!(err, CabotError::InsecureRedirection(_, _)));
+        assert_eq!(err.context().unwrap().url(), "https://example.com/");
+
+        let mut policy = RedirectPolicy::default();
+        policy.set_allow_https_downgrade(true);
+        client.set_redirect_policy(policy);
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.url(), Some("http://example.com/"));
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+    }
+
+    #[async_std::test]
+    async fn test_max_redir_allow_https_downgrade() {
+        let transport = Arc::new(MockTransport::new());
+        let request = RequestBuilder::new("https://example.com/").build().unwrap();
+        for max_redir_first in &[true, false] {
+            transport.add_response(
+                b"HTTP/1.1 301 Moved Permanently\r\nLocation: http://example.com/\r\n\
+                  Content-Length: 0\r\n\r\n",
+            );
+            transport.add_response(b"HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello");
+            let mut client = Client::new();
+            client.set_transport(transport.clone());
+            if *max_redir_first {
+                client.set_max_redir(5);
+                client.set_allow_https_downgrade(true);
+            } else {
+                client.set_allow_https_downgrade(true);
+                client.set_max_redir(5);
+            }
+            let response = client.execute(&request).await.unwrap();
+            assert_eq!(response.url(), Some("http://example.com/"));
+            assert_eq!(response.body_as_string().unwrap(), "Hello");
+        }
+    }
+
+    #[async_std::test]
+    async fn test_redirect_policy() {
+        let transport = Arc::new(MockTransport::new());
+        for _ in 0..2 {
+            transport.add_response(
+                b"HTTP/1.1 302 Found\r\nLocation: http://other.com/\r\n\
+                  Content-Length: 9\r\n\r\nGo next !",
+            );
+        }
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+        client.set_redirect_policy(RedirectPolicy::none());
+        let request = RequestBuilder::new("http://example.com/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 302);
+        assert_eq!(
+            response.headers().get("Location"),
+            Some("http://other.com/")
+        );
+        assert_eq!(response.body_as_string().unwrap(), "Go next !");
+        assert_eq!(response.url(), Some("http://example.com/"));
+        assert!(response.redirects().is_empty());
+
+        client.set_redirect_policy(RedirectPolicy::custom(|redirection| {
+            if redirection.location().starts_with("http://example.com/") {
+                RedirectAction::Follow
+            } else {
+                RedirectAction::Error(CabotError::MaxRedirectionAttempt(0))
+            }
+        }));
+        let err = client.execute(&request).await.unwrap_err();
+        assert_eq!(err.to_string(), "Maximum redirection attempt: 0");
+        assert_eq!(transport.requests().len(), 2);
+    }
+}
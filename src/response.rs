@@ -16,10 +16,20 @@
 //! assert_eq!(response.http_version(), "HTTP/1.1");
 //! assert_eq!(response.status_code(), 200);
 //! assert_eq!(response.status_line(), "200 Ok");
-//! assert_eq!(response.headers(), &["Content-Type: application/json"]);
+//! assert_eq!(response.headers().get("content-type"), Some("application/json"));
 //! assert_eq!(response.body_as_string().unwrap(), "{}");
 //! ```
 
+use std::fmt;
+use std::pin::Pin;
+
+use async_std::io::{Read, Result as IoResult};
+use async_std::stream::Stream;
+use async_std::task::{Context, Poll};
+use futures::channel::mpsc::Receiver;
+
+use super::headers::HeaderMap;
+use super::http::QueryInfo;
 use super::results::{CabotError, CabotResult};
 
 /// Represent the parsed HTTP response.
@@ -28,8 +38,12 @@ pub struct Response {
     http_version: String,
     status_code: usize,
     status_line: String,
-    headers: Vec<String>,
+    headers: HeaderMap,
     body: Option<Vec<u8>>,
+    body_stream: Option<BodyStream>,
+    tls_session_resumed: Option<bool>,
+    url: Option<String>,
+    redirects: Vec<(u16, String)>,
 }
 
 impl Response {
@@ -37,7 +51,7 @@ impl Response {
         http_version: String,
         status_code: usize,
         status_line: String,
-        headers: Vec<String>,
+        headers: HeaderMap,
         body: Option<Vec<u8>>,
     ) -> Response {
         Response {
@@ -46,9 +60,47 @@ impl Response {
             status_line,
             headers,
             body,
+            body_stream: None,
+            tls_session_resumed: None,
+            url: None,
+            redirects: Vec::new(),
         }
     }
 
+    /// Attach the stream that deliver the body of the response.
+    pub(crate) fn set_body_stream(mut self, body_stream: BodyStream) -> Self {
+        self.body_stream = Some(body_stream);
+        self
+    }
+
+    /// Set the diagnostic informations of the query.
+    pub(crate) fn set_query_info(mut self, info: &QueryInfo) -> Self {
+        self.tls_session_resumed = info.tls_session_resumed();
+        self.url = Some(info.url().to_owned());
+        self.redirects = info.redirects().to_vec();
+        self
+    }
+
+    /// True if the TLS session of the connection has been resumed instead
+    /// of performing a full handshake, None if the response has not been
+    /// received over TLS.
+    pub fn tls_session_resumed(&self) -> Option<bool> {
+        self.tls_session_resumed
+    }
+
+    /// The url of the request that received the response, once the
+    /// redirections have been followed. None if the response has not been
+    /// received by a client.
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    /// The redirections followed to receive the response, the status code of
+    /// the redirection response and the url of its request for every one.
+    pub fn redirects(&self) -> &[(u16, String)] {
+        self.redirects.as_slice()
+    }
+
     /// The response http version such as `HTTP/1.1` extracted from the
     /// repsonse status line.
     pub fn http_version(&self) -> &str {
@@ -69,14 +121,73 @@ impl Response {
         self.status_line.as_str()
     }
 
-    /// Response headers.
-    /// Headers are not key/value parsed here to avoid deduplicates them.
-    /// But multiline headers
+    /// Response headers, in the order they have been received.
+    /// Multiline headers
     /// ([obsolete line folding](https://tools.ietf.org/html/rfc7230#section-3.2]))
-    /// are implemented as specified and CRLF separator are preserved.
-    pub fn headers(&self) -> Vec<&str> {
-        let headers: Vec<&str> = self.headers.iter().map(|s| s.as_ref()).collect();
-        headers
+    /// are unfolded.
+    ///
+    /// If the body has been decoded, its `Content-Encoding` and
+    /// `Content-Length` headers are removed.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// The alternatives of a `300 Multiple Choices` response, the preferred
+    /// one from the `Location` header first, and then the targets of the
+    /// `Link` headers with the `alternate` relation type.
+    ///
+    /// Other responses have no alternatives.
+    pub fn alternatives(&self) -> Vec<&str> {
+        if self.status_code != 300 {
+            return Vec::new();
+        }
+        let mut alternatives: Vec<&str> = self.headers.get_all("Location");
+        for link in self.headers.get_all("Link") {
+            for link_value in link.split(',') {
+                let mut params = link_value.split(';');
+                let target = params.next().unwrap_or("").trim();
+                let is_alternate = params.any(|param| {
+                    let param = param.trim();
+                    param.starts_with("rel=")
+                        && param[4..]
+                            .trim_matches('"')
+                            .split_whitespace()
+                            .any(|rel| rel.eq_ignore_ascii_case("alternate"))
+                });
+                if is_alternate && target.starts_with('<') && target.ends_with('>') {
+                    alternatives.push(&target[1..target.len() - 1]);
+                }
+            }
+        }
+        alternatives
+    }
+
+    /// Response headers, to modify them.
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    /// The status line and the headers, as they are received.
+    pub(crate) fn encode_head(&self) -> Vec<u8> {
+        format!(
+            "{} {}\r\n{}\r\n",
+            self.http_version, self.status_line, self.headers
+        )
+        .into_bytes()
+    }
+
+    /// The body of a response returned by
+    /// [Client::execute_stream](../client/struct.Client.html#method.execute_stream).
+    ///
+    /// The body is read while consuming the stream, `body()` always returns
+    /// `None` for those responses.
+    pub fn body_stream(&mut self) -> Option<&mut BodyStream> {
+        self.body_stream.as_mut()
+    }
+
+    /// Consume the response to retrieve the stream of the body.
+    pub fn into_body_stream(self) -> Option<BodyStream> {
+        self.body_stream
     }
 
     /// Get the body in raw format.
@@ -108,13 +219,103 @@ impl Response {
     }
 }
 
+/// The body of a response, read while the bytes are received.
+///
+/// It can be consumed using the `async_std::io::Read` trait, or as
+/// a `Stream` of bytes chunks.
+pub struct BodyStream {
+    receiver: Receiver<IoResult<Vec<u8>>>,
+    /// chunk partially consumed by a read.
+    chunk: Vec<u8>,
+}
+
+impl BodyStream {
+    pub(crate) fn new(receiver: Receiver<IoResult<Vec<u8>>>) -> Self {
+        BodyStream {
+            receiver,
+            chunk: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BodyStream")
+    }
+}
+
+impl Stream for BodyStream {
+    type Item = IoResult<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let self_ = Pin::get_mut(self);
+        if !self_.chunk.is_empty() {
+            let chunk = std::mem::take(&mut self_.chunk);
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        Pin::new(&mut self_.receiver).poll_next(cx)
+    }
+}
+
+impl Read for BodyStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        let self_ = Pin::get_mut(self);
+        while self_.chunk.is_empty() {
+            match futures::ready!(Pin::new(&mut self_.receiver).poll_next(cx)) {
+                Some(Ok(chunk)) => self_.chunk = chunk,
+                Some(Err(err)) => return Poll::Ready(Err(err)),
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let count = std::cmp::min(buf.len(), self_.chunk.len());
+        buf[..count].copy_from_slice(&self_.chunk[..count]);
+        self_.chunk.drain(..count);
+        Poll::Ready(Ok(count))
+    }
+}
+
+/// Parse the status line and the headers of a response.
+pub(crate) fn parse_headers(buf: &[u8]) -> ResponseBuilder {
+    let mut builder = ResponseBuilder::new();
+    if let Some(pos) = buf.iter().position(|&x| x == b'\n') {
+        let (status_line, hdrs) = buf.split_at(pos);
+        let status_line = String::from_utf8_lossy(status_line);
+        builder = builder.set_status_line(status_line.trim_end());
+        let mut header = "".to_owned();
+        for hdr in hdrs.split(|&x| x == b'\n') {
+            let hdr = String::from_utf8_lossy(hdr);
+            if hdr.starts_with(' ') || hdr.starts_with('\t') {
+                debug!("Obsolete line folded header reveived in {}", header);
+                header.push(' ');
+                header.push_str(hdr.trim());
+            } else {
+                let clean_hdr = header.trim();
+                if !clean_hdr.is_empty() {
+                    builder = builder.add_header(clean_hdr.trim());
+                    header.clear();
+                }
+                header.push_str(hdr.trim());
+            }
+        }
+        let clean_hdr = header.trim();
+        if !clean_hdr.is_empty() {
+            builder = builder.add_header(clean_hdr.trim());
+        }
+    }
+    builder
+}
+
 #[derive(Debug, Default)]
 /// An internal class used to build response.
 ///
 ///
 pub struct ResponseBuilder {
     status_line: Option<String>,
-    headers: Vec<String>,
+    headers: HeaderMap,
     body: Option<Vec<u8>>,
 }
 
@@ -123,7 +324,7 @@ impl ResponseBuilder {
     pub fn new() -> Self {
         ResponseBuilder {
             status_line: None,
-            headers: Vec::new(),
+            headers: HeaderMap::new(),
             body: None,
         }
     }
@@ -134,9 +335,9 @@ impl ResponseBuilder {
         self
     }
 
-    /// Append an header
+    /// Append an header from a `Name: value` line
     pub fn add_header(mut self, header: &str) -> Self {
-        self.headers.push(header.to_owned());
+        self.headers.append_line(header);
         self
     }
 
@@ -201,14 +402,21 @@ mod tests {
             "HTTP/1.1".to_owned(),
             200,
             "200 Ok".to_owned(),
-            vec!["Content-Type: application/json".to_owned()],
+            {
+                let mut headers = HeaderMap::new();
+                headers.append("Content-Type", "application/json");
+                headers
+            },
             Some(vec![123, 125]),
         );
 
         assert_eq!(response.http_version(), "HTTP/1.1");
         assert_eq!(response.status_code(), 200);
         assert_eq!(response.status_line(), "200 Ok");
-        assert_eq!(response.headers(), &["Content-Type: application/json"]);
+        assert_eq!(
+            response.headers().lines(),
+            &["Content-Type: application/json"]
+        );
         let body: &[u8] = &[123, 125];
         assert_eq!(response.body(), Some(body));
         assert_eq!(response.body_as_string().unwrap(), "{}");
@@ -220,19 +428,74 @@ mod tests {
             "HTTP/1.1".to_owned(),
             204,
             "204 No Content".to_owned(),
-            vec![],
+            HeaderMap::new(),
             None,
         );
 
         assert_eq!(response.http_version(), "HTTP/1.1");
         assert_eq!(response.status_code(), 204);
         assert_eq!(response.status_line(), "204 No Content");
-        let headers: &[&str] = &[];
-        assert_eq!(response.headers(), headers);
+        assert!(response.headers().is_empty());
         assert_eq!(response.body(), None);
         assert_eq!(response.body_as_string().unwrap(), "".to_string());
     }
 
+    #[async_std::test]
+    async fn test_response_body_stream() {
+        use async_std::prelude::*;
+        use futures::channel::mpsc::channel;
+        use futures::SinkExt;
+
+        let (mut sender, receiver) = channel(4);
+        let mut response = ResponseBuilder::new()
+            .set_status_line("HTTP/1.1 200 Ok")
+            .build()
+            .unwrap()
+            .set_body_stream(BodyStream::new(receiver));
+        sender.send(Ok(b"Hello".to_vec())).await.unwrap();
+        sender.send(Ok(b" World!".to_vec())).await.unwrap();
+        drop(sender);
+
+        assert_eq!(response.body(), None);
+        let mut body = String::new();
+        let stream = response.body_stream().unwrap();
+        let mut buf = [0; 3];
+        let count = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..count], b"Hel");
+        body.push_str("Hel");
+        stream.read_to_string(&mut body).await.unwrap();
+        assert_eq!(body, "Hello World!");
+    }
+
+    #[async_std::test]
+    async fn test_response_body_stream_chunks() {
+        use async_std::prelude::*;
+        use futures::channel::mpsc::channel;
+        use futures::SinkExt;
+
+        let (mut sender, receiver) = channel(4);
+        let response = ResponseBuilder::new()
+            .set_status_line("HTTP/1.1 200 Ok")
+            .build()
+            .unwrap()
+            .set_body_stream(BodyStream::new(receiver));
+        sender.send(Ok(b"Hello".to_vec())).await.unwrap();
+        sender
+            .send(Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Read Timeout",
+            )))
+            .await
+            .unwrap();
+        drop(sender);
+
+        let mut stream = response.into_body_stream().unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), b"Hello".to_vec());
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(stream.next().await.is_none());
+    }
+
     #[test]
     fn test_build_response_ok() {
         let response = ResponseBuilder::new()
@@ -245,9 +508,34 @@ mod tests {
         assert_eq!(response.http_version(), "HTTP/1.1");
         assert_eq!(response.status_code(), 200);
         assert_eq!(response.status_line(), "200 Ok");
-        assert_eq!(response.headers(), &["Content-Type: application/json"]);
+        assert_eq!(
+            response.headers().lines(),
+            &["Content-Type: application/json"]
+        );
         let body: &[u8] = &[123, 125];
         assert_eq!(response.body(), Some(body));
         assert_eq!(response.body_as_string().unwrap(), "{}");
     }
+
+    #[test]
+    fn test_alternatives() {
+        let response = ResponseBuilder::new()
+            .set_status_line("HTTP/1.1 300 Multiple Choices")
+            .add_header("Location: /doc.en.html")
+            .add_header("Link: </doc.fr.html>; rel=\"alternate\"; hreflang=fr")
+            .add_header("Link: </style.css>; rel=stylesheet, </doc.pdf>; rel=alternate")
+            .build()
+            .unwrap();
+        assert_eq!(
+            response.alternatives(),
+            vec!["/doc.en.html", "/doc.fr.html", "/doc.pdf"]
+        );
+
+        let response = ResponseBuilder::new()
+            .set_status_line("HTTP/1.1 200 Ok")
+            .add_header("Link: </doc.fr.html>; rel=alternate")
+            .build()
+            .unwrap();
+        assert!(response.alternatives().is_empty());
+    }
 }
@@ -0,0 +1,399 @@
+//! Retry the requests that failed on a transient error.
+//!
+//! Retries are disabled by default, a `RetryPolicy` is set on the client
+//! to enable them:
+//!
+//! ```
+//! use cabot::retry::RetryPolicy;
+//! use cabot::Client;
+//!
+//! let mut policy = RetryPolicy::new(3);
+//! policy.set_base_delay_ms(200);
+//! policy.set_retry_statuses(&[429, 503]);
+//! let mut client = Client::new();
+//! client.set_retry_policy(policy);
+//! ```
+//!
+//! A request is retried after a connection error, a timeout, or a response
+//! with a retryable status code, as long as nothing of the response has been
+//! written. The delay between attempts grows exponentially, with a random
+//! jitter, unless the response has a `Retry-After` header.
+//!
+//! Only the idempotent methods are retried by default, and a request with a
+//! streamed body is never retried since its body can't be sent twice.
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime};
+
+use async_std::io::ErrorKind;
+
+use super::constants;
+use super::cookies;
+use super::request::Request;
+use super::results::CabotError;
+
+/// Status codes retried by default.
+const RETRY_STATUSES: [u16; 6] = [408, 429, 500, 502, 503, 504];
+
+/// Methods that can be sent many times with the same effect, see
+/// [rfc7231](https://tools.ietf.org/html/rfc7231#section-4.2.2).
+const IDEMPOTENT_METHODS: [&str; 6] = ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"];
+
+/// Decide which failed requests are retried, and when.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    base_delay: u64,
+    max_delay: u64,
+    max_time: u64,
+    jitter: bool,
+    statuses: Vec<u16>,
+    retry_timeouts: bool,
+    retry_connection_errors: bool,
+    all_methods: bool,
+}
+
+impl RetryPolicy {
+    /// Construct a policy retrying a request at most `max_retries` times,
+    /// `0` disable the retries.
+    pub fn new(max_retries: u32) -> Self {
+        RetryPolicy {
+            max_retries,
+            base_delay: constants::RETRY_BASE_DELAY * 1000,
+            max_delay: constants::RETRY_MAX_DELAY * 1000,
+            max_time: 0,
+            jitter: true,
+            statuses: RETRY_STATUSES.to_vec(),
+            retry_timeouts: true,
+            retry_connection_errors: true,
+            all_methods: false,
+        }
+    }
+
+    /// The max number of retries of a request.
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// Set the max number of retries of a request.
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    /// Set the delay in milliseconds before the first retry, it is doubled
+    /// after every retry.
+    pub fn set_base_delay_ms(&mut self, delay: u64) {
+        self.base_delay = delay;
+    }
+
+    /// Set the max delay in milliseconds between two attempts,
+    /// `Retry-After` headers asking for a longer delay are capped.
+    pub fn set_max_delay_ms(&mut self, delay: u64) {
+        self.max_delay = delay;
+    }
+
+    /// Set the time in milliseconds after the first attempt when no more
+    /// retries are started, `0` means no limit.
+    pub fn set_max_time_ms(&mut self, max_time: u64) {
+        self.max_time = max_time;
+    }
+
+    /// Randomize the delays, between the half and the full delay, to spread
+    /// the retries of many clients. Enabled by default.
+    pub fn set_jitter(&mut self, jitter: bool) {
+        self.jitter = jitter;
+    }
+
+    /// Set the status codes of the responses to retry,
+    /// 408, 429, 500, 502, 503 and 504 by default.
+    pub fn set_retry_statuses(&mut self, statuses: &[u16]) {
+        self.statuses = statuses.to_vec();
+    }
+
+    /// The status codes of the responses to retry.
+    pub fn retry_statuses(&self) -> &[u16] {
+        &self.statuses
+    }
+
+    /// Retry the requests that timed out. Enabled by default.
+    pub fn set_retry_timeouts(&mut self, enabled: bool) {
+        self.retry_timeouts = enabled;
+    }
+
+    /// Retry the requests on refused, reset or closed connections.
+    /// Enabled by default.
+    pub fn set_retry_connection_errors(&mut self, enabled: bool) {
+        self.retry_connection_errors = enabled;
+    }
+
+    /// Retry every methods, not only the idempotent ones.
+    pub fn set_retry_all_methods(&mut self, enabled: bool) {
+        self.all_methods = enabled;
+    }
+
+    /// True if the request may be retried, whatever the failure.
+    pub fn can_retry(&self, request: &Request) -> bool {
+        self.max_retries > 0
+            && !request.has_body_reader()
+            && (self.all_methods || IDEMPOTENT_METHODS.contains(&request.http_method()))
+    }
+
+    /// True if a response with the `status` code is retried.
+    pub fn is_retryable_status(&self, status: u16) -> bool {
+        self.statuses.contains(&status)
+    }
+
+    /// True if the error is a transient error that is retried.
+    pub fn is_retryable_error(&self, err: &CabotError) -> bool {
+        match err {
+            CabotError::DNSLookupError(_, _) => false,
+            err if err.is_timeout() => self.retry_timeouts,
+            CabotError::ConnectError(_, _) => self.retry_connection_errors,
+            CabotError::IOError(err)
+            | CabotError::SendError(_, err)
+            | CabotError::ReadError(_, err) => {
+                self.retry_connection_errors
+                    && matches!(
+                        err.kind(),
+                        ErrorKind::ConnectionRefused
+                            | ErrorKind::ConnectionReset
+                            | ErrorKind::ConnectionAborted
+                            | ErrorKind::BrokenPipe
+                            | ErrorKind::UnexpectedEof
+                    )
+            }
+            _ => false,
+        }
+    }
+
+    /// The delay before the retry following the `retries` already done,
+    /// the `retry_after` delay asked by the server is used if any.
+    pub fn delay(&self, retries: u32, retry_after: Option<Duration>) -> Duration {
+        let max_delay = Duration::from_millis(self.max_delay);
+        if let Some(retry_after) = retry_after {
+            return cmp::min(retry_after, max_delay);
+        }
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << cmp::min(retries, 32))
+            .min(self.max_delay);
+        let delay = if self.jitter && delay > 1 {
+            delay / 2 + random() % (delay / 2 + 1)
+        } else {
+            delay
+        };
+        Duration::from_millis(delay)
+    }
+
+    /// The time left to start retries, after `elapsed` since the first attempt.
+    pub(crate) fn remaining_time(&self, elapsed: Duration) -> Option<Duration> {
+        if self.max_time == 0 {
+            return None;
+        }
+        Some(Duration::from_millis(self.max_time).saturating_sub(elapsed))
+    }
+}
+
+impl Default for RetryPolicy {
+    /// A policy that never retries.
+    fn default() -> Self {
+        RetryPolicy::new(0)
+    }
+}
+
+/// Parse the value of a `Retry-After` header, a number of seconds or
+/// an http date.
+pub(crate) fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if !value.is_empty() && value.bytes().all(|c| c.is_ascii_digit()) {
+        return value.parse::<u64>().ok().map(Duration::from_secs);
+    }
+    cookies::parse_cookie_date(value).map(|date| {
+        date.duration_since(now)
+            .unwrap_or_else(|_| Duration::from_secs(0))
+    })
+}
+
+/// A random number, the hash keys of a new `RandomState` are random.
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    use std::net::SocketAddr;
+
+    use super::super::client::query_tests::{client, serve};
+    use super::super::client::Client;
+    use super::super::test_server::Route;
+
+    use async_std::io;
+
+    use super::super::errors::{ErrorContext, Phase};
+    use super::super::request::RequestBuilder;
+
+    #[test]
+    fn test_can_retry() {
+        let policy = RetryPolicy::new(2);
+        let get = RequestBuilder::new("http://localhost/").build().unwrap();
+        let post = RequestBuilder::new("http://localhost/")
+            .set_http_method("POST")
+            .build()
+            .unwrap();
+        assert!(policy.can_retry(&get));
+        assert!(!policy.can_retry(&post));
+        assert!(!RetryPolicy::default().can_retry(&get));
+
+        let mut policy = policy;
+        policy.set_retry_all_methods(true);
+        assert!(policy.can_retry(&post));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let mut policy = RetryPolicy::new(2);
+        assert!(policy.is_retryable_status(503));
+        assert!(!policy.is_retryable_status(404));
+        policy.set_retry_statuses(&[404]);
+        assert!(policy.is_retryable_status(404));
+        assert!(!policy.is_retryable_status(503));
+
+        let timeout =
+            CabotError::ReadTimeout(ErrorContext::new("http://localhost/", Phase::ReadHeaders));
+        let context = ErrorContext::new("http://localhost/", Phase::ReadHeaders);
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        let reset = CabotError::ReadError(context, reset);
+        let context = ErrorContext::new("http://localhost/", Phase::SendRequest);
+        let broken = io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe");
+        let send = CabotError::SendError(context, broken);
+        let context = ErrorContext::new("http://localhost/", Phase::Resolve);
+        let unknown = io::Error::new(io::ErrorKind::NotFound, "unknown host");
+        let dns = CabotError::DNSLookupError(context, unknown);
+        assert!(policy.is_retryable_error(&timeout));
+        assert!(policy.is_retryable_error(&reset));
+        assert!(policy.is_retryable_error(&send));
+        assert!(!policy.is_retryable_error(&dns));
+        policy.set_retry_timeouts(false);
+        policy.set_retry_connection_errors(false);
+        assert!(!policy.is_retryable_error(&timeout));
+        assert!(!policy.is_retryable_error(&reset));
+        assert!(!policy.is_retryable_error(&send));
+    }
+
+    #[test]
+    fn test_delay() {
+        let mut policy = RetryPolicy::new(5);
+        policy.set_base_delay_ms(100);
+        policy.set_max_delay_ms(1000);
+        policy.set_jitter(false);
+        assert_eq!(policy.delay(0, None), Duration::from_millis(100));
+        assert_eq!(policy.delay(2, None), Duration::from_millis(400));
+        assert_eq!(policy.delay(4, None), Duration::from_millis(1000));
+        assert_eq!(policy.delay(40, None), Duration::from_millis(1000));
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(5))),
+            Duration::from_millis(1000)
+        );
+
+        policy.set_jitter(true);
+        for _ in 0..20 {
+            let delay = policy.delay(1, None);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(
+            parse_retry_after(" 120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:50:07 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:07 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-1", now), None);
+    }
+
+    pub(crate) fn retry_client(addr: &SocketAddr, max_retries: u32) -> Client {
+        let mut policy = RetryPolicy::new(max_retries);
+        policy.set_base_delay_ms(10);
+        let mut client = client(addr);
+        client.set_retry_policy(policy);
+        client
+    }
+
+    #[async_std::test]
+    async fn test_retry_status() {
+        let server = serve(vec![
+            Route::raw("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 4\r\n\r\nBusy"),
+            Route::raw(
+                "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\nContent-Length: 0\r\n\r\n",
+            ),
+            Route::raw("HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello"),
+        ])
+        .await;
+        let client = retry_client(&server.addr(), 3);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert_eq!(server.connections(), 1);
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[async_std::test]
+    async fn test_retry_exhausted() {
+        let server = serve(vec![Route::raw(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 4\r\n\r\nBusy",
+        )])
+        .await;
+        let client = retry_client(&server.addr(), 1);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 503);
+        assert_eq!(response.body_as_string().unwrap(), "Busy");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[async_std::test]
+    async fn test_retry_connection_closed() {
+        let server = serve(vec![
+            Route::raw_and_close(""),
+            Route::raw_and_close("HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello"),
+        ])
+        .await;
+        let client = retry_client(&server.addr(), 1);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert_eq!(server.connections(), 2);
+    }
+
+    #[async_std::test]
+    async fn test_retry_not_idempotent() {
+        let server = serve(vec![Route::raw(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 4\r\n\r\nBusy",
+        )])
+        .await;
+        let client = retry_client(&server.addr(), 3);
+        let request = RequestBuilder::new("http://localhost/")
+            .set_http_method("POST")
+            .set_body_as_str("data")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 503);
+        assert_eq!(server.requests().len(), 1);
+    }
+}
@@ -0,0 +1,404 @@
+//! SOCKS5 client, see RFC 1928 and RFC 1929.
+
+use async_std::io::{ErrorKind, Read, Write};
+use async_std::net::{IpAddr, SocketAddr};
+use async_std::prelude::*;
+
+use super::errors::ErrorContext;
+use super::results::{CabotError, CabotResult};
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_PASSWORD: u8 = 0x02;
+const PASSWORD_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// The destination of a SOCKS5 connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target<'a> {
+    /// An address resolved by the client.
+    Addr(SocketAddr),
+    /// A host name and a port, resolved by the proxy.
+    Domain(&'a str, u16),
+}
+
+impl<'a> Target<'a> {
+    /// Encode the `ATYP`, `DST.ADDR` and `DST.PORT` fields.
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), String> {
+        let port = match self {
+            Target::Addr(SocketAddr::V4(addr)) => {
+                buf.push(ATYP_IPV4);
+                buf.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            Target::Addr(SocketAddr::V6(addr)) => {
+                buf.push(ATYP_IPV6);
+                buf.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            Target::Domain(host, port) => {
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                if let Ok(ip) = host.parse::<IpAddr>() {
+                    return Target::Addr(SocketAddr::new(ip, *port)).encode(buf);
+                }
+                if host.is_empty() || host.len() > 255 {
+                    return Err(format!("Invalid SOCKS5 host name {}", host));
+                }
+                buf.push(ATYP_DOMAIN);
+                buf.push(host.len() as u8);
+                buf.extend_from_slice(host.as_bytes());
+                *port
+            }
+        };
+        buf.extend_from_slice(&port.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// Read exactly `buf.len()` bytes, or fail if the proxy closed the connection.
+async fn read_exact<S>(stream: &mut S, buf: &mut [u8], context: &ErrorContext) -> CabotResult<()>
+where
+    S: Read + Unpin,
+{
+    stream
+        .read_exact(buf)
+        .await
+        .map_err(|err| match err.kind() {
+            ErrorKind::UnexpectedEof => {
+                CabotError::ProxyError(context.clone(), "Connection closed by the proxy".to_owned())
+            }
+            _ => CabotError::IOError(err),
+        })
+}
+
+/// Ask the SOCKS5 proxy on the other side of the `stream` to connect to
+/// the `target`.
+///
+/// If `credentials` are given, the username/password authentication is
+/// proposed to the proxy. The failures are reported with the `context` of
+/// the request.
+pub async fn handshake<S>(
+    stream: &mut S,
+    target: &Target<'_>,
+    credentials: Option<(&str, &str)>,
+    context: &ErrorContext,
+) -> CabotResult<()>
+where
+    S: Read + Write + Unpin,
+{
+    negotiate(stream, credentials, context).await?;
+    connect(stream, target, context).await
+}
+
+/// Choose the authentication method with the SOCKS5 proxy on the other
+/// side of the `stream`, and authenticate using the `credentials` if the
+/// proxy requires them.
+pub async fn negotiate<S>(
+    stream: &mut S,
+    credentials: Option<(&str, &str)>,
+    context: &ErrorContext,
+) -> CabotResult<()>
+where
+    S: Read + Write + Unpin,
+{
+    let proxy_error = |message: String| CabotError::ProxyError(context.clone(), message);
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[SOCKS_VERSION, 2, AUTH_NONE, AUTH_PASSWORD],
+        None => &[SOCKS_VERSION, 1, AUTH_NONE],
+    };
+    stream.write_all(greeting).await?;
+    let mut choice = [0; 2];
+    read_exact(stream, &mut choice, context).await?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(proxy_error("The proxy is not a SOCKS5 proxy".to_owned()));
+    }
+    match (choice[1], credentials) {
+        (AUTH_NONE, _) => {}
+        (AUTH_PASSWORD, Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(proxy_error("SOCKS5 credentials are too long".to_owned()));
+            }
+            let mut auth = vec![PASSWORD_VERSION, username.len() as u8];
+            auth.extend_from_slice(username.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+            let mut status = [0; 2];
+            read_exact(stream, &mut status, context).await?;
+            if status[1] != 0 {
+                return Err(proxy_error("SOCKS5 authentication failed".to_owned()));
+            }
+        }
+        _ => {
+            return Err(proxy_error(
+                "No acceptable SOCKS5 authentication method".to_owned(),
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Ask the SOCKS5 proxy, once the authentication is negotiated, to connect
+/// to the `target`. The proxy closes the connection if it fails.
+pub async fn connect<S>(
+    stream: &mut S,
+    target: &Target<'_>,
+    context: &ErrorContext,
+) -> CabotResult<()>
+where
+    S: Read + Write + Unpin,
+{
+    let proxy_error = |message: String| CabotError::ProxyError(context.clone(), message);
+    let mut connect = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+    target.encode(&mut connect).map_err(proxy_error)?;
+    stream.write_all(&connect).await?;
+
+    let mut reply = [0; 4];
+    read_exact(stream, &mut reply, context).await?;
+    if reply[1] != 0 {
+        return Err(proxy_error(format!(
+            "SOCKS5 connect failed, {}",
+            reply_message(reply[1])
+        )));
+    }
+    // The bound address is not used, but must be consumed.
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            read_exact(stream, &mut len, context).await?;
+            len[0] as usize
+        }
+        atyp => return Err(proxy_error(format!("Invalid SOCKS5 address type {}", atyp))),
+    };
+    let mut bound = vec![0; addr_len + 2];
+    read_exact(stream, &mut bound, context).await?;
+    Ok(())
+}
+
+/// The meaning of a SOCKS5 `REP` field.
+fn reply_message(reply: u8) -> &'static str {
+    match reply {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::io;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use super::super::asynctls::tests::{serve_tls, TLS_RESPONSE};
+    use super::super::client::query_tests::serve;
+    use super::super::client::Client;
+    use super::super::dns::tests::RecordingResolver;
+    use super::super::proxy::Proxy;
+    use super::super::request::RequestBuilder;
+    use super::super::test_server::Route;
+
+    #[test]
+    fn test_target_encode() {
+        let mut buf = Vec::new();
+        let addr = "127.0.0.1:8080".parse().unwrap();
+        Target::Addr(addr).encode(&mut buf).unwrap();
+        assert_eq!(buf, vec![1, 127, 0, 0, 1, 0x1f, 0x90]);
+
+        let mut buf = Vec::new();
+        Target::Domain("localhost", 80).encode(&mut buf).unwrap();
+        assert_eq!(buf, b"\x03\x09localhost\x00\x50".to_vec());
+
+        let mut buf = Vec::new();
+        Target::Domain("[::1]", 443).encode(&mut buf).unwrap();
+        let mut expected = vec![4];
+        expected.extend_from_slice(&[0; 15]);
+        expected.extend_from_slice(&[1, 0x01, 0xbb]);
+        assert_eq!(buf, expected);
+
+        let long = "a".repeat(256);
+        assert!(Target::Domain(&long, 80).encode(&mut Vec::new()).is_err());
+    }
+
+    /// A SOCKS5 proxy that connects to `target` whatever the requested
+    /// destination is, except the `refused` destinations, and returns the
+    /// requested destinations.
+    async fn serve_socks5_proxy(
+        target: SocketAddr,
+        credentials: Option<(&'static str, &'static str)>,
+        refused: &'static [&'static str],
+    ) -> (SocketAddr, task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = task::spawn(async move {
+            let mut destinations = Vec::new();
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 2];
+                stream.read_exact(&mut buf).await.unwrap();
+                let mut methods = vec![0; buf[1] as usize];
+                stream.read_exact(&mut methods).await.unwrap();
+                if let Some((username, password)) = credentials {
+                    stream.write_all(&[5, 2]).await.unwrap();
+                    stream.read_exact(&mut buf).await.unwrap();
+                    let mut user = vec![0; buf[1] as usize];
+                    stream.read_exact(&mut user).await.unwrap();
+                    stream.read_exact(&mut buf[..1]).await.unwrap();
+                    let mut pass = vec![0; buf[0] as usize];
+                    stream.read_exact(&mut pass).await.unwrap();
+                    if user != username.as_bytes() || pass != password.as_bytes() {
+                        stream.write_all(&[1, 1]).await.unwrap();
+                        return "denied".to_owned();
+                    }
+                    stream.write_all(&[1, 0]).await.unwrap();
+                } else {
+                    assert!(methods.contains(&0));
+                    stream.write_all(&[5, 0]).await.unwrap();
+                }
+                let mut head = [0; 4];
+                stream.read_exact(&mut head).await.unwrap();
+                let host = match head[3] {
+                    1 => {
+                        let mut ip = [0; 4];
+                        stream.read_exact(&mut ip).await.unwrap();
+                        std::net::Ipv4Addr::from(ip).to_string()
+                    }
+                    3 => {
+                        let mut len = [0; 1];
+                        stream.read_exact(&mut len).await.unwrap();
+                        let mut name = vec![0; len[0] as usize];
+                        stream.read_exact(&mut name).await.unwrap();
+                        String::from_utf8(name).unwrap()
+                    }
+                    atyp => panic!("Unexpected address type {}", atyp),
+                };
+                let mut port = [0; 2];
+                stream.read_exact(&mut port).await.unwrap();
+                let destination = format!("{}:{}", host, u16::from_be_bytes(port));
+                destinations.push(destination.clone());
+                if refused.contains(&destination.as_str()) {
+                    // Host unreachable, the connection is closed.
+                    stream
+                        .write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0])
+                        .await
+                        .unwrap();
+                    continue;
+                }
+                stream
+                    .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+                    .await
+                    .unwrap();
+                let mut server = TcpStream::connect(target).await.unwrap();
+                let (mut client_reader, mut server_writer) = (stream.clone(), server.clone());
+                task::spawn(async move {
+                    let upload = io::copy(&mut client_reader, &mut server_writer);
+                    let download = io::copy(&mut server, &mut stream);
+                    let _ = futures::future::join(upload, download).await;
+                });
+                return destinations.join(", ");
+            }
+        });
+        (addr, handle)
+    }
+
+    #[async_std::test]
+    async fn test_proxy_socks5h() {
+        let (tls_addr, tls_server) = serve_tls(TLS_RESPONSE, false, 1);
+        let (addr, proxy) = serve_socks5_proxy(tls_addr, Some(("user", "pass")), &[]).await;
+        let mut client = Client::new();
+        client
+            .add_root_certificates_pem(include_bytes!("../tests/certs/ca.pem"))
+            .unwrap();
+        client.set_proxy(Proxy::new(&format!("socks5h://user:pass@{}", addr)).unwrap());
+        let request = RequestBuilder::new("https://localhost/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert_eq!(proxy.await, "localhost:443");
+        tls_server.join().unwrap().unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_proxy_socks5() {
+        let server = serve(vec![Route::raw(
+            "HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello",
+        )])
+        .await;
+        let (addr, proxy) = serve_socks5_proxy(server.addr(), None, &[]).await;
+        let mut client = Client::new();
+        client.add_authority("example.org:80", &"127.0.0.2:8080".parse().unwrap());
+        client.set_proxy(Proxy::new(&format!("socks5://{}", addr)).unwrap());
+        let request = RequestBuilder::new("http://example.org/path")
+            .set_user_agent("cabot")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        // The host name is resolved by the client
+        assert_eq!(proxy.await, "127.0.0.2:8080");
+        assert_eq!(
+            server.requests(),
+            vec![
+                "GET /path HTTP/1.1\r\nUser-Agent: cabot\r\nHost: example.org\r\n\
+                 Connection: keep-alive\r\n\r\n"
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_proxy_socks5_refused_address() {
+        let server = serve(vec![Route::raw(
+            "HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello",
+        )])
+        .await;
+        let (addr, proxy) = serve_socks5_proxy(server.addr(), None, &["127.0.0.3:8080"]).await;
+        let mut client = Client::new();
+        client.set_resolver(Arc::new(RecordingResolver {
+            addrs: vec![
+                "[::1]:8080".parse().unwrap(),
+                "127.0.0.3:8080".parse().unwrap(),
+                "127.0.0.2:8080".parse().unwrap(),
+            ],
+            queries: Mutex::new(Vec::new()),
+        }));
+        client.set_ip_version(true, false);
+        client.set_proxy(Proxy::new(&format!("socks5://{}", addr)).unwrap());
+        let request = RequestBuilder::new("http://service.test:8080/")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        // The IPv6 address is skipped, and the refused address is replaced
+        // by the next one on a new connection to the proxy.
+        assert_eq!(proxy.await, "127.0.0.3:8080, 127.0.0.2:8080");
+    }
+
+    #[async_std::test]
+    async fn test_proxy_socks5_denied() {
+        let (addr, proxy) =
+            serve_socks5_proxy("127.0.0.1:1".parse().unwrap(), Some(("user", "pass")), &[]).await;
+        let mut client = Client::new();
+        client.set_proxy(Proxy::new(&format!("socks5h://user:secret@{}", addr)).unwrap());
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let err = client.execute(&request).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Proxy Error while connecting to http://localhost/: SOCKS5 authentication failed"
+        );
+        assert_eq!(proxy.await, "denied");
+    }
+}
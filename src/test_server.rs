@@ -0,0 +1,754 @@
+//! A local HTTP/1.1 server to test the client, enabled by the `test-server`
+//! feature.
+//!
+//! The server listens on `127.0.0.1`, on a random port, and responds to the
+//! requests using the route registered for their path. The connections
+//! accepted and the requests received are recorded.
+//!
+//! # Example
+//!
+//! ```
+//! use cabot::test_server::{Route, TestServerBuilder};
+//! use cabot::{Client, RequestBuilder};
+//!
+//! # async_std::task::block_on(async {
+//! let server = TestServerBuilder::new()
+//!     .add_route("/hello", Route::text("Hello"))
+//!     .add_route("/old", Route::redirect(301, "/hello"))
+//!     .start()
+//!     .await
+//!     .unwrap();
+//!
+//! let request = RequestBuilder::new(&server.url("/old")).build().unwrap();
+//! let response = Client::new().execute(&request).await.unwrap();
+//! assert_eq!(response.body_as_string().unwrap(), "Hello");
+//! assert_eq!(server.requests().len(), 2);
+//! # });
+//! ```
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read as SyncRead, Write as SyncWrite};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_std::io::{Read, Result as IoResult, Write};
+use async_std::net::{SocketAddr, TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task::{self, Context, Poll};
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use rustls::internal::pemfile;
+use rustls::{NoClientAuth, ServerConfig, ServerSession, Session};
+
+use super::results::{CabotError, CabotResult};
+
+/// Certificate of the authority signing the certificate of
+/// `TestServerBuilder::set_localhost_tls`, in the PEM format.
+pub const LOCALHOST_CA: &[u8] = include_bytes!("../tests/certs/ca.pem");
+
+const LOCALHOST_CERT: &[u8] = include_bytes!("../tests/certs/localhost.pem");
+const LOCALHOST_KEY: &[u8] = include_bytes!("../tests/certs/localhost.key");
+
+/// How the server responds to the requests of a path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+    /// A response with a `Content-Length`, the headers are `Name: value` lines.
+    Fixed {
+        status: u16,
+        headers: Vec<String>,
+        body: Vec<u8>,
+    },
+    /// A `Transfer-Encoding: chunked` response, sending one chunk per item.
+    Chunked {
+        headers: Vec<String>,
+        chunks: Vec<Vec<u8>>,
+    },
+    /// A response without length, the connection is closed after the body.
+    UntilClose { headers: Vec<String>, body: Vec<u8> },
+    /// A response with a `Content-Length`, the body is sent in pieces of
+    /// `chunk_size` bytes, waiting `interval` before every piece.
+    Drip {
+        body: Vec<u8>,
+        chunk_size: usize,
+        interval: Duration,
+    },
+    /// Wait `delay` before responding using the `route`.
+    Delayed { delay: Duration, route: Box<Route> },
+    /// Redirect to the `location`, a path is redirected on the server.
+    Redirect { status: u16, location: String },
+    /// Redirect `path?n` to `path?n-1`, and then `path?0` to the `location`.
+    CountDown { location: String },
+    /// Respond with the request received as the body.
+    Echo,
+    /// Write the `response` as is, the connection is closed after it if
+    /// `close` is set.
+    Raw { response: Vec<u8>, close: bool },
+    /// Respond to the n-th request of the path using the n-th route, the
+    /// last route is used once they have all been used.
+    Sequence(Vec<Route>),
+}
+
+impl Route {
+    /// A `200 OK` text response.
+    pub fn text(body: &str) -> Self {
+        Route::Fixed {
+            status: 200,
+            headers: vec!["Content-Type: text/plain; charset=utf-8".to_owned()],
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    /// An empty response with the `status`.
+    pub fn status(status: u16) -> Self {
+        Route::Fixed {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// A `200 OK` text response sent in the `chunks`.
+    pub fn chunked(chunks: &[&str]) -> Self {
+        Route::Chunked {
+            headers: vec!["Content-Type: text/plain; charset=utf-8".to_owned()],
+            chunks: chunks
+                .iter()
+                .map(|chunk| chunk.as_bytes().to_vec())
+                .collect(),
+        }
+    }
+
+    /// A `200 OK` response sending `chunk_size` bytes of the body every
+    /// `interval` milliseconds.
+    pub fn drip(body: &str, chunk_size: usize, interval: u64) -> Self {
+        Route::Drip {
+            body: body.as_bytes().to_vec(),
+            chunk_size,
+            interval: Duration::from_millis(interval),
+        }
+    }
+
+    /// Respond using the `route` after `delay` milliseconds.
+    pub fn delayed(delay: u64, route: Route) -> Self {
+        Route::Delayed {
+            delay: Duration::from_millis(delay),
+            route: Box::new(route),
+        }
+    }
+
+    /// Redirect to the `location` using the `status`.
+    pub fn redirect(status: u16, location: &str) -> Self {
+        Route::Redirect {
+            status,
+            location: location.to_owned(),
+        }
+    }
+
+    /// Write the `response`, status line and headers included, as is.
+    pub fn raw<R: AsRef<[u8]>>(response: R) -> Self {
+        Route::Raw {
+            response: response.as_ref().to_vec(),
+            close: false,
+        }
+    }
+
+    /// Write the `response` as is, and close the connection.
+    pub fn raw_and_close<R: AsRef<[u8]>>(response: R) -> Self {
+        Route::Raw {
+            response: response.as_ref().to_vec(),
+            close: true,
+        }
+    }
+}
+
+/// Construct a [TestServer](struct.TestServer.html).
+#[derive(Debug, Default)]
+pub struct TestServerBuilder {
+    routes: HashMap<String, Route>,
+    tls: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl TestServerBuilder {
+    /// Construct a builder of a server without routes, every request
+    /// receives a `404 Not Found` response.
+    pub fn new() -> Self {
+        TestServerBuilder {
+            routes: HashMap::new(),
+            tls: None,
+        }
+    }
+
+    /// Respond to the requests of the `path` using the `route`,
+    /// the querystring is ignored.
+    ///
+    /// The `*` path matches the requests of the paths without route,
+    /// including the absolute urls received by a proxy.
+    pub fn add_route(mut self, path: &str, route: Route) -> Self {
+        self.routes.insert(path.to_owned(), route);
+        self
+    }
+
+    /// Serve https using the certificate chain and its PKCS8 private key,
+    /// in the PEM format.
+    pub fn set_tls(mut self, cert_chain: &[u8], private_key: &[u8]) -> Self {
+        self.tls = Some((cert_chain.to_vec(), private_key.to_vec()));
+        self
+    }
+
+    /// Serve https using a certificate for `localhost`, signed by the
+    /// `LOCALHOST_CA` authority.
+    pub fn set_localhost_tls(self) -> Self {
+        self.set_tls(LOCALHOST_CERT, LOCALHOST_KEY)
+    }
+
+    /// Start the server, it is stopped when the `TestServer` is dropped.
+    pub async fn start(self) -> CabotResult<TestServer> {
+        let tls_config = match self.tls {
+            Some((cert_chain, private_key)) => Some(Arc::new(server_config(
+                cert_chain.as_slice(),
+                private_key.as_slice(),
+            )?)),
+            None => None,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let base_url = match tls_config {
+            Some(_) => format!("https://localhost:{}", addr.port()),
+            None => format!("http://{}", addr),
+        };
+        let shared = Arc::new(Shared {
+            routes: self.routes,
+            base_url,
+            connections: AtomicUsize::new(0),
+            requests: Mutex::new(Vec::new()),
+            served: Mutex::new(HashMap::new()),
+        });
+        let (stop, mut stopped) = oneshot::channel::<()>();
+        let server = shared.clone();
+        task::spawn(async move {
+            loop {
+                let stream = match future::select(Box::pin(listener.accept()), &mut stopped).await {
+                    Either::Left((Ok((stream, _)), _)) => stream,
+                    Either::Left((Err(err), _)) => {
+                        warn!("Test server failed to accept a connection: {}", err);
+                        continue;
+                    }
+                    Either::Right(_) => break,
+                };
+                server.connections.fetch_add(1, Ordering::SeqCst);
+                let server = server.clone();
+                match tls_config.as_ref() {
+                    Some(config) => {
+                        let stream = TlsServerStream::new(stream, ServerSession::new(config));
+                        task::spawn(serve_connection(stream, server));
+                    }
+                    None => {
+                        task::spawn(serve_connection(stream, server));
+                    }
+                }
+            }
+        });
+        Ok(TestServer {
+            addr,
+            shared,
+            stop: Some(stop),
+        })
+    }
+}
+
+/// A running local server.
+pub struct TestServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl TestServer {
+    /// The address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The absolute url of the `path` on the server.
+    ///
+    /// The host of https servers is `localhost`, to match their certificate.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.shared.base_url, path)
+    }
+
+    /// The number of connections accepted.
+    pub fn connections(&self) -> usize {
+        self.shared.connections.load(Ordering::SeqCst)
+    }
+
+    /// The raw requests received, in order.
+    pub fn requests(&self) -> Vec<String> {
+        self.shared.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// The state shared by the connections of a server.
+struct Shared {
+    routes: HashMap<String, Route>,
+    base_url: String,
+    connections: AtomicUsize,
+    requests: Mutex<Vec<String>>,
+    /// The number of requests served by the sequences, by path.
+    served: Mutex<HashMap<String, usize>>,
+}
+
+impl Shared {
+    /// The index of the next request served by the sequence of the path.
+    fn next_served(&self, path: &str) -> usize {
+        let mut served = self.served.lock().unwrap();
+        let count = served.entry(path.to_owned()).or_insert(0);
+        *count += 1;
+        *count - 1
+    }
+}
+
+fn server_config(cert_chain: &[u8], private_key: &[u8]) -> CabotResult<ServerConfig> {
+    let certs = pemfile::certs(&mut &cert_chain[..])
+        .map_err(|_| CabotError::CertificateParseError("Invalid certificate".to_owned()))?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut &private_key[..])
+        .map_err(|_| CabotError::CertificateParseError("Invalid private key".to_owned()))?;
+    if keys.is_empty() {
+        return Err(CabotError::CertificateParseError(
+            "No PKCS8 private key found in PEM".to_owned(),
+        ));
+    }
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, keys.remove(0))
+        .map_err(|err| CabotError::CertificateParseError(err.to_string()))?;
+    Ok(config)
+}
+
+/// Respond to the requests of a connection until it is closed.
+async fn serve_connection<S>(mut stream: S, server: Arc<Shared>)
+where
+    S: Read + Write + Unpin,
+{
+    while let Some(request) = read_request(&mut stream).await {
+        let request = String::from_utf8_lossy(&request).into_owned();
+        server.requests.lock().unwrap().push(request.clone());
+        match respond(&mut stream, &server, &request).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                debug!("Test server failed to respond: {}", err);
+                break;
+            }
+        }
+    }
+    let _ = future::poll_fn(|cx| Pin::new(&mut stream).poll_close(cx)).await;
+}
+
+/// Read a request, the headers and the body, return None on EOF.
+async fn read_request<S>(stream: &mut S) -> Option<Vec<u8>>
+where
+    S: Read + Unpin,
+{
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&request[..pos]).to_lowercase();
+            if head.contains("\r\ntransfer-encoding: chunked") {
+                if request[pos + 4..].ends_with(b"0\r\n\r\n") {
+                    return Some(request);
+                }
+            } else {
+                let length = head
+                    .lines()
+                    .find(|hdr| hdr.starts_with("content-length:"))
+                    .and_then(|hdr| hdr[15..].trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if request.len() >= pos + 4 + length {
+                    return Some(request);
+                }
+            }
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(count) => request.extend_from_slice(&buf[..count]),
+        }
+    }
+}
+
+/// Write the response to the request, return true if the connection
+/// can be reused.
+async fn respond<S>(stream: &mut S, server: &Shared, request: &str) -> IoResult<bool>
+where
+    S: Write + Unpin,
+{
+    let target = request.split_whitespace().nth(1).unwrap_or("/");
+    let mut parts = target.splitn(2, '?');
+    let path = parts.next().unwrap_or("/");
+    let query = parts.next().unwrap_or("");
+    let close = request
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .any(|line| line.eq_ignore_ascii_case("connection: close"));
+
+    let not_found = Route::Fixed {
+        status: 404,
+        headers: Vec::new(),
+        body: b"Not Found".to_vec(),
+    };
+    let (key, mut route) = match server
+        .routes
+        .get_key_value(path)
+        .or_else(|| server.routes.get_key_value("*"))
+    {
+        Some((key, route)) => (key.as_str(), route.clone()),
+        None => ("", not_found.clone()),
+    };
+    let route = loop {
+        route = match route {
+            Route::Delayed { delay, route: next } => {
+                task::sleep(delay).await;
+                *next
+            }
+            Route::Sequence(mut routes) => {
+                let index = server.next_served(key);
+                if routes.is_empty() {
+                    not_found.clone()
+                } else {
+                    routes.swap_remove(cmp::min(index, routes.len() - 1))
+                }
+            }
+            route => break route,
+        }
+    };
+    let location = |location: &str| {
+        if location.starts_with('/') {
+            format!("{}{}", server.base_url, location)
+        } else {
+            location.to_owned()
+        }
+    };
+    let (status, headers, body) = match route {
+        Route::Fixed {
+            status,
+            headers,
+            body,
+        } => (status, headers, body),
+        Route::Redirect {
+            status,
+            location: to,
+        } => (
+            status,
+            vec![format!("Location: {}", location(&to))],
+            Vec::new(),
+        ),
+        Route::CountDown { location: to } => {
+            let to = match query.parse::<u32>() {
+                Ok(count) if count > 0 => format!("{}?{}", path, count - 1),
+                _ => to,
+            };
+            (
+                302,
+                vec![format!("Location: {}", location(&to))],
+                Vec::new(),
+            )
+        }
+        Route::Echo => (
+            200,
+            vec!["Content-Type: text/plain".to_owned()],
+            request.as_bytes().to_vec(),
+        ),
+        Route::Chunked { headers, chunks } => {
+            let mut head = response_head(200, &headers, close);
+            head.push_str("Transfer-Encoding: chunked\r\n\r\n");
+            stream.write_all(head.as_bytes()).await?;
+            for chunk in chunks.iter().filter(|chunk| !chunk.is_empty()) {
+                let mut data = format!("{:x}\r\n", chunk.len()).into_bytes();
+                data.extend_from_slice(chunk);
+                data.extend_from_slice(b"\r\n");
+                stream.write_all(&data).await?;
+                stream.flush().await?;
+            }
+            stream.write_all(b"0\r\n\r\n").await?;
+            stream.flush().await?;
+            return Ok(!close);
+        }
+        Route::UntilClose { headers, body } => {
+            let mut head = response_head(200, &headers, true);
+            head.push_str("\r\n");
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(&body).await?;
+            stream.flush().await?;
+            return Ok(false);
+        }
+        Route::Drip {
+            body,
+            chunk_size,
+            interval,
+        } => {
+            let mut head = response_head(200, &[], close);
+            head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+            stream.write_all(head.as_bytes()).await?;
+            stream.flush().await?;
+            for piece in body.chunks(cmp::max(chunk_size, 1)) {
+                task::sleep(interval).await;
+                stream.write_all(piece).await?;
+                stream.flush().await?;
+            }
+            return Ok(!close);
+        }
+        Route::Raw {
+            response,
+            close: close_after,
+        } => {
+            stream.write_all(&response).await?;
+            stream.flush().await?;
+            return Ok(!close && !close_after);
+        }
+        Route::Delayed { .. } | Route::Sequence(_) => unreachable!(),
+    };
+    let mut head = response_head(status, &headers, close);
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    let mut response = head.into_bytes();
+    response.extend_from_slice(&body);
+    stream.write_all(&response).await?;
+    stream.flush().await?;
+    Ok(!close)
+}
+
+/// The status line and the headers, without the blank line ending them.
+fn response_head(status: u16, headers: &[String], close: bool) -> String {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason_phrase(status));
+    for header in headers {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    if close {
+        head.push_str("Connection: close\r\n");
+    }
+    head
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// Server side of a TLS connection, the handshake is performed while
+/// reading the request.
+struct TlsServerStream {
+    tcpstream: TcpStream,
+    session: ServerSession,
+    /// ciphered bytes not written to the TcpStream yet.
+    tls_out: Vec<u8>,
+}
+
+impl TlsServerStream {
+    fn new(tcpstream: TcpStream, session: ServerSession) -> Self {
+        TlsServerStream {
+            tcpstream,
+            session,
+            tls_out: Vec::new(),
+        }
+    }
+
+    /// Write the pending ciphered bytes to the TcpStream.
+    fn poll_write_tls(&mut self, cx: &mut Context) -> Poll<IoResult<()>> {
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.tls_out)?;
+        }
+        while !self.tls_out.is_empty() {
+            let stream = Pin::new(&mut self.tcpstream);
+            let count = futures::ready!(stream.poll_write(cx, self.tls_out.as_slice()))?;
+            if count == 0 {
+                return Poll::Ready(Err(IoError::new(
+                    IoErrorKind::WriteZero,
+                    "Unable to write TLS bytes",
+                )));
+            }
+            self.tls_out.drain(..count);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Read for TlsServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        let self_ = Pin::get_mut(self);
+        let mut tcp_buf = [0; 2048];
+        loop {
+            // the handshake messages are sent while reading.
+            futures::ready!(self_.poll_write_tls(cx))?;
+            match self_.session.read(buf) {
+                Err(err) if err.kind() == IoErrorKind::ConnectionAborted => {
+                    return Poll::Ready(Ok(0))
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+                Ok(0) => {}
+                Ok(count) => return Poll::Ready(Ok(count)),
+            }
+            let count =
+                futures::ready!(Pin::new(&mut self_.tcpstream).poll_read(cx, &mut tcp_buf))?;
+            if count == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            self_.session.read_tls(&mut &tcp_buf[..count])?;
+            if let Err(err) = self_.session.process_new_packets() {
+                // send the alert before failing.
+                let _ = self_.poll_write_tls(cx);
+                return Poll::Ready(Err(IoError::new(
+                    IoErrorKind::InvalidData,
+                    format!("{:?}", err),
+                )));
+            }
+        }
+    }
+}
+
+impl Write for TlsServerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let self_ = Pin::get_mut(self);
+        futures::ready!(self_.poll_write_tls(cx))?;
+        self_.session.write_all(buf)?;
+        if let Poll::Ready(Err(err)) = self_.poll_write_tls(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        let self_ = Pin::get_mut(self);
+        futures::ready!(self_.poll_write_tls(cx))?;
+        Pin::new(&mut self_.tcpstream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        let self_ = Pin::get_mut(self);
+        self_.session.send_close_notify();
+        futures::ready!(self_.poll_write_tls(cx))?;
+        Pin::new(&mut self_.tcpstream).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::client::Client;
+    use super::super::request::RequestBuilder;
+
+    #[async_std::test]
+    async fn test_routes() {
+        let server = TestServerBuilder::new()
+            .add_route("/text", Route::text("It is working."))
+            .add_route("/chunked", Route::chunked(&["It is ", "working."]))
+            .add_route(
+                "/count-down",
+                Route::CountDown {
+                    location: "/text".to_owned(),
+                },
+            )
+            .add_route("/echo", Route::Echo)
+            .start()
+            .await
+            .unwrap();
+        let client = Client::new();
+        for path in &["/text", "/chunked", "/count-down?3"] {
+            let request = RequestBuilder::new(&server.url(path)).build().unwrap();
+            let response = client.execute(&request).await.unwrap();
+            assert_eq!(response.body_as_string().unwrap(), "It is working.");
+        }
+        assert_eq!(server.requests().len(), 7);
+        assert!(server.requests()[5].starts_with("GET /count-down?0 HTTP/1.1\r\n"));
+
+        let request = RequestBuilder::new(&server.url("/echo"))
+            .set_http_method("POST")
+            .set_body_as_str("{}")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        let body = response.body_as_string().unwrap();
+        assert!(body.starts_with("POST /echo HTTP/1.1\r\n"));
+        assert!(body.ends_with("\r\n\r\n{}"));
+
+        let request = RequestBuilder::new(&server.url("/missing"))
+            .build()
+            .unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.status_code(), 404);
+    }
+
+    #[async_std::test]
+    async fn test_raw_sequence() {
+        let server = TestServerBuilder::new()
+            .add_route(
+                "*",
+                Route::Sequence(vec![
+                    Route::raw("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst"),
+                    Route::raw_and_close("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nlast"),
+                ]),
+            )
+            .start()
+            .await
+            .unwrap();
+        let client = Client::new();
+        for (path, body) in &[("/a", "first"), ("/b", "last"), ("/a", "last")] {
+            let request = RequestBuilder::new(&server.url(path)).build().unwrap();
+            let response = client.execute(&request).await.unwrap();
+            assert_eq!(response.body_as_string().unwrap(), *body);
+        }
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(server.connections(), 2);
+    }
+
+    #[async_std::test]
+    async fn test_tls() {
+        let server = TestServerBuilder::new()
+            .add_route("/", Route::text("Hello"))
+            .set_localhost_tls()
+            .start()
+            .await
+            .unwrap();
+        let mut client = Client::new();
+        client.add_authority(
+            &format!("localhost:{}", server.addr().port()),
+            &server.addr(),
+        );
+        client.add_root_certificates_pem(LOCALHOST_CA).unwrap();
+        let request = RequestBuilder::new(&server.url("/")).build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+    }
+}
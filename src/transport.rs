@@ -0,0 +1,247 @@
+//! Pluggable transport, to open the connections of a client.
+//!
+//! By default, the client connects to the authorities using TCP, and TLS for
+//! https. A `Transport` replaces the way the connections are established,
+//! in order to use another kind of stream, or to test the client without
+//! network using the [MockTransport](struct.MockTransport.html).
+//!
+//! # Example
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use cabot::transport::MockTransport;
+//! use cabot::{Client, RequestBuilder};
+//!
+//! # async_std::task::block_on(async {
+//! let transport = Arc::new(MockTransport::new());
+//! transport.add_response(b"HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nHello");
+//! let mut client = Client::new();
+//! client.set_transport(transport.clone());
+//!
+//! let request = RequestBuilder::new("http://localhost/").build().unwrap();
+//! let response = client.execute(&request).await.unwrap();
+//! assert_eq!(response.body_as_string().unwrap(), "Hello");
+//! assert!(transport.requests()[0].starts_with(b"GET / HTTP/1.1\r\n"));
+//! # });
+//! ```
+use std::cmp;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use async_std::io::{Read, Result as IoResult, Write};
+use async_std::task::{Context, Poll};
+use futures::future::{self, BoxFuture};
+
+use super::proxy::Proxy;
+use super::request::Request;
+use super::results::CabotResult;
+
+/// A connection opened by a transport.
+pub trait TransportStream: Read + Write + Unpin + Send {}
+
+impl<T: Read + Write + Unpin + Send> TransportStream for T {}
+
+/// Open the connections used to send the requests.
+pub trait Transport: Send + Sync {
+    /// Open a connection to the authority of the `request`, or to the
+    /// `proxy` if the request is sent through a proxy.
+    ///
+    /// The stream is ready to send the request, the TLS handshake and the
+    /// proxy tunnel, if any, are the responsibility of the transport.
+    fn connect<'a>(
+        &'a self,
+        request: &'a Request,
+        proxy: Option<&'a Proxy>,
+    ) -> BoxFuture<'a, CabotResult<Box<dyn TransportStream>>>;
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    responses: VecDeque<Vec<u8>>,
+    requests: Vec<Vec<u8>>,
+    connections: Vec<String>,
+}
+
+/// A transport serving canned responses, and recording the requests,
+/// without network.
+///
+/// Every request receives the next response added, whatever the connection
+/// it has been sent on. Once a response has been read, the connection is
+/// closed if no request is sent, like a server closing the connection.
+#[derive(Debug, Default, Clone)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockTransport {
+    /// Construct a transport without responses.
+    pub fn new() -> Self {
+        MockTransport {
+            state: Arc::new(Mutex::new(MockState::default())),
+        }
+    }
+
+    /// Add the raw bytes of a response, status line, headers and body,
+    /// sent after the previously added ones.
+    pub fn add_response(&self, response: &[u8]) {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .push_back(response.to_vec());
+    }
+
+    /// The raw bytes of the requests received, in order.
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// The authorities of the connections opened, in order.
+    pub fn connections(&self) -> Vec<String> {
+        self.state.lock().unwrap().connections.clone()
+    }
+}
+
+impl Transport for MockTransport {
+    fn connect<'a>(
+        &'a self,
+        request: &'a Request,
+        proxy: Option<&'a Proxy>,
+    ) -> BoxFuture<'a, CabotResult<Box<dyn TransportStream>>> {
+        let authority = match proxy {
+            Some(proxy) => proxy.authority(),
+            None => request.authority(),
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .connections
+            .push(authority.to_owned());
+        let stream = MockStream {
+            state: self.state.clone(),
+            request: Vec::new(),
+            response: Vec::new(),
+        };
+        Box::pin(future::ready(Ok(
+            Box::new(stream) as Box<dyn TransportStream>
+        )))
+    }
+}
+
+/// A connection of the `MockTransport`.
+struct MockStream {
+    state: Arc<Mutex<MockState>>,
+    /// the bytes written since the last response.
+    request: Vec<u8>,
+    /// the bytes of the response not read yet.
+    response: Vec<u8>,
+}
+
+impl Read for MockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        let self_ = Pin::get_mut(self);
+        if self_.response.is_empty() && !self_.request.is_empty() {
+            let mut state = self_.state.lock().unwrap();
+            state.requests.push(self_.request.split_off(0));
+            self_.response = state.responses.pop_front().unwrap_or_default();
+        }
+        let count = cmp::min(buf.len(), self_.response.len());
+        buf[..count].copy_from_slice(&self_.response[..count]);
+        self_.response.drain(..count);
+        Poll::Ready(Ok(count))
+    }
+}
+
+impl Write for MockStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        Pin::get_mut(self).request.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::prelude::*;
+
+    use super::super::client::Client;
+
+    use super::super::request::RequestBuilder;
+
+    #[async_std::test]
+    async fn test_mock_transport() {
+        let transport = MockTransport::new();
+        transport.add_response(b"HTTP/1.1 200 Ok\r\n\r\n");
+        transport.add_response(b"HTTP/1.1 204 No Content\r\n\r\n");
+        let request = RequestBuilder::new("http://localhost:8080/")
+            .build()
+            .unwrap();
+        let mut stream = transport.connect(&request, None).await.unwrap();
+
+        let mut buf = Vec::new();
+        stream.write_all(b"GET /1").await.unwrap();
+        stream.write_all(b" HTTP/1.1\r\n\r\n").await.unwrap();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"HTTP/1.1 200 Ok\r\n\r\n".to_vec());
+
+        let mut buf = Vec::new();
+        stream.write_all(b"GET /2 HTTP/1.1\r\n\r\n").await.unwrap();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"HTTP/1.1 204 No Content\r\n\r\n".to_vec());
+
+        let mut buf = Vec::new();
+        stream.write_all(b"GET /3 HTTP/1.1\r\n\r\n").await.unwrap();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+
+        assert_eq!(
+            transport.requests(),
+            vec![
+                b"GET /1 HTTP/1.1\r\n\r\n".to_vec(),
+                b"GET /2 HTTP/1.1\r\n\r\n".to_vec(),
+                b"GET /3 HTTP/1.1\r\n\r\n".to_vec(),
+            ]
+        );
+        assert_eq!(transport.connections(), vec!["localhost:8080"]);
+    }
+
+    #[async_std::test]
+    async fn test_client_transport() {
+        let transport = Arc::new(MockTransport::new());
+        transport.add_response(
+            b"HTTP/1.1 302 Found\r\nLocation: http://example.com/next\r\n\
+              Set-Cookie: sid=42\r\nContent-Length: 0\r\n\r\n",
+        );
+        transport.add_response(
+            b"HTTP/1.1 200 Ok\r\nTransfer-Encoding: chunked\r\n\r\n\
+              2\r\nHe\r\n3\r\nllo\r\n0\r\n\r\n",
+        );
+        let mut client = Client::new();
+        client.set_transport(transport.clone());
+        let request = RequestBuilder::new("http://example.com/").build().unwrap();
+        let response = client.execute(&request).await.unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+        assert_eq!(response.tls_session_resumed(), None);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        let redirection = String::from_utf8(requests[1].clone()).unwrap();
+        assert!(redirection.starts_with("GET /next HTTP/1.1\r\n"));
+        assert!(redirection.contains("\r\nCookie: sid=42\r\n"));
+        assert_eq!(transport.connections(), vec!["example.com:80"]);
+    }
+}
@@ -0,0 +1,43 @@
+#!/bin/sh
+# Generate the certificates used by the TLS unit tests.
+#
+# ca.pem signs localhost.pem, a certificate for the localhost domain,
+# and the client certificates using an ECDSA, a RSA and an Ed25519 key.
+set -e
+cd "$(dirname "$0")"
+
+openssl ecparam -name prime256v1 -genkey -noout -out ca.key
+openssl req -x509 -new -key ca.key -sha256 -days 36500 \
+    -subj "/CN=cabot test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" \
+    -addext "keyUsage=critical,keyCertSign,cRLSign" \
+    -out ca.pem
+openssl x509 -in ca.pem -outform DER -out ca.der
+
+# sign <name> <extendedKeyUsage> [subjectAltName]
+sign() {
+    openssl req -new -key "$1.key" -subj "/CN=$1" -out "$1.csr"
+    printf "basicConstraints=critical,CA:FALSE\nextendedKeyUsage=%s\n" "$2" > "$1.ext"
+    if [ -n "$3" ]; then
+        printf "subjectAltName=%s\n" "$3" >> "$1.ext"
+    fi
+    openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca.key -CAcreateserial \
+        -sha256 -days 36500 -extfile "$1.ext" -out "$1.pem"
+    rm -f "$1.csr" "$1.ext"
+}
+
+openssl ecparam -name prime256v1 -genkey -noout | \
+    openssl pkcs8 -topk8 -nocrypt -out localhost.key
+sign localhost serverAuth DNS:localhost
+
+openssl ecparam -name prime256v1 -genkey -noout | \
+    openssl pkcs8 -topk8 -nocrypt -out client-ecdsa.key
+sign client-ecdsa clientAuth
+
+openssl genrsa -traditional -out client-rsa.key 2048
+sign client-rsa clientAuth
+
+openssl genpkey -algorithm ed25519 -out client-ed25519.key
+sign client-ed25519 clientAuth
+
+rm -f ca.srl ca.key
@@ -11,7 +11,7 @@ And stderr display
         <URL>
 
     USAGE:
-        cabot <URL> --connect-timeout <CONNECT_TIMEOUT> --dns-timeout <DNS_LOOKUP_TIMEOUT> --max-redirs <NUMBER_OF_REDIRECT> --read-timeout <READ_TIMEOUT> --request <REQUEST> --max-time <REQUEST_TIMEOUT> --user-agent <UA>
+        cabot <URL> --connect-timeout <CONNECT_TIMEOUT> --dns-timeout <DNS_LOOKUP_TIMEOUT> --max-filesize <MAX_FILESIZE> --max-redirs <NUMBER_OF_REDIRECT> --read-timeout <READ_TIMEOUT> --request <REQUEST> --max-time <REQUEST_TIMEOUT> --retry <RETRY> --retry-delay <RETRY_DELAY> --retry-max-time <RETRY_MAX_TIME> --user-agent <UA>
 
     For more information try --help
 
@@ -32,25 +32,50 @@ And stdout display
         cabot [FLAGS] [OPTIONS] <URL>
 
     FLAGS:
-        -4, --ipv4       Resolve host names to IPv4 addresses
-        -6, --ipv6       Resolve host names to IPv6 addresses
-        -v, --verbose    Make the operation more talkative
-        -h, --help       Prints help information
-        -V, --version    Prints version information
+            --allow-https-downgrade    Follow the redirections from https to http
+            --compressed               Request a compressed response and decompress it
+            --digest                   Use the Digest authentication scheme, with the credentials of -u or of the URL
+        -4, --ipv4                     Resolve host names to IPv4 addresses
+        -6, --ipv6                     Resolve host names to IPv6 addresses
+        -v, --verbose                  Make the operation more talkative
+        -h, --help                     Prints help information
+        -V, --version                  Prints version information
 
     OPTIONS:
         -d, --data <BODY>                          Post Data (Using utf-8 encoding)
+            --cacert <CACERT>...                   <file> CA certificate to verify peer against (PEM or DER) instead of the
+                                                   default ones
+            --capath <CAPATH>...                   <dir> CA directory to verify peer against instead of the default ones
+        -E, --cert <CERT>                          <file> Client certificate chain file (PEM)
             --connect-timeout <CONNECT_TIMEOUT>    timeout for the tcp connection [default: 15]
+        -b, --cookie <COOKIE>                      <data|filename> Send cookies from string or read them from a Netscape
+                                                   cookie file
+        -c, --cookie-jar <COOKIE_JAR>              <filename> Write the cookies to this file after the operation
             --dns-timeout <DNS_LOOKUP_TIMEOUT>     timeout for the dns lookup resolution in seconds [default: 5]
         -o, --output <FILE>                        Write to FILE instead of stdout
         -H, --header <HEADER>...                   Pass custom header to server
-            --max-redirs <NUMBER_OF_REDIRECT>      max number of redirection before returning a response [default: 16]
+            --key <KEY>                            <file> Private key file of the client certificate (PEM), read from the
+                                                   certificate file if omitted
+            --max-filesize <MAX_FILESIZE>          <bytes> Fail if the response body is larger, 0 means no limit [default:
+                                                   0]
+            --max-redirs <NUMBER_OF_REDIRECT>      max number of redirection before returning a response, redirections from
+                                                   https to http are refused unless --allow-https-downgrade is set [default:
+                                                   16]
+        -x, --proxy <PROXY>                        <[protocol://][user:password@]proxyhost[:port]> Use this proxy, instead
+                                                   of the http_proxy, https_proxy and all_proxy environment variables
             --read-timeout <READ_TIMEOUT>          timeout for the tcp read in seconds [default: 10]
         -X, --request <REQUEST>                    Specify request command to use [default: GET]
             --max-time <REQUEST_TIMEOUT>           timeout for the whole http request in seconds (0 means no timeout)
                                                    [default: 0]
             --resolve <RESOLVE>...                 <host:port:address> Resolve the host+port to this address
+            --retry <RETRY>                        <num> Retry the request on transient errors [default: 0]
+            --retry-delay <RETRY_DELAY>            <seconds> Wait time between retries, 0 means an exponential backoff
+                                                   [default: 0]
+            --retry-max-time <RETRY_MAX_TIME>      <seconds> Stop retrying after this time, 0 means no limit [default: 0]
+            --socks5 <SOCKS5>                      <[user:password@]host[:port]> Use this SOCKS5 proxy, overrides -x
         -A, --user-agent <UA>                      The user-agent HTTP header to use [default: cabot/0.6.0]
+        -u, --user <USER>                          <user:password> Server user and password, sent using the Basic scheme by
+                                                   default
 
     ARGS:
         <URL>    URL to request
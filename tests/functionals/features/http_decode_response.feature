@@ -22,7 +22,7 @@ And stdout display
     """
 And stderr display
     """
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /no-length HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
@@ -55,7 +55,7 @@ And stdout display
     """
 And stderr display
     """
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /with-length HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
@@ -79,7 +79,7 @@ And stdout display
     """
 And stderr display
     """
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /small-chunked HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
@@ -22,27 +22,27 @@ And stdout display
     """
 And stderr display
     """
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?3 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?2 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?1 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?0 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /with-length HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
@@ -83,87 +83,87 @@ When I run "cabot http://127.0.0.1:8000/redirect-count-down?50 -v"
 Then the status code is "1"
 And stderr display
     """
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?50 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?49 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?48 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?47 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?46 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?45 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?44 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?43 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?42 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?41 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?40 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?39 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?38 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?37 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?36 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?35 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?34 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
@@ -191,17 +191,17 @@ When I run "cabot http://127.0.0.1:8000/redirect-count-down?5 -v --max-redirs 2"
 Then the status code is "1"
 And stderr display
     """
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?5 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?4 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /redirect-count-down?3 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
@@ -19,7 +19,7 @@ Then the status code is "1"
 And stdout is empty
 And stderr display
     """
-    IO Error: Read Timeout
+    Read Timeout while reading the response headers of http://127.0.0.1:8000/timeout?1100
     """
 
 @http
@@ -30,7 +30,7 @@ Then the status code is "1"
 And stdout is empty
 And stderr display
     """
-    IO Error: Read Timeout
+    Read Timeout while reading the response headers of http://127.0.0.1:8000/timeout?1100
     """
 
 @http
@@ -41,7 +41,7 @@ Then the status code is "1"
 And stdout is empty
 And stderr display
     """
-    IO Error: Read Timeout
+    Read Timeout while reading the response headers of http://127.0.0.1:8000/timeout?1100
     """
 
 @http
@@ -52,7 +52,7 @@ Then the status code is "1"
 And stdout is empty
 And stderr display
     """
-    IO Error: Read Timeout
+    Read Timeout while reading the response headers of http://127.0.0.1:8000/timeout?1100
     """
 
 
@@ -64,7 +64,7 @@ Then the status code is "1"
 And stdout is empty
 And stderr display
     """
-    IO Error: Read Timeout
+    Read Timeout while reading the response headers of http://127.0.0.1:8000/timeout?1100
     """
 
 @http
@@ -76,11 +76,11 @@ And stdout is empty
 And stderr display
     """
     * Read timeout is greater than request timeout, overridden (1000ms)
-    * Authority 127.0.0.1:8000 has been resolved to 127.0.0.1:8000
+    * Connected to 127.0.0.1:8000
     > GET /timeout?1100 HTTP/1.1
     > User-Agent: cabot/0.6.0
     > Connection: close
     >
-    IO Error: Read Timeout
+    Read Timeout while reading the response headers of http://127.0.0.1:8000/timeout?1100
     """
 